@@ -0,0 +1,753 @@
+//! Parser and validator of the bhyve command line.
+//!
+//! [`BhyveCommand::parse`] turns the arguments of bhyve (without the program
+//! name) into a structured model following the grammar of `-c`, `-m`, `-s`,
+//! `-l` and `-o` described in bhyve(8), and [`BhyveCommand::validate`] checks
+//! the model against what bhyve accepts, such as the range of PCI slots and
+//! the options known to each device model.
+
+use serde::Serialize;
+use std::collections::BTreeSet;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("unknown option `{0}`")]
+    UnknownOption(String),
+    #[error("option `{0}` requires a value")]
+    MissingValue(String),
+    #[error("missing vm name")]
+    MissingName,
+    #[error("unexpected argument `{0}` after the vm name")]
+    TrailingArgument(String),
+    #[error("invalid value `{value}` of `{option}`: {reason}")]
+    InvalidValue {
+        option: String,
+        value: String,
+        reason: String,
+    },
+}
+
+/// A problem found by [`BhyveCommand::validate`]. The location is the option
+/// and the value the problem is found in, for example `-s 0:3:0,virtio-blk`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Issue {
+    pub location: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.reason)
+    }
+}
+
+/// Options of bhyve taking no value
+const FLAGS: &str = "aACDeHhPSuWwxY";
+
+/// Options of bhyve taking a value
+const VALUED: &str = "cfGklmoprsU";
+
+/// Group the arguments such that an option taking a value and its value are
+/// one item, for example `["-A", "-c 2", "vm"]`
+pub fn group_args<S: AsRef<str>>(args: &[S]) -> Vec<String> {
+    let mut groups: Vec<String> = vec![];
+    let mut args = args.iter().map(|arg| arg.as_ref());
+    while let Some(arg) = args.next() {
+        let valued = arg.len() == 2
+            && arg.starts_with('-')
+            && arg[1..].chars().all(|opt| VALUED.contains(opt));
+        match args.clone().next() {
+            Some(value) if valued => {
+                groups.push(format!("{arg} {value}"));
+                args.next();
+            }
+            _ => groups.push(arg.to_string()),
+        }
+    }
+    groups
+}
+
+/// The vCPU topology from `-c`, the fields not specified are filled in the
+/// way bhyve does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Topology {
+    pub cpus: usize,
+    pub sockets: usize,
+    pub cores: usize,
+    pub threads: usize,
+    pub maxcpus: Option<usize>,
+}
+
+impl Topology {
+    pub fn parse(value: &str) -> Result<Topology, ParseError> {
+        let invalid = |reason: &str| ParseError::InvalidValue {
+            option: "-c".to_string(),
+            value: value.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let (mut cpus, mut sockets, mut cores, mut threads, mut maxcpus) =
+            (None, None, None, None, None);
+
+        for (index, part) in value.split(',').enumerate() {
+            let (key, number) = match part.split_once('=') {
+                Some((key, number)) => (key, number),
+                /* the number of vCPUs can be given without `cpus=` first */
+                None if index == 0 => ("cpus", part),
+                None => return Err(invalid(&format!("expected key=value, got `{part}`"))),
+            };
+            let number = number
+                .parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| invalid(&format!("`{key}` must be a positive integer")))?;
+            let field = match key {
+                "cpus" => &mut cpus,
+                "sockets" => &mut sockets,
+                "cores" => &mut cores,
+                "threads" => &mut threads,
+                "maxcpus" => &mut maxcpus,
+                key => return Err(invalid(&format!("unknown key `{key}`"))),
+            };
+            if field.replace(number).is_some() {
+                return Err(invalid(&format!("`{key}` is specified more than once")));
+            }
+        }
+
+        let topology = match (cpus, sockets, cores, threads) {
+            /* bhyve treats a bare vCPU count as that many sockets */
+            (Some(cpus), None, None, None) => Topology {
+                cpus,
+                sockets: cpus,
+                cores: 1,
+                threads: 1,
+                maxcpus,
+            },
+            (cpus, sockets, cores, threads) => {
+                let (sockets, cores, threads) = (
+                    sockets.unwrap_or(1),
+                    cores.unwrap_or(1),
+                    threads.unwrap_or(1),
+                );
+                let product = sockets * cores * threads;
+                if cpus.is_some_and(|cpus| cpus != product) {
+                    return Err(invalid(&format!(
+                        "cpus does not match sockets * cores * threads ({product})"
+                    )));
+                }
+                Topology {
+                    cpus: product,
+                    sockets,
+                    cores,
+                    threads,
+                    maxcpus,
+                }
+            }
+        };
+
+        Ok(topology)
+    }
+}
+
+/// Parse the memory size of `-m` to bytes. A value without suffix is in
+/// megabytes unless it is larger than 1M, in which case it is in bytes
+pub fn parse_memsize(value: &str) -> Result<u64, ParseError> {
+    let invalid = |reason: &str| ParseError::InvalidValue {
+        option: "-m".to_string(),
+        value: value.to_string(),
+        reason: reason.to_string(),
+    };
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let number = digits
+        .parse::<u64>()
+        .map_err(|_| invalid("expected a number optionally followed by K, M, G or T"))?;
+    let multiplier: u64 = match &value[digits.len()..] {
+        "" if number < 1024 * 1024 => 1024 * 1024,
+        "" => 1,
+        "k" | "K" => 1024,
+        "m" | "M" => 1024 * 1024,
+        "g" | "G" => 1024 * 1024 * 1024,
+        "t" | "T" => 1024 * 1024 * 1024 * 1024,
+        suffix => return Err(invalid(&format!("unknown suffix `{suffix}`"))),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| invalid("memory size overflows"))
+}
+
+/// The bus, slot and function of an emulated PCI device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct PciAddress {
+    pub bus: u16,
+    pub slot: u16,
+    pub func: u16,
+}
+
+impl std::fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.bus, self.slot, self.func)
+    }
+}
+
+impl PciAddress {
+    /// Parse the slot of `-s`, in the form of `slot`, `slot:func` or
+    /// `bus:slot:func`
+    pub fn parse(value: &str) -> Option<PciAddress> {
+        let numbers = value
+            .split(':')
+            .map(|n| n.parse::<u16>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        match numbers[..] {
+            [slot] => Some(PciAddress {
+                bus: 0,
+                slot,
+                func: 0,
+            }),
+            [slot, func] => Some(PciAddress { bus: 0, slot, func }),
+            [bus, slot, func] => Some(PciAddress { bus, slot, func }),
+            _ => None,
+        }
+    }
+}
+
+/// An option of a device, a bare `value` such as `nocache` or the backend of
+/// the device, or a `key=value` pair
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceOption {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl DeviceOption {
+    fn parse(option: &str) -> DeviceOption {
+        match option.split_once('=') {
+            Some((key, value)) => DeviceOption {
+                key: key.to_string(),
+                value: Some(value.to_string()),
+            },
+            None => DeviceOption {
+                key: option.to_string(),
+                value: None,
+            },
+        }
+    }
+}
+
+/// How an emulated PCI device is specified
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Syntax {
+    /// `-s slot,model,options`
+    Legacy,
+    /// `-o pci.bus.slot.func.key=value`
+    Config,
+}
+
+/// An emulated PCI device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub model: String,
+    pub options: Vec<DeviceOption>,
+    pub syntax: Syntax,
+}
+
+impl PciDevice {
+    /// The value of the option `key`
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|option| option.key == key)
+            .and_then(|option| option.value.as_deref())
+    }
+}
+
+/// A device of the LPC bus from `-l`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LpcDevice {
+    pub name: String,
+    pub options: Vec<String>,
+}
+
+/// The structured form of a bhyve command line
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BhyveCommand {
+    /// Options without value, for example `A` for `-A`
+    pub flags: BTreeSet<char>,
+    pub topology: Option<Topology>,
+    pub mem_bytes: Option<u64>,
+    pub gdb: Option<String>,
+    pub uuid: Option<String>,
+    pub devices: Vec<PciDevice>,
+    pub lpc_devices: Vec<LpcDevice>,
+    /// `-o` options that are not PCI devices
+    pub config: Vec<(String, String)>,
+    /// Options that are kept as is, for example `-k` and `-p`
+    pub others: Vec<(char, String)>,
+    pub name: String,
+}
+
+impl BhyveCommand {
+    pub fn parse<S: AsRef<str>>(args: &[S]) -> Result<BhyveCommand, ParseError> {
+        let mut command = BhyveCommand::default();
+        let mut iter = args.iter().map(|arg| arg.as_ref());
+        let mut name = None;
+
+        while let Some(arg) = iter.next() {
+            if name.is_some() {
+                return Err(ParseError::TrailingArgument(arg.to_string()));
+            }
+
+            let options = match arg.strip_prefix('-') {
+                Some(options) if !options.is_empty() => options,
+                _ => {
+                    name = Some(arg.to_string());
+                    continue;
+                }
+            };
+
+            /* flags can be grouped, for example -AHP */
+            for (index, opt) in options.char_indices() {
+                if FLAGS.contains(opt) {
+                    command.flags.insert(opt);
+                    continue;
+                }
+                if !VALUED.contains(opt) {
+                    return Err(ParseError::UnknownOption(format!("-{opt}")));
+                }
+                /* the value may be attached to the option, for example -c2 */
+                let rest = &options[index + opt.len_utf8()..];
+                let value = if rest.is_empty() {
+                    iter.next()
+                        .ok_or_else(|| ParseError::MissingValue(format!("-{opt}")))?
+                } else {
+                    rest
+                };
+                command.push_option(opt, value)?;
+                break;
+            }
+        }
+
+        command.name = name.ok_or(ParseError::MissingName)?;
+        Ok(command)
+    }
+
+    fn push_option(&mut self, opt: char, value: &str) -> Result<(), ParseError> {
+        let invalid = |reason: &str| ParseError::InvalidValue {
+            option: format!("-{opt}"),
+            value: value.to_string(),
+            reason: reason.to_string(),
+        };
+
+        match opt {
+            'c' => self.topology = Some(Topology::parse(value)?),
+            'm' => self.mem_bytes = Some(parse_memsize(value)?),
+            'G' => self.gdb = Some(value.to_string()),
+            'U' => self.uuid = Some(value.to_string()),
+            's' => {
+                let mut parts = value.split(',');
+                let address = parts
+                    .next()
+                    .and_then(PciAddress::parse)
+                    .ok_or_else(|| invalid("expected [bus:]slot[:func] before the model"))?;
+                let model = parts
+                    .next()
+                    .filter(|model| !model.is_empty())
+                    .ok_or_else(|| invalid("missing device model"))?;
+                self.devices.push(PciDevice {
+                    address,
+                    model: model.to_string(),
+                    options: parts.map(DeviceOption::parse).collect(),
+                    syntax: Syntax::Legacy,
+                });
+            }
+            'l' => {
+                let mut parts = value.split(',');
+                let name = parts
+                    .next()
+                    .filter(|name| !name.is_empty())
+                    .ok_or_else(|| invalid("missing lpc device"))?;
+                self.lpc_devices.push(LpcDevice {
+                    name: name.to_string(),
+                    options: parts.map(|s| s.to_string()).collect(),
+                });
+            }
+            'o' => {
+                let (key, val) = value
+                    .split_once('=')
+                    .ok_or_else(|| invalid("expected key=value"))?;
+                self.push_config(key, val)
+                    .map_err(|reason| invalid(&reason))?;
+            }
+            opt => self.others.push((opt, value.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Fold `pci.bus.slot.func.key=value` into the device at the address
+    fn push_config(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let path = match key.strip_prefix("pci.") {
+            None => {
+                self.config.push((key.to_string(), value.to_string()));
+                return Ok(());
+            }
+            Some(path) => path,
+        };
+
+        let parts = path.splitn(4, '.').collect::<Vec<_>>();
+        let (address, key) = match parts[..] {
+            [bus, slot, func, key] => (
+                PciAddress::parse(&format!("{bus}:{slot}:{func}"))
+                    .ok_or("expected pci.bus.slot.func.key")?,
+                key,
+            ),
+            _ => return Err("expected pci.bus.slot.func.key".to_string()),
+        };
+
+        let index = match self
+            .devices
+            .iter()
+            .position(|device| device.address == address && device.syntax == Syntax::Config)
+        {
+            Some(index) => index,
+            None => {
+                self.devices.push(PciDevice {
+                    address,
+                    model: String::new(),
+                    options: vec![],
+                    syntax: Syntax::Config,
+                });
+                self.devices.len() - 1
+            }
+        };
+
+        let device = &mut self.devices[index];
+        if key == "device" {
+            device.model = value.to_string();
+        } else {
+            device.options.push(DeviceOption {
+                key: key.to_string(),
+                value: Some(value.to_string()),
+            });
+        }
+        Ok(())
+    }
+
+    /// The device at the PCI address
+    pub fn device(&self, address: PciAddress) -> Option<&PciDevice> {
+        self.devices.iter().find(|device| device.address == address)
+    }
+
+    /// Check the command against what bhyve accepts
+    pub fn validate(&self) -> Vec<Issue> {
+        let mut issues = vec![];
+        let mut seen = BTreeSet::new();
+
+        for device in self.devices.iter() {
+            let location = match device.syntax {
+                Syntax::Legacy => format!("-s {},{}", device.address, device.model),
+                Syntax::Config => format!("-o pci.{}", device.address).replace(':', "."),
+            };
+            let mut issue = |reason: String| {
+                issues.push(Issue {
+                    location: location.clone(),
+                    reason,
+                })
+            };
+
+            let PciAddress { bus, slot, func } = device.address;
+            if bus > 255 || slot > 31 || func > 7 {
+                issue("bus must be 0-255, slot 0-31 and function 0-7".to_string());
+            }
+            if !seen.insert(device.address) {
+                issue("slot is used by more than one device".to_string());
+            }
+            if device.model.is_empty() {
+                issue("missing `device` for the slot".to_string());
+                continue;
+            }
+
+            match model_spec(&device.model) {
+                None => issue(format!("unknown device model `{}`", device.model)),
+                Some(spec) => {
+                    for reason in spec.check(device) {
+                        issue(reason);
+                    }
+                }
+            }
+        }
+
+        for lpc in self.lpc_devices.iter() {
+            let location = format!("-l {}", lpc.name);
+            let reason = match lpc.name.as_str() {
+                "com1" | "com2" | "com3" | "com4" => match lpc.options.len() {
+                    1 => None,
+                    _ => Some("expected exactly one backend of the com port"),
+                },
+                "bootrom" => match lpc.options.len() {
+                    1 | 2 => None,
+                    _ => Some("expected the firmware and an optional variable file"),
+                },
+                "pc-testdev" | "tpm" | "fwcfg" => None,
+                _ => Some("unknown lpc device"),
+            };
+            if let Some(reason) = reason {
+                issues.push(Issue {
+                    location,
+                    reason: reason.to_string(),
+                });
+            }
+        }
+
+        if !self.lpc_devices.is_empty() && !self.devices.iter().any(|d| d.model == "lpc") {
+            issues.push(Issue {
+                location: "-l".to_string(),
+                reason: "lpc devices require an lpc PCI device".to_string(),
+            });
+        }
+
+        issues
+    }
+}
+
+/// What options a device model accepts
+struct ModelSpec {
+    /// The first option may be the backend of the device, such as the path of
+    /// a disk image, which can be anything
+    backend: bool,
+    flags: &'static [&'static str],
+    keys: &'static [&'static str],
+}
+
+const BLOCK_FLAGS: &[&str] = &["nocache", "direct", "ro", "nodelete"];
+
+fn model_spec(model: &str) -> Option<ModelSpec> {
+    let spec = |backend, flags, keys| {
+        Some(ModelSpec {
+            backend,
+            flags,
+            keys,
+        })
+    };
+
+    match model {
+        "hostbridge" | "amd_hostbridge" | "lpc" | "virtio-rnd" => spec(false, &[], &[]),
+        "virtio-net" | "e1000" => spec(true, &[], &["mac", "mtu", "type"]),
+        "virtio-blk" => spec(true, BLOCK_FLAGS, &["sectorsize", "ser", "path"]),
+        "ahci-hd" | "ahci-cd" => spec(
+            true,
+            BLOCK_FLAGS,
+            &["sectorsize", "nmrr", "ser", "rev", "model", "path"],
+        ),
+        "nvme" => spec(
+            true,
+            &[],
+            &[
+                "maxq", "qsz", "ioslots", "sectsz", "ser", "eui64", "dsm", "ram", "path",
+            ],
+        ),
+        "passthru" => spec(true, &[], &["rom"]),
+        "fbuf" => spec(
+            false,
+            &["wait"],
+            &["tcp", "rfb", "w", "h", "vga", "password"],
+        ),
+        "xhci" => spec(false, &["tablet"], &[]),
+        "virtio-console" => spec(false, &[], &[]),
+        /* models with options too dynamic to be checked */
+        "ahci" | "virtio-scsi" | "virtio-9p" | "virtio-input" | "uart" | "hda" => {
+            spec(true, &[], &[])
+        }
+        _ => None,
+    }
+}
+
+impl ModelSpec {
+    fn check(&self, device: &PciDevice) -> Vec<String> {
+        let mut reasons = vec![];
+        let unchecked = self.backend && self.flags.is_empty() && self.keys.is_empty();
+        if unchecked {
+            return reasons;
+        }
+
+        for (index, option) in device.options.iter().enumerate() {
+            let known = match &option.value {
+                None => self.flags.contains(&option.key.as_str()),
+                Some(_) if device.model == "virtio-console" => {
+                    option.key.starts_with("port")
+                        && option.key[4..]
+                            .parse::<u8>()
+                            .is_ok_and(|n| (1..=16).contains(&n))
+                }
+                Some(_) => self.keys.contains(&option.key.as_str()),
+            };
+            /* bhyve takes a bare first option as the backend of the device */
+            let backend = self.backend
+                && index == 0
+                && option.value.is_none()
+                && device.syntax == Syntax::Legacy;
+            if !known && !backend {
+                let option = match &option.value {
+                    None => option.key.to_string(),
+                    Some(value) => format!("{}={value}", option.key),
+                };
+                reasons.push(format!("unknown option `{option}` of {}", device.model));
+            }
+        }
+
+        if let Some(sectorsize) = device.option("sectorsize") {
+            let valid = sectorsize
+                .split('/')
+                .map(|size| {
+                    size.parse::<u32>()
+                        .is_ok_and(|n| n.is_power_of_two() && n >= 512)
+                })
+                .collect::<Vec<_>>();
+            if valid.is_empty() || valid.len() > 2 || valid.contains(&false) {
+                reasons.push(format!(
+                    "sectorsize must be logical[/physical] in powers of 2 of at least 512, got `{sectorsize}`"
+                ));
+            }
+        }
+
+        reasons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_topology() {
+        assert_eq!(
+            Topology::parse("2").unwrap(),
+            Topology {
+                cpus: 2,
+                sockets: 2,
+                cores: 1,
+                threads: 1,
+                maxcpus: None
+            }
+        );
+        assert_eq!(
+            Topology::parse("sockets=1,cores=4,threads=2").unwrap(),
+            Topology {
+                cpus: 8,
+                sockets: 1,
+                cores: 4,
+                threads: 2,
+                maxcpus: None
+            }
+        );
+        assert!(Topology::parse("cpus=4,sockets=1,cores=2").is_err());
+        assert!(Topology::parse("sockets=1,cpus").is_err());
+        assert!(Topology::parse("0").is_err());
+    }
+
+    #[test]
+    fn parse_memsize_units() {
+        assert_eq!(parse_memsize("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_memsize("524288K"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_memsize("2g"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_memsize("512"), Ok(512 * 1024 * 1024));
+        assert!(parse_memsize("512X").is_err());
+    }
+
+    #[test]
+    fn parse_command() {
+        let args = [
+            "-AHu",
+            "-c",
+            "2",
+            "-m",
+            "512M",
+            "-s",
+            "0,hostbridge",
+            "-s",
+            "0:31:0,lpc",
+            "-s",
+            "2:0,virtio-blk,disk.img,ro,sectorsize=512/4096",
+            "-o",
+            "pci.0.3.0.device=nvme",
+            "-o",
+            "pci.0.3.0.ram=1024",
+            "-l",
+            "com1,stdio",
+            "vm",
+        ];
+        let command = BhyveCommand::parse(&args).unwrap();
+        assert_eq!(command.flags, BTreeSet::from(['A', 'H', 'u']));
+        assert_eq!(command.name, "vm");
+        assert_eq!(command.devices.len(), 4);
+
+        let blk = command
+            .device(PciAddress {
+                bus: 0,
+                slot: 2,
+                func: 0,
+            })
+            .unwrap();
+        assert_eq!(blk.model, "virtio-blk");
+        assert_eq!(blk.option("sectorsize"), Some("512/4096"));
+
+        let nvme = command.devices.last().unwrap();
+        assert_eq!(
+            (nvme.model.as_str(), nvme.option("ram")),
+            ("nvme", Some("1024"))
+        );
+        assert_eq!(command.validate(), vec![]);
+
+        assert_eq!(
+            BhyveCommand::parse(&["-c"]),
+            Err(ParseError::MissingValue("-c".to_string()))
+        );
+        assert_eq!(BhyveCommand::parse(&["-A"]), Err(ParseError::MissingName));
+        assert_eq!(
+            BhyveCommand::parse(&["vm", "-A"]),
+            Err(ParseError::TrailingArgument("-A".to_string()))
+        );
+        assert_eq!(
+            BhyveCommand::parse(&["-Z", "vm"]),
+            Err(ParseError::UnknownOption("-Z".to_string()))
+        );
+    }
+
+    #[test]
+    fn validate_command() {
+        let args = [
+            "-s",
+            "0,hostbridge",
+            "-s",
+            "32,virtio-rnd",
+            "-s",
+            "2,virtio-blk,disk.img,rosectorsize=512",
+            "-s",
+            "2,virtio-blk,disk.img,sectorsize=500",
+            "-s",
+            "4,virtio-gpu",
+            "-l",
+            "com5,stdio",
+            "vm",
+        ];
+        let issues = BhyveCommand::parse(&args)
+            .unwrap()
+            .validate()
+            .into_iter()
+            .map(|issue| issue.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            issues,
+            vec![
+                "-s 0:32:0,virtio-rnd: bus must be 0-255, slot 0-31 and function 0-7",
+                "-s 0:2:0,virtio-blk: unknown option `rosectorsize=512` of virtio-blk",
+                "-s 0:2:0,virtio-blk: slot is used by more than one device",
+                "-s 0:2:0,virtio-blk: sectorsize must be logical[/physical] in powers of 2 of at least 512, got `500`",
+                "-s 0:4:0,virtio-gpu: unknown device model `virtio-gpu`",
+                "-l com5: unknown lpc device",
+                "-l: lpc devices require an lpc PCI device",
+            ]
+        );
+    }
+}
@@ -0,0 +1,686 @@
+use crate::script::ScriptFailure;
+use crate::util::utc_timestamp;
+use crate::vm::{ComBackend, ConsoleLogging};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// Log of a serial console with a timestamp at the start of every line. The
+/// log is rotated to `PATH.1` once it reaches the size limit, at the start of
+/// a line such that a line is never split across logs
+pub struct ConsoleLog {
+    settings: ConsoleLogging,
+    file: File,
+    size: u64,
+    line_start: bool,
+}
+
+impl ConsoleLog {
+    pub fn open(settings: &ConsoleLogging) -> std::io::Result<ConsoleLog> {
+        let path = Path::new(&settings.path);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(ConsoleLog {
+            settings: settings.clone(),
+            file,
+            size,
+            line_start: true,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{n}", self.settings.path))
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.settings.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.settings.keep).rev() {
+                match std::fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(error)
+                    }
+                    _ => (),
+                }
+            }
+            std::fs::rename(&self.settings.path, self.rotated(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.settings.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        for line in bytes.split_inclusive(|byte| *byte == b'\n') {
+            if self.line_start {
+                if self.size >= self.settings.max_bytes {
+                    self.rotate()?;
+                }
+                let stamp = format!("[{}] ", utc_timestamp(SystemTime::now()));
+                self.file.write_all(stamp.as_bytes())?;
+                self.size += stamp.len() as u64;
+            }
+            self.file.write_all(line)?;
+            self.size += line.len() as u64;
+            self.line_start = line.ends_with(b"\n");
+        }
+        Ok(())
+    }
+}
+
+/// The terminal in raw mode, such that keys reach the guest as typed. The
+/// terminal is restored when dropped
+pub struct RawTerminal {
+    saved: libc::termios,
+}
+
+impl RawTerminal {
+    /// Switch the terminal on stdin to raw mode, `None` if stdin is not a
+    /// terminal
+    pub fn enter() -> Option<RawTerminal> {
+        if !std::io::stdin().is_terminal() {
+            return None;
+        }
+        // SAFETY: termios is plain data filled by tcgetattr
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return None;
+            }
+            let saved = termios;
+            libc::cfmakeraw(&mut termios);
+            /* keep translating newlines for the messages of vmrun */
+            termios.c_oflag |= libc::OPOST;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                return None;
+            }
+            Some(RawTerminal { saved })
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        // SAFETY: restoring the attributes saved by tcgetattr
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+/// A console owned by the supervisor. The output of the guest is passed to
+/// every sink subscribed to the console: the terminal, the log and the viewers
+/// attached through the control socket
+#[derive(Default)]
+pub struct ConsoleHub {
+    sinks: Mutex<Vec<(u64, Box<dyn Write + Send>)>>,
+    input: Mutex<Option<Box<dyn Write + Send>>>,
+    writer: Mutex<bool>,
+    next_id: AtomicU64,
+}
+
+impl ConsoleHub {
+    /// Pass the output of the guest to `sink` until the sink fails
+    pub fn subscribe(&self, sink: Box<dyn Write + Send>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.sinks.lock().unwrap().push((id, sink));
+        id
+    }
+
+    pub fn unsubscribe(&self, id: u64) {
+        self.sinks.lock().unwrap().retain(|(sink, _)| *sink != id);
+    }
+
+    /// Take the single seat of writer, false if another viewer writes to the
+    /// console
+    pub fn claim_writer(&self) -> bool {
+        let mut writer = self.writer.lock().unwrap();
+        !std::mem::replace(&mut *writer, true)
+    }
+
+    pub fn release_writer(&self) {
+        *self.writer.lock().unwrap() = false;
+    }
+
+    /// Send keys to the guest
+    pub fn send(&self, bytes: &[u8]) -> std::io::Result<()> {
+        match self.input.lock().unwrap().as_mut() {
+            Some(input) => input.write_all(bytes).and_then(|_| input.flush()),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "the console takes no input",
+            )),
+        }
+    }
+
+    pub fn set_input(&self, input: Option<Box<dyn Write + Send>>) {
+        *self.input.lock().unwrap() = input;
+    }
+
+    /// Pass the output of the guest to every sink, the failed sinks are
+    /// dropped
+    pub fn broadcast(&self, bytes: &[u8]) {
+        self.sinks
+            .lock()
+            .unwrap()
+            .retain_mut(|(_, sink)| sink.write_all(bytes).and_then(|_| sink.flush()).is_ok());
+    }
+
+    /// Broadcast the output of the guest. A persistent output, the peer of a
+    /// nmdm pair, is read again after bhyve closes its side, other outputs
+    /// are copied until they end
+    fn pump(&self, mut output: impl Read, persistent: bool) {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match output.read(&mut buffer) {
+                Ok(n) if n > 0 => self.broadcast(&buffer[..n]),
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                _ if persistent => std::thread::sleep(Duration::from_millis(200)),
+                _ => return,
+            }
+        }
+    }
+}
+
+/// The text of the console output matched against patterns. A pattern
+/// matches within a line, the text before a match or a newline is not looked
+/// at again
+#[derive(Debug, Default)]
+pub struct PatternMatcher {
+    pending: String,
+}
+
+impl PatternMatcher {
+    /// Longest partial line kept, a longer line is only matched in part
+    const MAX_PENDING: usize = 4096;
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.pending.push_str(&String::from_utf8_lossy(bytes));
+    }
+
+    /// The first of `patterns` matching the text, by where the match starts,
+    /// with the matched text. The text up to the end of the match is consumed
+    pub fn find<'a>(
+        &mut self,
+        patterns: impl IntoIterator<Item = &'a regex::Regex>,
+    ) -> Option<(usize, String)> {
+        let found = patterns
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, pattern)| Some((index, pattern.find(&self.pending)?)))
+            .min_by_key(|(index, found)| (found.start(), *index))
+            .map(|(index, found)| (index, found.as_str().to_string(), found.end()));
+        match found {
+            Some((index, text, end)) => {
+                self.pending.drain(..end);
+                Some((index, text))
+            }
+            None => {
+                /* a pattern never spans a line */
+                if let Some(newline) = self.pending.rfind('\n') {
+                    self.pending.drain(..=newline);
+                }
+                if self.pending.len() > Self::MAX_PENDING {
+                    let mut cut = self.pending.len() - Self::MAX_PENDING;
+                    while !self.pending.is_char_boundary(cut) {
+                        cut += 1;
+                    }
+                    self.pending.drain(..cut);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// What the consoles report to the supervisor while the VM runs
+#[derive(Debug)]
+pub enum ConsoleEvent {
+    /// A console trigger, by its index in the VM, matched the text
+    Triggered(usize, String),
+    /// The boot script ran to its end or failed
+    ScriptFinished(Result<(), ScriptFailure>),
+}
+
+/// A sink matching the console output against the patterns of the triggers
+/// of a com port, the index of a matching trigger is sent with the matched
+/// text. The sink is dropped once the receiver is gone
+pub struct TriggerSink {
+    triggers: Vec<(usize, regex::Regex)>,
+    matcher: PatternMatcher,
+    sender: Sender<ConsoleEvent>,
+}
+
+impl TriggerSink {
+    pub fn new(triggers: Vec<(usize, regex::Regex)>, sender: Sender<ConsoleEvent>) -> TriggerSink {
+        TriggerSink {
+            triggers,
+            matcher: PatternMatcher::default(),
+            sender,
+        }
+    }
+}
+
+impl Write for TriggerSink {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.matcher.feed(bytes);
+        while let Some((index, text)) = self.matcher.find(self.triggers.iter().map(|(_, p)| p)) {
+            self.sender
+                .send(ConsoleEvent::Triggered(self.triggers[index].0, text))
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The chunks of console output queued for a sink before it is dropped
+const SINK_QUEUE: usize = 256;
+
+/// A sink written by a thread of its own, such that a sink that does not keep
+/// up stalls neither the console nor the other sinks. The sink fails once its
+/// queue is full, and is dropped by the console
+pub struct QueuedSink(SyncSender<Vec<u8>>);
+
+impl QueuedSink {
+    pub fn spawn(mut sink: impl Write + Send + 'static) -> QueuedSink {
+        let (sender, queue) = std::sync::mpsc::sync_channel::<Vec<u8>>(SINK_QUEUE);
+        std::thread::spawn(move || {
+            for bytes in queue {
+                if sink.write_all(&bytes).and_then(|_| sink.flush()).is_err() {
+                    break;
+                }
+            }
+        });
+        QueuedSink(sender)
+    }
+}
+
+impl Write for QueuedSink {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .try_send(bytes.to_vec())
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::WouldBlock))?;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The console log as a sink, a failure to write the log is reported once
+/// and does not detach the log
+struct LogSink {
+    log: Arc<Mutex<ConsoleLog>>,
+    warned: bool,
+}
+
+impl Write for LogSink {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        if let Err(error) = self.log.lock().unwrap().write(bytes) {
+            if !self.warned {
+                eprintln!("warn: cannot write console log: {error}");
+                self.warned = true;
+            }
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct ConsoleState {
+    /// The com ports of the running target
+    ports: BTreeMap<u8, ComBackend>,
+    /// The consoles by the peer of the com port, or `stdio`
+    hubs: HashMap<String, Arc<ConsoleHub>>,
+    log: Option<Arc<Mutex<ConsoleLog>>>,
+    /// The consoles passed to the terminal of the supervisor, and logged
+    on_terminal: Vec<String>,
+    raw_terminal: Option<RawTerminal>,
+}
+
+impl ConsoleState {
+    fn log(&mut self, settings: &ConsoleLogging) -> std::io::Result<Arc<Mutex<ConsoleLog>>> {
+        match &self.log {
+            Some(log) if log.lock().unwrap().settings == *settings => Ok(log.clone()),
+            _ => {
+                let log = Arc::new(Mutex::new(ConsoleLog::open(settings)?));
+                self.log = Some(log.clone());
+                Ok(log)
+            }
+        }
+    }
+
+    /// The console of the peer, which is opened on first use and kept open
+    /// for the life of the supervisor, such that no output is lost across
+    /// reboots
+    fn peer_hub(&mut self, peer: &str) -> std::io::Result<Arc<ConsoleHub>> {
+        if let Some(hub) = self.hubs.get(peer) {
+            return Ok(hub.clone());
+        }
+        let output = OpenOptions::new().read(true).write(true).open(peer)?;
+        let hub = Arc::new(ConsoleHub::default());
+        hub.set_input(Some(Box::new(output.try_clone()?)));
+        let pump = hub.clone();
+        std::thread::spawn(move || pump.pump(output, true));
+        self.hubs.insert(peer.to_string(), hub.clone());
+        Ok(hub)
+    }
+
+    /// Pass the console to the terminal and the log if any, once per console
+    fn on_terminal(
+        &mut self,
+        key: &str,
+        hub: &ConsoleHub,
+        settings: Option<&ConsoleLogging>,
+    ) -> std::io::Result<bool> {
+        if self.on_terminal.iter().any(|console| console == key) {
+            return Ok(false);
+        }
+        hub.subscribe(Box::new(std::io::stdout()));
+        if let Some(settings) = settings {
+            let log = self.log(settings)?;
+            hub.subscribe(Box::new(LogSink { log, warned: false }));
+        }
+        self.on_terminal.push(key.to_string());
+        Ok(true)
+    }
+}
+
+/// Pass the keys read from `input` to the guest as the writer of the console
+/// until the input ends, the seat of writer is then released
+fn type_into(hub: &ConsoleHub, mut input: impl Read) {
+    let mut buffer = [0u8; 256];
+    while let Ok(n) = input.read(&mut buffer) {
+        if n == 0 || hub.send(&buffer[..n]).is_err() {
+            break;
+        }
+    }
+    hub.release_writer();
+}
+
+/// The consoles of the VM owned by the supervisor, shared with the control
+/// socket and kept across reboots
+#[derive(Clone, Default)]
+pub struct Consoles(Arc<Mutex<ConsoleState>>);
+
+impl Consoles {
+    /// Track the com ports of the target about to be launched
+    pub fn set_ports<'a>(&self, ports: impl Iterator<Item = (u8, &'a ComBackend)>) {
+        self.0.lock().unwrap().ports = ports.map(|(n, backend)| (n, backend.clone())).collect();
+    }
+
+    /// The console of com port `n` for the viewers, which is the other side
+    /// of a nmdm pair. The stdio console belongs to the terminal of the
+    /// supervisor, and a TCP console is attached directly
+    pub fn hub(&self, n: u8) -> Result<Arc<ConsoleHub>, String> {
+        let mut state = self.0.lock().unwrap();
+        let backend = state
+            .ports
+            .get(&n)
+            .cloned()
+            .ok_or_else(|| format!("com{n} is not configured"))?;
+        match (&backend, backend.peer()) {
+            (ComBackend::Tcp(address), _) => Err(format!("com{n} listens on {address}")),
+            (_, Some(peer)) => state
+                .peer_hub(&peer)
+                .map_err(|error| format!("cannot open {peer}: {error}")),
+            (_, None) => Err(format!(
+                "com{n} is on {}, which is not attachable",
+                backend.as_bhyve_arg()
+            )),
+        }
+    }
+
+    /// Subscribe `sink` to the console of com port `n` to watch the output of
+    /// the guest. The console of a stdio com port is only fed once bhyve is
+    /// launched with its output captured
+    pub fn watch(
+        &self,
+        n: u8,
+        sink: Box<dyn Write + Send>,
+    ) -> Result<(Arc<ConsoleHub>, u64), String> {
+        let stdio = self.0.lock().unwrap().ports.get(&n) == Some(&ComBackend::Stdio);
+        let hub = if stdio {
+            let mut state = self.0.lock().unwrap();
+            state.hubs.entry("stdio".to_string()).or_default().clone()
+        } else {
+            self.hub(n)?
+        };
+        let id = hub.subscribe(sink);
+        Ok((hub, id))
+    }
+
+    /// Pass the output of bhyve, the console of a stdio com port, to the
+    /// terminal, the log and the watchers until bhyve exits
+    pub fn capture_output(
+        &self,
+        settings: Option<&ConsoleLogging>,
+        output: Box<dyn Read + Send>,
+    ) -> std::io::Result<JoinHandle<()>> {
+        let mut state = self.0.lock().unwrap();
+        let hub = state.hubs.entry("stdio".to_string()).or_default().clone();
+        state.on_terminal("stdio", &hub, settings)?;
+        Ok(std::thread::spawn(move || hub.pump(output, false)))
+    }
+
+    /// Take over the peer of a nmdm com port: log its output and pass it to
+    /// the terminal, and pass the input of the terminal to the guest. The
+    /// terminal keeps the seat of writer, unless a viewer took it first
+    pub fn log_peer(&self, settings: &ConsoleLogging, peer: &str) -> std::io::Result<()> {
+        let mut state = self.0.lock().unwrap();
+        let hub = state.peer_hub(peer)?;
+        if !state.on_terminal(peer, &hub, Some(settings))? {
+            return Ok(());
+        }
+        /* in the background, the seat of writer is left to `vmrun console` */
+        if !std::io::stdin().is_terminal() {
+            return Ok(());
+        }
+        if !hub.claim_writer() {
+            eprintln!(
+                "warn: a viewer writes to {peer}, the keys typed in the terminal are dropped"
+            );
+            return Ok(());
+        }
+        if state.raw_terminal.is_none() {
+            state.raw_terminal = RawTerminal::enter();
+        }
+        std::thread::spawn(move || type_into(&hub, std::io::stdin()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sink the test can read back, or a sink failing every write
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Option<Vec<u8>>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            match self.0.lock().unwrap().as_mut() {
+                Some(buffer) => buffer.write(bytes),
+                None => Err(std::io::ErrorKind::BrokenPipe.into()),
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn new() -> Shared {
+            Shared(Arc::new(Mutex::new(Some(vec![]))))
+        }
+
+        fn take(&self) -> Vec<u8> {
+            self.0.lock().unwrap().as_mut().map(std::mem::take).unwrap()
+        }
+    }
+
+    #[test]
+    fn match_patterns_within_lines() {
+        let complete = regex::Regex::new("Installation complete").unwrap();
+        let panic = regex::Regex::new("panic: .*").unwrap();
+        let patterns = [&complete, &panic];
+        let mut matcher = PatternMatcher::default();
+
+        matcher.feed(b"Extracting base.txz\r\nInstallation comp");
+        assert_eq!(matcher.find(patterns), None);
+        matcher.feed(b"lete\r\n");
+        assert_eq!(
+            matcher.find(patterns),
+            Some((0, "Installation complete".to_string()))
+        );
+        /* a match is reported once */
+        assert_eq!(matcher.find(patterns), None);
+
+        /* the earliest match wins, the rest is matched on the next call */
+        matcher.feed(b"panic: page fault\nInstallation complete\n");
+        assert_eq!(
+            matcher.find(patterns),
+            Some((1, "panic: page fault".to_string()))
+        );
+        assert_eq!(matcher.find(patterns).map(|(index, _)| index), Some(0));
+
+        /* a pattern does not span lines */
+        matcher.feed(b"Installation\ncomplete\n");
+        assert_eq!(matcher.find(patterns), None);
+        assert!(matcher.pending.is_empty());
+    }
+
+    #[test]
+    fn console_hub() {
+        let hub = ConsoleHub::default();
+        let (first, second, failing) = (Shared::new(), Shared::new(), Shared::default());
+        hub.subscribe(Box::new(first.clone()));
+        let id = hub.subscribe(Box::new(second.clone()));
+        hub.subscribe(Box::new(failing));
+        hub.broadcast(b"login: ");
+        assert_eq!(first.take(), b"login: ");
+        assert_eq!(second.take(), b"login: ");
+        assert_eq!(hub.sinks.lock().unwrap().len(), 2);
+        hub.unsubscribe(id);
+        hub.broadcast(b"root");
+        assert_eq!(first.take(), b"root");
+        assert!(second.take().is_empty());
+
+        assert!(hub.send(b"root\r").is_err());
+        let input = Shared::new();
+        hub.set_input(Some(Box::new(input.clone())));
+        assert!(hub.send(b"root\r").is_ok());
+        assert_eq!(input.take(), b"root\r");
+
+        assert!(hub.claim_writer());
+        assert!(!hub.claim_writer());
+        hub.release_writer();
+        assert!(hub.claim_writer());
+
+        /* the terminal gives the seat back once its input ends */
+        type_into(&hub, &b"ls\r"[..]);
+        assert_eq!(input.take(), b"ls\r");
+        assert!(hub.claim_writer());
+    }
+
+    /// A sink blocked until the test ends
+    struct Stuck(Mutex<std::sync::mpsc::Receiver<()>>);
+
+    impl Write for Stuck {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            let _ = self.0.lock().unwrap().recv();
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stuck_sink() {
+        let hub = ConsoleHub::default();
+        let (_unblock, blocked) = std::sync::mpsc::channel();
+        hub.subscribe(Box::new(QueuedSink::spawn(Stuck(Mutex::new(blocked)))));
+        let viewer = Shared::new();
+        hub.subscribe(Box::new(QueuedSink::spawn(viewer.clone())));
+
+        /* the stuck sink is dropped once its queue is full, the other keeps
+         * up and goes on
+         */
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let mut received = vec![];
+        for sent in 1..=SINK_QUEUE + 2 {
+            hub.broadcast(b"x");
+            while received.len() < sent && std::time::Instant::now() < deadline {
+                received.extend(viewer.take());
+                std::thread::yield_now();
+            }
+        }
+        assert_eq!(received.len(), SINK_QUEUE + 2);
+        assert_eq!(hub.sinks.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rotate_console_log() {
+        let dir = std::env::temp_dir().join(format!("vmrun-console-{}", std::process::id()));
+        let settings = ConsoleLogging {
+            path: dir.join("com1.log").to_string_lossy().to_string(),
+            com: 1,
+            max_bytes: 64,
+            keep: 2,
+        };
+        let mut log = ConsoleLog::open(&settings).unwrap();
+        /* a timestamp and a line take 34 bytes, every log keeps two lines */
+        for n in 0..7 {
+            log.write(format!("line {n}\n").as_bytes()).unwrap();
+        }
+        log.write(b"partial").unwrap();
+        log.write(b" line\n").unwrap();
+
+        let lines = |path: &str| -> Vec<String> {
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    let (stamp, line) = line.split_once("Z] ").unwrap();
+                    assert!(stamp.starts_with('[') && stamp.len() == 24, "{stamp}");
+                    line.to_string()
+                })
+                .collect()
+        };
+        assert_eq!(lines(&settings.path), ["line 6", "partial line"]);
+        assert_eq!(lines(&format!("{}.1", settings.path)), ["line 4", "line 5"]);
+        assert_eq!(lines(&format!("{}.2", settings.path)), ["line 2", "line 3"]);
+        assert!(!Path::new(&format!("{}.3", settings.path)).exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_millis(1_654_072_200_042);
+        assert_eq!(utc_timestamp(epoch), "2022-06-01T08:30:00.042Z");
+    }
+}
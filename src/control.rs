@@ -0,0 +1,415 @@
+use crate::console::{Consoles, QueuedSink, RawTerminal};
+use crate::util::run_dir;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The control socket of the supervisor of the VM `name`
+pub fn socket_path(name: &str) -> PathBuf {
+    run_dir().join(format!("{name}.sock"))
+}
+
+/// A request to the supervisor, sent as a line of text on the control socket.
+/// The supervisor answers with `ok` or `error MESSAGE` on a line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Attach to the console of a com port, as the writer or read-only. The
+    /// console output follows the answer, and the input of the writer is
+    /// passed to the guest until the connection is closed
+    Console { com: u8, write: bool },
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Request::Console { com, write } => {
+                write!(f, "console {com} {}", if *write { "rw" } else { "ro" })
+            }
+        }
+    }
+}
+
+impl FromStr for Request {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Request, String> {
+        match s.split_whitespace().collect::<Vec<_>>()[..] {
+            ["console", com, mode] => {
+                let com = com
+                    .parse()
+                    .map_err(|_| format!("invalid com port `{com}`"))?;
+                let write = match mode {
+                    "rw" => true,
+                    "ro" => false,
+                    _ => return Err(format!("invalid console mode `{mode}`")),
+                };
+                Ok(Request::Console { com, write })
+            }
+            _ => Err(format!("unknown request `{s}`")),
+        }
+    }
+}
+
+/// The control socket of a supervisor, removed when dropped
+pub struct ControlServer {
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Listen on `path` for the requests to the supervisor. A socket left by
+    /// a supervisor that is gone is replaced
+    pub fn start(path: &Path, consoles: Consoles) -> std::io::Result<ControlServer> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    "another supervisor of the VM is running",
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let consoles = consoles.clone();
+                std::thread::spawn(move || serve(stream, &consoles));
+            }
+        });
+        Ok(ControlServer {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve(stream: UnixStream, consoles: &Consoles) {
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+        return;
+    }
+    let mut answer = &stream;
+    match line.trim().parse::<Request>() {
+        Ok(Request::Console { com, write }) => {
+            if let Err(error) = serve_console(&stream, reader, consoles, com, write) {
+                let _ = writeln!(answer, "error {error}");
+            }
+        }
+        Err(error) => {
+            let _ = writeln!(answer, "error {error}");
+        }
+    }
+}
+
+/// The connection of a viewer as a sink of the console, closed once the
+/// console drops the sink, such that a detached viewer notices
+struct Viewer(UnixStream);
+
+impl Write for Viewer {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0.write(bytes)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Pass the console output to the viewer, and the input of the writer to the
+/// guest, until the viewer detaches
+fn serve_console(
+    stream: &UnixStream,
+    mut input: impl Read,
+    consoles: &Consoles,
+    com: u8,
+    write: bool,
+) -> Result<(), String> {
+    let hub = consoles.hub(com)?;
+    let viewer = stream.try_clone().map_err(|error| error.to_string())?;
+    /* a viewer that does not keep up is detached, see `QueuedSink` */
+    viewer
+        .set_write_timeout(Some(Duration::from_secs(1)))
+        .map_err(|error| error.to_string())?;
+    if write && !hub.claim_writer() {
+        return Err(format!("com{com} already has a writer, attach read-only"));
+    }
+    let mut answer = stream;
+    /* the answer goes before any console output */
+    let id = match writeln!(answer, "ok") {
+        Ok(()) => hub.subscribe(Box::new(QueuedSink::spawn(Viewer(viewer)))),
+        Err(error) => {
+            if write {
+                hub.release_writer();
+            }
+            return Err(error.to_string());
+        }
+    };
+
+    let mut buffer = [0u8; 256];
+    loop {
+        match input.read(&mut buffer) {
+            Ok(n) if n > 0 => {
+                /* the keys of a read-only viewer are dropped */
+                if write && hub.send(&buffer[..n]).is_err() {
+                    break;
+                }
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            _ => break,
+        }
+    }
+    if write {
+        hub.release_writer();
+    }
+    hub.unsubscribe(id);
+    Ok(())
+}
+
+/// Detection of the `~.` escape sequence in the keys typed by the operator,
+/// which is only recognised at the start of a line as in cu(1). `~~` sends a
+/// single `~`
+#[derive(Debug)]
+pub struct Escape {
+    line_start: bool,
+    tilde: bool,
+}
+
+impl Default for Escape {
+    fn default() -> Escape {
+        Escape {
+            line_start: true,
+            tilde: false,
+        }
+    }
+}
+
+impl Escape {
+    /// The keys to pass to the guest, and whether the operator detaches
+    pub fn filter(&mut self, keys: &[u8]) -> (Vec<u8>, bool) {
+        let mut output = Vec::with_capacity(keys.len());
+        for &key in keys {
+            if self.tilde {
+                self.tilde = false;
+                match key {
+                    b'.' => return (output, true),
+                    b'~' => {
+                        output.push(b'~');
+                        self.line_start = false;
+                        continue;
+                    }
+                    _ => output.push(b'~'),
+                }
+            } else if self.line_start && key == b'~' {
+                self.tilde = true;
+                continue;
+            }
+            output.push(key);
+            self.line_start = matches!(key, b'\r' | b'\n');
+        }
+        (output, false)
+    }
+}
+
+/// Attach the terminal to the console of com port `com` of the VM `name`
+/// through its supervisor, until the operator types `~.` or the supervisor
+/// exits
+pub fn attach(name: &str, com: u8, read_only: bool) -> std::io::Result<()> {
+    let path = socket_path(name);
+    let stream = UnixStream::connect(&path).map_err(|error| {
+        std::io::Error::new(
+            error.kind(),
+            format!(
+                "no supervisor of {name} listens on {}: {error}",
+                path.display()
+            ),
+        )
+    })?;
+    let mut request = &stream;
+    writeln!(
+        request,
+        "{}",
+        Request::Console {
+            com,
+            write: !read_only
+        }
+    )?;
+
+    let mut output = BufReader::new(stream.try_clone()?);
+    let mut answer = String::new();
+    output.read_line(&mut answer)?;
+    match answer.trim_end().strip_prefix("error ") {
+        Some(error) => return Err(std::io::Error::other(error.to_string())),
+        None if answer.trim_end() != "ok" => {
+            return Err(std::io::Error::other(format!(
+                "unexpected answer from the supervisor: {}",
+                answer.trim_end()
+            )))
+        }
+        None => (),
+    }
+
+    eprintln!(
+        "info: attached to com{com} of {name}{}, type ~. to detach",
+        if read_only { " read-only" } else { "" }
+    );
+    let raw_terminal = Arc::new(Mutex::new(RawTerminal::enter()));
+
+    let terminal = raw_terminal.clone();
+    std::thread::spawn(move || {
+        let mut stdout = std::io::stdout();
+        let mut buffer = [0u8; 4096];
+        while let Ok(n) = output.read(&mut buffer) {
+            if n == 0 || stdout.write_all(&buffer[..n]).is_err() {
+                break;
+            }
+            let _ = stdout.flush();
+        }
+        terminal.lock().unwrap().take();
+        eprintln!("\ninfo: the supervisor of the console exited");
+        std::process::exit(0);
+    });
+
+    let mut escape = Escape::default();
+    let mut stdin = std::io::stdin();
+    let mut input = &stream;
+    let mut buffer = [0u8; 256];
+    loop {
+        let n = stdin.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        let (keys, detach) = escape.filter(&buffer[..n]);
+        input.write_all(&keys)?;
+        if detach {
+            break;
+        }
+    }
+    raw_terminal.lock().unwrap().take();
+    eprintln!("\ninfo: detached from com{com} of {name}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::ComBackend;
+
+    #[test]
+    fn parse_requests() {
+        for request in [
+            Request::Console {
+                com: 1,
+                write: true,
+            },
+            Request::Console {
+                com: 2,
+                write: false,
+            },
+        ] {
+            assert_eq!(request.to_string().parse::<Request>(), Ok(request));
+        }
+        assert!("console 1 rx".parse::<Request>().is_err());
+        assert!("stop".parse::<Request>().is_err());
+    }
+
+    #[test]
+    fn escape_at_line_start() {
+        let mut escape = Escape::default();
+        assert_eq!(escape.filter(b"ls ~.\r"), (b"ls ~.\r".to_vec(), false));
+        assert_eq!(escape.filter(b"~~"), (b"~".to_vec(), false));
+        assert_eq!(escape.filter(b"\r~x"), (b"\r~x".to_vec(), false));
+        /* the escape can be split across reads */
+        assert_eq!(escape.filter(b"\n~"), (b"\n".to_vec(), false));
+        assert_eq!(escape.filter(b".ignored"), (vec![], true));
+    }
+
+    #[test]
+    fn one_writer_many_viewers() {
+        let dir = std::env::temp_dir().join(format!("vmrun-control-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.sock");
+        /* a fifo stands for the peer of the nmdm pair: the keys typed into it
+         * come back as the output of the guest
+         */
+        let device = dir.join("nmdm0A").to_string_lossy().to_string();
+        let fifo = std::ffi::CString::new(format!("{}B", &device[..device.len() - 1])).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+        let consoles = Consoles::default();
+        consoles.set_ports([(1, &ComBackend::Stdio), (2, &ComBackend::Device(device))].into_iter());
+        let _server = ControlServer::start(&path, consoles.clone()).unwrap();
+        assert!(ControlServer::start(&path, Consoles::default()).is_err());
+
+        let attach = |request: &str| {
+            let stream = UnixStream::connect(&path).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            writeln!(&stream, "{request}").unwrap();
+            let mut answer = vec![];
+            /* byte by byte, the console output follows the answer */
+            let mut byte = [0u8; 1];
+            while answer.last() != Some(&b'\n') {
+                (&stream).read_exact(&mut byte).unwrap();
+                answer.push(byte[0]);
+            }
+            (stream, String::from_utf8(answer).unwrap())
+        };
+        let request = |request: &str| attach(request).1;
+        assert_eq!(
+            request("console 1 ro"),
+            "error com1 is on stdio, which is not attachable\n"
+        );
+        assert_eq!(request("console 3 rw"), "error com3 is not configured\n");
+        assert!(request("reboot").starts_with("error unknown request"));
+
+        let (first, answer) = attach("console 2 ro");
+        assert_eq!(answer, "ok\n");
+        let (second, answer) = attach("console 2 ro");
+        assert_eq!(answer, "ok\n");
+        let (mut writer, answer) = attach("console 2 rw");
+        assert_eq!(answer, "ok\n");
+        assert_eq!(
+            request("console 2 rw"),
+            "error com2 already has a writer, attach read-only\n"
+        );
+
+        /* the keys of the writer reach the guest, and every viewer sees the
+         * output
+         */
+        writer.write_all(b"root\r").unwrap();
+        for mut viewer in [&first, &second, &writer] {
+            let mut output = [0u8; 5];
+            viewer.read_exact(&mut output).unwrap();
+            assert_eq!(&output, b"root\r");
+        }
+
+        /* the seat of writer is free once the writer detaches */
+        drop(writer);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while attach("console 2 rw").1 != "ok\n" {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
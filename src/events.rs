@@ -0,0 +1,259 @@
+use crate::util::assertion::Assertion;
+use crate::vm::recovery::Recovery;
+use serde::Serialize;
+use std::io::Write;
+
+/// A lifecycle step of the supervisor. Each event is written as a single
+/// JSON object per line, tagged by the `event` field.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    ConfigLoaded {
+        source: &'a str,
+    },
+    TargetSelected {
+        target: Option<&'a str>,
+        next_target: Option<&'a str>,
+    },
+    Preconditions {
+        scope: &'a str,
+        passed: bool,
+        assertion: Option<&'a Assertion>,
+    },
+    RecoveryApplied {
+        scope: &'a str,
+        assertion: &'a Assertion,
+    },
+    RecoveryReverted {
+        recovery: &'a Recovery,
+        error: Option<String>,
+    },
+    ConsoleAttached {
+        com: u8,
+        backend: &'a str,
+        /// What the operator connects to, `None` for stdio
+        peer: Option<&'a str>,
+    },
+    LoaderExited {
+        program: &'a str,
+        argv: &'a [String],
+        /// `None` if the loader is terminated by a signal
+        code: Option<i32>,
+    },
+    BhyveSpawned {
+        argv: &'a [String],
+        pid: u32,
+    },
+    ConsoleTriggered {
+        com: u8,
+        pattern: &'a str,
+        action: &'a str,
+        /// The text of the console output matching the pattern
+        matched: &'a str,
+    },
+    BootScriptFinished {
+        com: u8,
+        /// The failed step, from 1, `None` once every step ran
+        failed_step: Option<usize>,
+        error: Option<&'a str>,
+        action: Option<&'a str>,
+    },
+    BhyveExited {
+        /// `None` if bhyve is terminated by a signal
+        code: Option<i32>,
+    },
+    Cleanup {
+        resource: String,
+        error: Option<String>,
+    },
+    RebootDecision {
+        exit_code: i32,
+        reboot: bool,
+        reboot_count: usize,
+        next_target: Option<&'a str>,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    ts: f64,
+    vm: &'a str,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Append-only JSON Lines event log. A log without sink silently discards
+/// all events such that the supervisor does not need to special case it.
+pub struct EventLog {
+    vm: String,
+    sink: Option<Box<dyn Write>>,
+}
+
+impl EventLog {
+    pub fn discard() -> EventLog {
+        EventLog {
+            vm: String::new(),
+            sink: None,
+        }
+    }
+
+    /// Open an event log at `dest`, if `dest` is `-`, events are written to
+    /// stderr instead.
+    pub fn open(dest: &str) -> std::io::Result<EventLog> {
+        let sink: Box<dyn Write> = if dest == "-" {
+            Box::new(std::io::stderr())
+        } else {
+            Box::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(dest)?,
+            )
+        };
+
+        Ok(EventLog {
+            vm: String::new(),
+            sink: Some(sink),
+        })
+    }
+
+    pub fn set_vm_name(&mut self, name: &str) {
+        self.vm = name.to_string();
+    }
+
+    pub fn emit(&mut self, event: Event) {
+        if let Some(sink) = &mut self.sink {
+            let ts = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0);
+            let record = Record {
+                ts,
+                vm: &self.vm,
+                event: &event,
+            };
+
+            // Failing to write the event log should never take down the VM
+            let result = serde_json::to_writer(&mut *sink, &record)
+                .map_err(std::io::Error::from)
+                .and_then(|_| sink.write_all(b"\n"))
+                .and_then(|_| sink.flush());
+
+            if let Err(error) = result {
+                eprintln!("warn: cannot write event log: {error}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    /// A sink the test can read back once the log is done with it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        let buffer = Shared::default();
+        let mut log = EventLog {
+            vm: String::new(),
+            sink: Some(Box::new(buffer.clone())),
+        };
+        log.set_vm_name("vm0");
+
+        let assertion = Assertion::Container(vec![
+            (
+                "tap".to_string(),
+                Assertion::Recoverable(
+                    "tap0".to_string(),
+                    "tap0 does not exist".to_string(),
+                    Recovery::CreateTap("tap0".to_string()),
+                ),
+            ),
+            (
+                "disk".to_string(),
+                Assertion::Fatal("disk.img".to_string(), "no such file".to_string()),
+            ),
+        ]);
+        log.emit(Event::ConfigLoaded { source: "vm.json" });
+        log.emit(Event::Preconditions {
+            scope: "launch",
+            passed: false,
+            assertion: Some(&assertion),
+        });
+        log.emit(Event::RebootDecision {
+            exit_code: 0,
+            reboot: true,
+            reboot_count: 1,
+            next_target: None,
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        for line in &lines {
+            assert_eq!(line["vm"], "vm0");
+            assert!(line["ts"].as_f64().is_some_and(|ts| ts > 0.0));
+        }
+
+        assert_eq!(lines[0]["event"], "config_loaded");
+        assert_eq!(lines[0]["source"], "vm.json");
+
+        assert_eq!(lines[1]["event"], "preconditions");
+        assert_eq!(lines[1]["scope"], "launch");
+        assert_eq!(lines[1]["passed"], false);
+        assert_eq!(
+            lines[1]["assertion"],
+            json!({
+                "kind": "container",
+                "recoverable": false,
+                "items": [
+                    {
+                        "name": "tap",
+                        "assertion": {
+                            "kind": "recoverable",
+                            "scope": "tap0",
+                            "reason": "tap0 does not exist",
+                            "recovery": {
+                                "name": "create-tap",
+                                "description": "create tap interface tap0",
+                                "script": ["ifconfig tap create name tap0"],
+                            },
+                        },
+                    },
+                    {
+                        "name": "disk",
+                        "assertion": {
+                            "kind": "fatal",
+                            "scope": "disk.img",
+                            "reason": "no such file",
+                        },
+                    },
+                ],
+            })
+        );
+
+        assert_eq!(lines[2]["event"], "reboot_decision");
+        assert_eq!(lines[2]["exit_code"], 0);
+        assert_eq!(lines[2]["reboot"], true);
+        assert_eq!(lines[2]["reboot_count"], 1);
+        assert_eq!(lines[2]["next_target"], Value::Null);
+    }
+}
@@ -0,0 +1,3 @@
+//! Parts of vmrun that are useful to other tools managing bhyve VMs
+
+pub mod bhyve;
@@ -1,14 +1,30 @@
+mod console;
+mod control;
+mod events;
+mod script;
 mod spec;
 mod util;
 mod vm;
 
-use clap::Parser;
+use clap::{ArgEnum, Args, CommandFactory, Parser, Subcommand};
+use console::{ConsoleEvent, ConsoleHub, Consoles, TriggerSink};
+use events::{Event, EventLog};
 use spec::FormatError;
+use std::ffi::OsString;
 use std::io::{Read, Write};
+use std::path::Path;
 use std::process;
+use std::sync::mpsc;
+use std::time::Duration;
 use thiserror::Error;
 use util::assertion::Assertion;
-use vm::BhyveDev;
+use util::os::plan::PlanHost;
+use util::os::tools::ToolPaths;
+use util::os::{Host, NativeHost};
+use vm::backend::{Backend, BhyveProcess, SimulationScript, Simulator};
+use vm::recovery::{Recovery, RecoveryError};
+use vm::{BhyveDev, ComBackend, TriggerAction};
+use vmrun::bhyve::{self, BhyveCommand};
 
 #[derive(Error, Debug)]
 enum VmRunError {
@@ -20,6 +36,26 @@ enum VmRunError {
     PreconditionFailure(String),
     #[error("{0}")]
     IoError(std::io::Error),
+    #[error("{0}")]
+    RecoveryFailure(RecoveryError),
+    #[error("recovery for `{recoveries}` ran but condition still failing:\n{assertion}")]
+    RecoveryIneffective {
+        recoveries: String,
+        assertion: String,
+    },
+    #[error(
+        "not on a bhyve host, only `run` with --dry-run, --debug or --simulate is available in plan mode"
+    )]
+    PlanMode,
+    #[error("{program} failed to load the guest, {}", match .code {
+        Some(code) => format!("exited with {code}"),
+        None => "terminated by signal".to_string(),
+    })]
+    LoaderFailed { program: String, code: Option<i32> },
+    #[error("{reason}, the VM is marked failed")]
+    GuestFailed { reason: String },
+    #[error("a supervisor of `{0}` is already running")]
+    AlreadySupervised(String),
 }
 
 /* To work around clap */
@@ -36,7 +72,14 @@ impl<T: PartialEq> ArgVec<T> {
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
-struct Arguments {
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+/// Options selecting and building the VM, common to all subcommands
+#[derive(Args, Debug)]
+struct VmArgs {
     /// Boot wth a target (boot option) specified in the config file
     #[clap(short, long)]
     target: Option<String>,
@@ -46,6 +89,36 @@ struct Arguments {
     #[clap(short, long, value_name = "FILE")]
     config: String,
 
+    /// Override a field of the configuration before it is loaded, for example
+    /// `mem=4G`, `emulations[2].path=/iso/new.iso` or
+    /// `targets.install.next_target=default`. The value is parsed as JSON, or
+    /// taken as a string if it is not valid JSON
+    #[clap(long, value_name = "PATH=VALUE", multiple_occurrences = true)]
+    set: Vec<String>,
+
+    /// A JSON file of the paths of the host utilities vmrun runs, for example
+    /// {"bhyve": "/usr/sbin/bhyve"}. Defaults to `VMRUN_HOST_PROFILE`
+    #[clap(long, value_name = "FILE")]
+    host_profile: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "NAME=PATH",
+        multiple_occurrences = true,
+        help = ToolPaths::override_help()
+    )]
+    tool: Vec<String>,
+
+    /// arguments that get passed directly to bhyve
+    #[clap(raw = true, value_name = "BHYVE_ARGS")]
+    extra_bhyve_args: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    #[clap(flatten)]
+    vm: VmArgs,
+
     /// Do not follow reboots initiated by the guest
     #[clap(long)]
     no_reboot: bool,
@@ -84,13 +157,183 @@ struct Arguments {
     #[clap(long)]
     no_requirement_check: bool,
 
+    /// Treat warnings from the requirement check as fatal failures
+    #[clap(long)]
+    strict: bool,
+
+    /// Print the known fixes to the failed requirements as a shell script and
+    /// exit
+    #[clap(long)]
+    print_fixes: bool,
+
+    /// Do not revert the host changes made by the fixes when the VM stops
+    #[clap(long)]
+    keep_fixes: bool,
+
+    /// Maximum number of times the fixes to a failed requirement are applied
+    /// before giving up
+    #[clap(long, default_value = "3")]
+    recovery_attempts: usize,
+
     /// Dump the resultant configuration and exit
     #[clap(long)]
     debug: bool,
 
-    /// arguments that get passed directly to bhyve
-    #[clap(raw = true, value_name = "BHYVE_ARGS")]
-    extra_bhyve_args: Vec<String>,
+    /// Do not launch bhyve, but simulate the VM exiting with the exit codes in
+    /// order, separate by ",". A code can be followed by `@SECONDS` to delay
+    /// the exit, for example 0@1.5,0,1. Nothing on the host is changed
+    #[clap(long, value_name = "EXIT_CODES")]
+    simulate: Option<SimulationScript>,
+
+    /// Append lifecycle events as JSON Lines to the specified file. If the
+    /// value is `-`, events are written to stderr instead.
+    #[clap(long, value_name = "FILE")]
+    event_log: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Launch and supervise the VM. This is the default if no subcommand is
+    /// given
+    Run(RunArgs),
+
+    /// Check the preconditions of the VM without launching bhyve
+    Check {
+        #[clap(flatten)]
+        vm: VmArgs,
+
+        /// Treat warnings as fatal failures
+        #[clap(long)]
+        strict: bool,
+
+        /// Output format of the report
+        #[clap(long, arg_enum, default_value = "text")]
+        format: OutputFormat,
+    },
+
+    /// Print the bhyve command of the VM without launching bhyve
+    Args {
+        #[clap(flatten)]
+        vm: VmArgs,
+
+        /// Parse the arguments back with the bhyve grammar, and report the
+        /// arguments bhyve would reject or that do not match the configuration
+        #[clap(long)]
+        verify: bool,
+
+        /// Print the command as a line of text, a shell script with the
+        /// arguments quoted, or a JSON array
+        #[clap(long, arg_enum, default_value = "text")]
+        format: ArgsFormat,
+
+        /// Annotate each argument with where it comes from: a field of the
+        /// configuration or a target, an override, a default of vmrun or the
+        /// command line
+        #[clap(long)]
+        explain: bool,
+    },
+
+    /// Print the configuration of the VM resolved with the target
+    Show {
+        #[clap(flatten)]
+        vm: VmArgs,
+    },
+
+    /// Check if the configuration can be built, without accessing the host
+    Validate {
+        #[clap(flatten)]
+        vm: VmArgs,
+    },
+
+    /// Print the PCI slot of every device of the VM
+    Slots {
+        #[clap(flatten)]
+        vm: VmArgs,
+
+        /// Print the slots of the root configuration and of every target
+        #[clap(long)]
+        all: bool,
+    },
+
+    /// Print the differences of the VM between two targets
+    Diff {
+        #[clap(flatten)]
+        vm: VmArgs,
+
+        /// The target to compare from, defaults to `--target` or the root
+        /// configuration
+        #[clap(long, value_name = "TARGET")]
+        from: Option<String>,
+
+        /// The target to compare to
+        #[clap(long, value_name = "TARGET")]
+        to: String,
+    },
+
+    /// Attach to the serial console of a running VM through its supervisor.
+    /// Type `~.` at the start of a line to detach
+    Console {
+        /// The name of the VM
+        name: String,
+
+        /// The com port to attach to
+        #[clap(long, default_value = "1")]
+        com: u8,
+
+        /// Watch the console without typing into it, any number of viewers
+        /// can attach read-only next to the single writer
+        #[clap(long)]
+        read_only: bool,
+    },
+}
+
+impl Command {
+    /// The options of the VM, `None` for the subcommands taking the VM from
+    /// its supervisor instead of the configuration
+    fn vm_args(&self) -> Option<&VmArgs> {
+        match self {
+            Command::Run(args) => Some(&args.vm),
+            Command::Check { vm, .. }
+            | Command::Args { vm, .. }
+            | Command::Show { vm }
+            | Command::Validate { vm }
+            | Command::Slots { vm, .. }
+            | Command::Diff { vm, .. } => Some(vm),
+            Command::Console { .. } => None,
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ArgsFormat {
+    Text,
+    Shell,
+    Json,
+}
+
+/// `vmrun -c vm.json ...` without a subcommand is the same as `vmrun run -c
+/// vm.json ...`, such that the existing scripts keep working
+fn with_default_command(args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut args: Vec<OsString> = args.collect();
+    let explicit = match args.get(1).and_then(|arg| arg.to_str()) {
+        None => true,
+        Some(arg) => {
+            ["-h", "--help", "-V", "--version", "help"].contains(&arg)
+                || Cli::command()
+                    .get_subcommands()
+                    .any(|command| command.get_name() == arg)
+        }
+    };
+    if !explicit {
+        args.insert(1, OsString::from("run"));
+    }
+    args
 }
 
 fn arg_to_vec(s: &str) -> Result<ArgVec<i32>, &'static str> {
@@ -113,72 +356,504 @@ fn ask_yesno(question: String) -> bool {
     buffer.starts_with('Y') || buffer.starts_with('y')
 }
 
-fn vm_main(args: &Arguments, vm: &spec::VmSpec) -> Result<i32, VmRunError> {
+/// Promote warnings to fatal failures if `--strict` is specified
+fn strictly(strict: bool, result: Result<(), Assertion>) -> Result<(), Assertion> {
+    if strict {
+        result.map_err(Assertion::promote_warnings)
+    } else {
+        result
+    }
+}
+
+/// Check the condition and apply the recoveries if approved, the condition is
+/// checked again after each application until it passes, or fails in a way
+/// that the recoveries cannot help
+fn recover_condition(
+    args: &RunArgs,
+    host: &dyn Host,
+    want_fix: bool,
+    cond: &dyn vm::conditions::Condition,
+    events: &mut EventLog,
+    undo: &mut Vec<Recovery>,
+) -> Result<(), VmRunError> {
+    let scope = cond.name();
+    let mut attempts = 0;
+    let mut approved = want_fix || args.force;
+    let mut applied: Vec<&'static str> = vec![];
+
+    loop {
+        let result = strictly(args.strict, cond.check(host));
+        events.emit(Event::Preconditions {
+            scope: &scope,
+            passed: result.is_ok(),
+            assertion: result.as_ref().err(),
+        });
+
+        let assertion = match result {
+            Ok(()) => return Ok(()),
+            Err(assertion) => assertion,
+        };
+
+        let fixable = assertion.is_recoverable() && assertion.has_recovery();
+        /* only warnings left after a recovery, the final check reports them */
+        let settled = assertion.is_recoverable() && !assertion.has_recovery();
+
+        if attempts > 0 && !settled && (!fixable || attempts >= args.recovery_attempts) {
+            return Err(VmRunError::RecoveryIneffective {
+                recoveries: applied.join(", "),
+                assertion: assertion.print(scope),
+            });
+        }
+
+        if !fixable {
+            /* leave it to the final check to report */
+            return Ok(());
+        }
+
+        approved = approved || ask_yesno(assertion.recovery_prompt());
+        if !approved {
+            return Ok(());
+        }
+
+        for (_, recovery) in assertion.recoveries(scope.to_string()) {
+            if !applied.contains(&recovery.name()) {
+                applied.push(recovery.name());
+            }
+        }
+
+        /* a recovery applied again is reverted once */
+        let mut reverts = vec![];
+        let recovered = assertion.recover(host, &mut reverts);
+        for revert in reverts {
+            if !undo.contains(&revert) {
+                undo.push(revert);
+            }
+        }
+        recovered.map_err(VmRunError::RecoveryFailure)?;
+        events.emit(Event::RecoveryApplied {
+            scope: &scope,
+            assertion: &assertion,
+        });
+        attempts += 1;
+    }
+}
+
+fn vm_main(
+    args: &RunArgs,
+    host: &dyn Host,
+    backend: &mut dyn Backend,
+    vm: &spec::VmSpec,
+    events: &mut EventLog,
+) -> Result<i32, VmRunError> {
+    if !util::os::BHYVE_HOST && !args.dry_run && !args.debug && !backend.is_simulated() {
+        return Err(VmRunError::PlanMode);
+    }
+
+    let mut undo = vec![];
+    let result = vm_supervise(args, host, backend, vm, events, &mut undo);
+
+    /* revert the host changes made by the recoveries in reverse order */
+    while let Some(recovery) = undo.pop() {
+        if args.keep_fixes {
+            break;
+        }
+        let result = recovery.apply(host);
+        events.emit(Event::RecoveryReverted {
+            recovery: &recovery,
+            error: result.as_ref().err().map(|error| error.to_string()),
+        });
+        if let Err(error) = result {
+            eprintln!("warn: cannot revert host change: {error}");
+        }
+    }
+
+    result
+}
+
+/// Subscribe the triggers of every com port to its console, the triggers
+/// are reported to `triggered` by their index in the VM
+fn watch_consoles(
+    vmrun: &vm::VmRun,
+    consoles: &Consoles,
+    triggered: &mpsc::Sender<ConsoleEvent>,
+) -> Result<Vec<(u8, std::sync::Arc<ConsoleHub>, u64)>, VmRunError> {
+    let mut watches = vec![];
+    let mut coms: Vec<u8> = vmrun.console_triggers.iter().map(|t| t.com).collect();
+    coms.sort();
+    coms.dedup();
+    for com in coms {
+        let triggers = vmrun
+            .console_triggers
+            .iter()
+            .enumerate()
+            .filter(|(_, trigger)| trigger.com == com)
+            .map(|(index, trigger)| (index, trigger.pattern.clone()))
+            .collect();
+        let sink = TriggerSink::new(triggers, triggered.clone());
+        let (hub, id) = consoles
+            .watch(com, Box::new(sink))
+            .map_err(|error| VmRunError::IoError(std::io::Error::other(error)))?;
+        watches.push((com, hub, id));
+    }
+    Ok(watches)
+}
+
+/// Act on the console of com port `com`, for a console trigger matching
+/// `matched` in the output of the VM or a failed boot script
+fn run_action(
+    vmrun: &vm::VmRun,
+    action: &TriggerAction,
+    com: u8,
+    matched: Option<&str>,
+    hub: Option<&ConsoleHub>,
+    instance: &mut dyn vm::backend::Instance,
+) -> std::io::Result<()> {
+    match action {
+        TriggerAction::Stop { .. } => instance.force_exit(false),
+        TriggerAction::Reset | TriggerAction::SwitchTarget { .. } => instance.force_exit(true),
+        TriggerAction::Send { keys } => match hub {
+            Some(hub) => hub.send(keys.as_bytes()),
+            None => Err(std::io::ErrorKind::NotConnected.into()),
+        },
+        TriggerAction::Hook { command } => {
+            let mut hook = process::Command::new("/bin/sh");
+            hook.args(["-c", command])
+                .env("VMRUN_VM", &vmrun.name)
+                .env("VMRUN_COM", com.to_string());
+            if let Some(matched) = matched {
+                hook.env("VMRUN_MATCH", matched);
+            }
+            let mut hook = hook.spawn()?;
+            /* the hook runs next to the VM, reaped once it exits */
+            std::thread::spawn(move || hook.wait());
+            Ok(())
+        }
+    }
+}
+
+/// The nmdm pairs newly recorded by the supervisor, released once it exits
+struct NmdmPairs<'a> {
+    host: &'a dyn Host,
+    pairs: Vec<u32>,
+}
+
+impl Drop for NmdmPairs<'_> {
+    fn drop(&mut self) {
+        for n in self.pairs.iter() {
+            if let Err(error) = self.host.release_nmdm(*n) {
+                eprintln!("warn: cannot release nmdm pair {n}: {error}");
+            }
+        }
+    }
+}
+
+fn vm_supervise(
+    args: &RunArgs,
+    host: &dyn Host,
+    backend: &mut dyn Backend,
+    vm: &spec::VmSpec,
+    events: &mut EventLog,
+    undo: &mut Vec<Recovery>,
+) -> Result<i32, VmRunError> {
+    /* pin the nmdm pairs such that they stay the same across reboots */
+    let mut vm = vm.clone();
+    let claim = !(args.dry_run || args.debug || backend.is_simulated());
+    /* the records and the instance belong to the supervisor already running */
+    if claim && host.supervisor_running(&vm.name).unwrap_or(false) {
+        return Err(VmRunError::AlreadySupervised(vm.name.clone()));
+    }
+    let _nmdm_pairs = NmdmPairs {
+        host,
+        pairs: vm
+            .allocate_consoles(host, claim)
+            .map_err(VmRunError::SpecErr)?,
+    };
+    let vm = &vm;
+
     let mut spec = vm.clone();
+    let mut consoles_reported = vec![];
+    let consoles = Consoles::default();
+    let control_path = control::socket_path(&vm.name);
+    /* the consoles are attached with `vmrun console` through the control
+     * socket, which is opened once the checks of the first launch, which
+     * look for a running supervisor, are done
+     */
+    let mut control = None;
     let mut reboot_count = 0;
-    let mut next_target = args.target.clone();
+    let mut next_target = args.vm.target.clone();
     let mut exit_code: i32;
 
     fn vm_run_session(
-        args: &Arguments,
+        args: &RunArgs,
+        host: &dyn Host,
+        backend: &mut dyn Backend,
         spec: &spec::VmSpec,
         vmrun: &vm::VmRun,
-    ) -> Result<i32, VmRunError> {
+        events: &mut EventLog,
+        consoles: &Consoles,
+    ) -> Result<(i32, Option<(TriggerAction, String)>), VmRunError> {
         let bootargs = vmrun.bhyve_args().map_err(VmRunError::VmErr)?;
-        let hyve = std::option_env!("BHYVE_EXEC").unwrap_or("bhyve");
 
         if args.debug {
             eprintln!("{:#?}", vmrun);
         }
 
         if args.dry_run || args.debug {
-            eprint!("{} ", hyve);
+            if let Some(loader) = &vmrun.loader {
+                eprintln!("{} {}", loader.program(), loader.args(vmrun).join(" "));
+            }
+            eprint!("{} ", backend.program());
             for arg in bootargs {
                 eprint!("{} ", arg);
             }
             eprintln!();
-            return Ok(0);
+            return Ok((0, None));
         }
 
         let pid_file = match &args.vm_pid_file {
-            Some(pid_file) => Some(open_pid_file(pid_file)?),
+            Some(pid_file) if !backend.is_simulated() => Some(open_pid_file(pid_file)?),
+            _ => None,
+        };
+
+        /* the loader creates the VM instance and loads the guest into it */
+        if let Some(loader) = &vmrun.loader {
+            for (path, content) in loader.generated_files(vmrun) {
+                if backend.is_simulated() {
+                    eprintln!("simulate: write {}", path.display());
+                    continue;
+                }
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir).map_err(VmRunError::IoError)?;
+                }
+                std::fs::write(&path, content).map_err(VmRunError::IoError)?;
+            }
+            let argv = loader.args(vmrun);
+            let code = backend
+                .run(loader.program(), &argv)
+                .map_err(VmRunError::IoError)?;
+            events.emit(Event::LoaderExited {
+                program: loader.program(),
+                argv: &argv,
+                code,
+            });
+            if code != Some(0) {
+                /* do not leave the half loaded instance behind */
+                if !backend.is_simulated() && host.vmm_exists(&vmrun.name).unwrap_or(false) {
+                    if let Err(error) = host.destroy_vm(&vmrun.name) {
+                        eprintln!("warn: cannot destroy VM instance: {error}");
+                    }
+                }
+                return Err(VmRunError::LoaderFailed {
+                    program: loader.program().to_string(),
+                    code,
+                });
+            }
+        }
+
+        /* the com port the console log is taken from, if any */
+        let logged_console = vmrun.console_log.as_ref().and_then(|settings| {
+            vmrun
+                .com_port(settings.com)
+                .map(|backend| (settings, backend.peer()))
+        });
+        let logged_stdio = logged_console
+            .as_ref()
+            .and_then(|(settings, peer)| peer.is_none().then_some(*settings));
+        let watched_stdio = vmrun
+            .console_triggers
+            .iter()
+            .any(|trigger| vmrun.com_port(trigger.com) == Some(&ComBackend::Stdio));
+
+        /* watch the consoles before bhyve is launched to see all the output */
+        let (triggered, fired) = mpsc::channel();
+        let mut watches = if backend.is_simulated() {
+            for trigger in vmrun.console_triggers.iter() {
+                eprintln!(
+                    "simulate: watch com{} for `{}`",
+                    trigger.com, trigger.pattern
+                );
+            }
+            vec![]
+        } else {
+            watch_consoles(vmrun, consoles, &triggered)?
+        };
+        let mut script_writer = None;
+        let script_console = match &vmrun.boot_script {
+            Some(script) if backend.is_simulated() => {
+                eprintln!(
+                    "simulate: run boot script of {} steps on com{}",
+                    script.steps.len(),
+                    script.com
+                );
+                None
+            }
+            Some(script) => {
+                let (output, received) = mpsc::channel();
+                let (hub, id) = consoles
+                    .watch(script.com, Box::new(script::ChannelSink(output)))
+                    .map_err(|error| VmRunError::IoError(std::io::Error::other(error)))?;
+                watches.push((script.com, hub.clone(), id));
+                /* keep the operator from typing into the script */
+                if hub.claim_writer() {
+                    script_writer = Some(hub.clone());
+                } else {
+                    eprintln!(
+                        "warn: com{} already has a writer, whose keys mix with the boot script",
+                        script.com
+                    );
+                }
+                Some((script.clone(), hub, received))
+            }
             None => None,
         };
 
-        let dev = std::path::PathBuf::from(format!("/dev/vmm/{}", vmrun.name));
-        if dev.exists() && args.force {
-            std::process::Command::new("bhyvectl")
-                .arg("--destroy")
-                .arg(format!("--vm={}", vmrun.name))
-                .spawn()
-                .ok()
-                .unwrap();
+        let mut instance = backend
+            .spawn(&bootargs, logged_stdio.is_some() || watched_stdio)
+            .map_err(VmRunError::IoError)?;
+
+        let mut console_tee = None;
+        if let Some(output) = instance.take_console() {
+            console_tee = Some(
+                consoles
+                    .capture_output(logged_stdio, output)
+                    .map_err(VmRunError::IoError)?,
+            );
+        }
+        match logged_console {
+            Some((settings, _)) if backend.is_simulated() => {
+                eprintln!("simulate: log com{} to {}", settings.com, settings.path)
+            }
+            Some((settings, Some(peer))) => consoles
+                .log_peer(settings, &peer)
+                .map_err(VmRunError::IoError)?,
+            _ => (),
         }
 
-        let mut process = std::process::Command::new(hyve)
-            .args(&bootargs)
-            .spawn()
-            .ok()
-            .unwrap();
+        events.emit(Event::BhyveSpawned {
+            argv: &bootargs,
+            pid: instance.id(),
+        });
+        let script_run = script_console
+            .map(|(script, hub, output)| script::ScriptRun::start(script, hub, output, triggered));
 
         if let Some(mut pid_file) = pid_file {
             pid_file
-                .write(process.id().to_string().as_bytes())
+                .write(instance.id().to_string().as_bytes())
                 .map_err(VmRunError::IoError)?;
         }
 
         if let Some(action) = &spec.post_start_script {
-            let args: Vec<&str> = action.split(' ').collect();
-            let mut p = std::process::Command::new(args[0])
-                .args(&args[1..])
-                .spawn()
-                .ok()
-                .unwrap();
-            p.wait().ok().unwrap();
+            if backend.is_simulated() {
+                eprintln!("simulate: post start script: {action}");
+            } else {
+                let args: Vec<&str> = action.split(' ').collect();
+                let mut p = std::process::Command::new(args[0])
+                    .args(&args[1..])
+                    .spawn()
+                    .ok()
+                    .unwrap();
+                p.wait().ok().unwrap();
+            }
         }
 
-        let exit_status = process.wait().ok().unwrap();
-        Ok(exit_status.code().unwrap())
+        let mut stopped_by = None;
+        let code = if watches.is_empty() {
+            instance.wait().map_err(VmRunError::IoError)?
+        } else {
+            loop {
+                if let Some(code) = instance.try_wait().map_err(VmRunError::IoError)? {
+                    break code;
+                }
+                let event = match fired.recv_timeout(Duration::from_millis(100)) {
+                    Ok(event) => event,
+                    Err(_) => continue,
+                };
+                /* the VM is on its way out */
+                if stopped_by.is_some() {
+                    continue;
+                }
+                let (com, action, matched, reason) = match event {
+                    ConsoleEvent::Triggered(index, matched) => {
+                        let trigger = &vmrun.console_triggers[index];
+                        events.emit(Event::ConsoleTriggered {
+                            com: trigger.com,
+                            pattern: trigger.pattern.as_str(),
+                            action: trigger.action.name(),
+                            matched: &matched,
+                        });
+                        eprintln!(
+                            "info: com{} matched `{}`, {}",
+                            trigger.com,
+                            trigger.pattern,
+                            trigger.action.name()
+                        );
+                        let reason = format!(
+                            "the guest printed `{}` on com{}",
+                            trigger.pattern, trigger.com
+                        );
+                        (trigger.com, trigger.action.clone(), Some(matched), reason)
+                    }
+                    ConsoleEvent::ScriptFinished(result) => {
+                        let com = vmrun.boot_script.as_ref().map_or(0, |script| script.com);
+                        events.emit(Event::BootScriptFinished {
+                            com,
+                            failed_step: result.as_ref().err().map(|failure| failure.step),
+                            error: result.as_ref().err().map(|failure| failure.reason.as_str()),
+                            action: result.as_ref().err().map(|failure| failure.action.name()),
+                        });
+                        let failure = match result {
+                            Ok(()) => {
+                                eprintln!("info: the boot script on com{com} ran to its end");
+                                continue;
+                            }
+                            Err(failure) => failure,
+                        };
+                        eprintln!(
+                            "warn: step {} of the boot script failed: {}, {}",
+                            failure.step,
+                            failure.reason,
+                            failure.action.name()
+                        );
+                        let reason = format!(
+                            "step {} of the boot script failed: {}",
+                            failure.step, failure.reason
+                        );
+                        (com, failure.action, None, reason)
+                    }
+                };
+                let hub = watches
+                    .iter()
+                    .find(|(watched, _, _)| *watched == com)
+                    .map(|(_, hub, _)| hub.as_ref());
+                if let Err(error) = run_action(
+                    vmrun,
+                    &action,
+                    com,
+                    matched.as_deref(),
+                    hub,
+                    instance.as_mut(),
+                ) {
+                    eprintln!("warn: cannot {} ({reason}): {error}", action.name());
+                } else if action.ends_run() {
+                    stopped_by = Some((action, reason));
+                }
+            }
+        };
+        if let Some(script_run) = script_run {
+            script_run.cancel();
+        }
+        if let Some(hub) = script_writer {
+            hub.release_writer();
+        }
+        for (_, hub, id) in watches {
+            hub.unsubscribe(id);
+        }
+        if let Some(console_tee) = console_tee {
+            let _ = console_tee.join();
+        }
+        events.emit(Event::BhyveExited { code });
+        /* treat a VM terminated by signal as crashed */
+        Ok((code.unwrap_or(4), stopped_by))
     }
 
     loop {
@@ -195,30 +870,51 @@ fn vm_main(args: &Arguments, vm: &spec::VmSpec) -> Result<i32, VmRunError> {
             }
         }
 
+        events.emit(Event::TargetSelected {
+            target: next_target.as_deref(),
+            next_target: spec.next_target.as_deref(),
+        });
+
         next_target = spec.next_target.clone();
 
         let vmrun = spec
-            .build(&args.extra_bhyve_args)
+            .build(host, &args.vm.extra_bhyve_args)
             .map_err(VmRunError::SpecErr)?;
+        consoles.set_ports(vmrun.com_ports());
+
+        /* the next launch keeps the slots even if devices are added */
+        if claim && !vmrun.stable_slots.is_empty() {
+            if let Err(error) = host.record_slots(&vm.name, &vmrun.stable_slots) {
+                eprintln!("warn: cannot record the PCI slots: {error}");
+            }
+        }
+
+        /* a missing variable store is copied by the checks below */
+        if let Some(reset) = vmrun.varfile().and_then(|varfile| varfile.reset_recovery()) {
+            if backend.is_simulated() {
+                eprintln!("simulate: {}", reset.describe());
+            } else if !args.dry_run && !args.debug {
+                reset.apply(host).map_err(VmRunError::RecoveryFailure)?;
+            }
+        }
 
         // Check if every requirements are archieved before handing to bhyve
         if !args.no_requirement_check {
             // if the user put "fix": true, we apply the known fix to the device
-            for emulation in vmrun.emulations.iter() {
-                let cond = emulation.preconditions();
-                if let Err(assertion) = cond.check() {
-                    if assertion.is_recoverable()
-                        && (emulation.want_fix
-                            || args.force
-                            || ask_yesno(assertion.recovery_prompt()))
-                    {
-                        assertion.recover();
-                    }
-                }
+            /* a reboot reuses the VM instance of the previous run */
+            let first_launch = reboot_count == 0;
+            for (want_fix, cond) in vmrun.recoverable_conditions(first_launch) {
+                recover_condition(args, host, want_fix, cond.as_ref(), events, undo)?;
             }
 
-            let condition = vmrun.preconditions();
-            match condition.check() {
+            let condition = vmrun.launch_conditions(first_launch);
+            let result = strictly(args.strict, condition.check(host));
+            events.emit(Event::Preconditions {
+                scope: &condition.name(),
+                passed: result.is_ok(),
+                assertion: result.as_ref().err(),
+            });
+            match result {
                 Ok(()) => (),
                 Err(assertion) => {
                     println!("{}", assertion.print("vm".to_string()));
@@ -231,14 +927,63 @@ fn vm_main(args: &Arguments, vm: &spec::VmSpec) -> Result<i32, VmRunError> {
             }
         }
 
-        let run_result = vm_run_session(args, vm, &vmrun);
+        if reboot_count == 0 {
+            control = if args.dry_run || args.debug {
+                None
+            } else if backend.is_simulated() {
+                eprintln!("simulate: control socket {}", control_path.display());
+                None
+            } else {
+                match control::ControlServer::start(&control_path, consoles.clone()) {
+                    Ok(control) => Some(control),
+                    Err(error) => {
+                        eprintln!(
+                            "warn: cannot listen on {}, `vmrun console` is unavailable: {error}",
+                            control_path.display()
+                        );
+                        None
+                    }
+                }
+            };
+        }
+
+        for (n, backend) in vmrun.com_ports() {
+            let peer = backend.peer();
+            if consoles_reported.contains(&(n, peer.clone())) {
+                continue;
+            }
+            match &peer {
+                Some(peer) if matches!(backend, ComBackend::Tcp(_)) => {
+                    eprintln!("info: com{n} listens on {peer}")
+                }
+                Some(_) if control.is_some() => eprintln!(
+                    "info: com{n} is attached with `vmrun console {} --com {n}`",
+                    vm.name
+                ),
+                Some(peer) => eprintln!("info: com{n} is attached with `cu -l {peer}`"),
+                None => (),
+            }
+            events.emit(Event::ConsoleAttached {
+                com: n,
+                backend: &backend.as_bhyve_arg(),
+                peer: peer.as_deref(),
+            });
+            consoles_reported.push((n, peer));
+        }
+
+        let run_result = vm_run_session(args, host, backend, vm, &vmrun, events, &consoles);
 
         if args.debug || args.dry_run {
             return Ok(0);
         }
 
         for object in vmrun.ephemeral_objects() {
-            match object.release() {
+            let result = backend.release(&object);
+            events.emit(Event::Cleanup {
+                resource: object.to_string(),
+                error: result.as_ref().err().map(|error| error.to_string()),
+            });
+            match result {
                 Err(error) => {
                     if args.panic_on_failed_cleanup {
                         panic!("Error occured when cleaning up: {}", error);
@@ -250,18 +995,45 @@ fn vm_main(args: &Arguments, vm: &spec::VmSpec) -> Result<i32, VmRunError> {
             }
         }
 
-        exit_code = if let Ok(ec) = run_result { ec } else { 4 };
+        exit_code = if let Ok((ec, _)) = run_result { ec } else { 4 };
+        let stopped_by = run_result
+            .as_ref()
+            .ok()
+            .and_then(|(_, stopped_by)| stopped_by.as_ref());
+
+        if let Some((TriggerAction::SwitchTarget { target }, _)) = stopped_by {
+            next_target = Some(target.to_string());
+        }
+        /* a reset by a console trigger or boot script reboots whatever the exit code */
+        let rebootable = match stopped_by.map(|(action, _)| action) {
+            Some(TriggerAction::Stop { .. }) => false,
+            Some(_) => true,
+            None => args.reboot_on.contains(&exit_code),
+        };
 
         /* if exit code is 0, it means the guest wanna reboot */
-        if reboot_count < args.reboot_count.unwrap_or(usize::MAX)
-            && args.reboot_on.contains(&exit_code)
+        let reboot = reboot_count < args.reboot_count.unwrap_or(usize::MAX)
+            && rebootable
             && run_result.is_ok()
             && !args.dry_run
-            && !args.no_reboot
-        {
+            && !args.no_reboot;
+
+        events.emit(Event::RebootDecision {
+            exit_code,
+            reboot,
+            reboot_count,
+            next_target: next_target.as_deref(),
+        });
+
+        if reboot {
             reboot_count += 1;
             continue;
         } else {
+            if let Some((TriggerAction::Stop { failed: true }, reason)) = stopped_by {
+                return Err(VmRunError::GuestFailed {
+                    reason: reason.clone(),
+                });
+            }
             _ = run_result?;
             break;
         }
@@ -270,6 +1042,373 @@ fn vm_main(args: &Arguments, vm: &spec::VmSpec) -> Result<i32, VmRunError> {
     Ok(exit_code)
 }
 
+/// Resolve the configuration to boot with `target`, the root configuration
+/// itself is the default target unless a target named "default" is defined
+fn resolve_target(vm: &spec::VmSpec, target: &Option<String>) -> Result<spec::VmSpec, VmRunError> {
+    let mut spec = vm.clone();
+    if let Some(target) = target {
+        if target != "default" || spec.has_target(target) {
+            spec.consume_target(target).map_err(VmRunError::SpecErr)?;
+        }
+    }
+    Ok(spec)
+}
+
+#[derive(serde::Serialize)]
+struct CheckReport<'a> {
+    name: &'a str,
+    target: Option<&'a str>,
+    /// true if no fatal assertion found, i.e. the VM can launch, possibly
+    /// after applying recoveries
+    launchable: bool,
+    /// `None` if bhyve on the host cannot be probed
+    bhyve: Option<util::os::BhyveCapabilities>,
+    devices: Vec<DeviceReport>,
+    vm: Option<Assertion>,
+}
+
+#[derive(serde::Serialize)]
+struct DeviceReport {
+    scope: String,
+    want_fix: bool,
+    assertion: Option<Assertion>,
+}
+
+/// Check every device and the VM as a whole against the host
+fn check_report<'a>(
+    args: &'a VmArgs,
+    host: &dyn Host,
+    vmrun: &'a vm::VmRun,
+    strict: bool,
+) -> CheckReport<'a> {
+    let devices: Vec<DeviceReport> = vmrun
+        .emulations
+        .iter()
+        .map(|emulation| {
+            let cond = emulation.preconditions();
+            DeviceReport {
+                scope: cond.name(),
+                want_fix: emulation.want_fix,
+                assertion: strictly(strict, cond.check(host)).err(),
+            }
+        })
+        .collect();
+
+    let assertion = strictly(strict, vmrun.preconditions().check(host)).err();
+    CheckReport {
+        name: &vmrun.name,
+        target: args.target.as_deref(),
+        launchable: assertion.as_ref().is_none_or(|a| a.is_recoverable()),
+        bhyve: host.bhyve_capabilities().ok(),
+        devices,
+        vm: assertion,
+    }
+}
+
+fn check_main(
+    args: &VmArgs,
+    host: &dyn Host,
+    vm: &spec::VmSpec,
+    strict: bool,
+    format: OutputFormat,
+) -> Result<i32, VmRunError> {
+    let spec = resolve_target(vm, &args.target)?;
+    let vmrun = spec
+        .build(host, &args.extra_bhyve_args)
+        .map_err(VmRunError::SpecErr)?;
+    let report = check_report(args, host, &vmrun, strict);
+
+    match format {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        }
+        OutputFormat::Text => match &report.vm {
+            None => println!("vm: all preconditions satisfied"),
+            Some(assertion) => println!("{}", assertion.print("vm".to_string())),
+        },
+    }
+
+    Ok(if report.launchable { 0 } else { 1 })
+}
+
+/// Parse the bhyve arguments back and compare them against the VM, the
+/// problems found are printed to stderr
+fn verify_bootargs(vmrun: &vm::VmRun, bootargs: &[String]) -> bool {
+    let issues = match BhyveCommand::parse(bootargs) {
+        Ok(command) => {
+            let mut issues = command.validate();
+            issues.extend(vmrun.verify_args(&command));
+            issues
+        }
+        Err(error) => {
+            eprintln!("error: {error}");
+            return false;
+        }
+    };
+
+    for issue in issues.iter() {
+        eprintln!("error: {issue}");
+    }
+
+    issues.is_empty()
+}
+
+fn print_explained(explained: &[(Vec<String>, spec::Provenance)], format: ArgsFormat) {
+    match format {
+        ArgsFormat::Json => {
+            let explained: Vec<_> = explained
+                .iter()
+                .map(|(args, provenance)| {
+                    serde_json::json!({
+                        "args": args,
+                        "provenance": provenance,
+                        "explain": provenance.to_string(),
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&explained).unwrap());
+        }
+        ArgsFormat::Text => {
+            let lines: Vec<(String, String)> = explained
+                .iter()
+                .map(|(args, provenance)| (args.join(" "), provenance.to_string()))
+                .collect();
+            let width = lines.iter().map(|(args, _)| args.len()).max().unwrap_or(0);
+            for (args, provenance) in lines {
+                println!("{args:<width$}  # {provenance}");
+            }
+        }
+        /* the shell script is printed after the explanation as comments */
+        ArgsFormat::Shell => {
+            println!("#!/bin/sh");
+            for (args, provenance) in explained {
+                println!("# {}: {provenance}", args.join(" "));
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn args_main(
+    args: &VmArgs,
+    host: &dyn Host,
+    vm: &spec::VmSpec,
+    config: &serde_json::Value,
+    verify: bool,
+    format: ArgsFormat,
+    explain: bool,
+) -> Result<i32, VmRunError> {
+    let spec = resolve_target(vm, &args.target)?;
+    let vmrun = spec
+        .build(host, &args.extra_bhyve_args)
+        .map_err(VmRunError::SpecErr)?;
+    let bootargs = vmrun.bhyve_args().map_err(VmRunError::VmErr)?;
+
+    let mut argv = vec![ToolPaths::current().bhyve.clone()];
+    argv.extend(bootargs.iter().cloned());
+
+    if explain {
+        let explainer = spec::Explainer::new(&args.config, config, &spec, &args.set);
+        print_explained(&explainer.explain(&vmrun), format);
+    }
+
+    match format {
+        ArgsFormat::Text if !explain => println!("{}", argv.join(" ")),
+        ArgsFormat::Shell => {
+            let quoted: Vec<String> = argv.iter().map(|arg| util::shell_quote(arg)).collect();
+            if !explain {
+                println!("#!/bin/sh");
+            }
+            if let Some(loader) = &vmrun.loader {
+                let files = loader.generated_files(&vmrun);
+                let mut dirs: Vec<_> = files.iter().filter_map(|(path, _)| path.parent()).collect();
+                dirs.dedup();
+                for dir in dirs {
+                    println!("mkdir -p {}", util::shell_quote(&dir.to_string_lossy()));
+                }
+                for (path, content) in files.iter() {
+                    let path = util::shell_quote(&path.to_string_lossy());
+                    print!("cat > {path} <<'EOF'\n{content}EOF\n");
+                }
+                let mut loader_argv = vec![loader.program().to_string()];
+                loader_argv.extend(loader.args(&vmrun));
+                let quoted: Vec<String> = loader_argv
+                    .iter()
+                    .map(|arg| util::shell_quote(arg))
+                    .collect();
+                println!("{} || exit $?", quoted.join(" "));
+            }
+            println!("exec {}", quoted.join(" "));
+        }
+        ArgsFormat::Json if !explain => {
+            println!("{}", serde_json::to_string_pretty(&argv).unwrap())
+        }
+        _ => (),
+    }
+
+    if verify && !verify_bootargs(&vmrun, &bootargs) {
+        return Ok(1);
+    }
+
+    Ok(0)
+}
+
+fn show_main(
+    args: &VmArgs,
+    vm: &spec::VmSpec,
+    config: &serde_json::Value,
+) -> Result<i32, VmRunError> {
+    let spec = resolve_target(vm, &args.target)?;
+    let resolved = spec::resolve_json(config, &spec);
+    println!("{}", serde_json::to_string_pretty(&resolved).unwrap());
+    Ok(0)
+}
+
+/// Build the VM the same way `run` does, but against a host that has nothing,
+/// such that only the configuration itself is checked
+fn validate_main(args: &VmArgs, vm: &spec::VmSpec) -> Result<i32, VmRunError> {
+    let spec = resolve_target(vm, &args.target)?;
+    let vmrun = spec
+        .build(&PlanHost::default(), &args.extra_bhyve_args)
+        .map_err(VmRunError::SpecErr)?;
+    let bootargs = vmrun.bhyve_args().map_err(VmRunError::VmErr)?;
+
+    if !verify_bootargs(&vmrun, &bootargs) {
+        return Ok(1);
+    }
+
+    println!("{}: configuration is valid", vmrun.name);
+    Ok(0)
+}
+
+fn slots_main(
+    args: &VmArgs,
+    host: &dyn Host,
+    vm: &spec::VmSpec,
+    all: bool,
+) -> Result<i32, VmRunError> {
+    let targets = if all {
+        let mut targets: Vec<Option<String>> = vm.targets.keys().cloned().map(Some).collect();
+        targets.sort();
+        targets.insert(0, None);
+        targets
+    } else {
+        vec![args.target.clone()]
+    };
+
+    for target in targets {
+        let vmrun = resolve_target(vm, &target)?
+            .build(host, &args.extra_bhyve_args)
+            .map_err(VmRunError::SpecErr)?;
+        println!("{}:", target.as_deref().unwrap_or("default"));
+        for (slot, device) in vmrun.slot_map() {
+            println!("  {:<9} {device}", slot.as_bhyve_arg());
+        }
+    }
+
+    Ok(0)
+}
+
+fn diff_main(
+    args: &VmArgs,
+    host: &dyn Host,
+    vm: &spec::VmSpec,
+    from: &Option<String>,
+    to_target: &str,
+) -> Result<i32, VmRunError> {
+    let build = |target: &Option<String>| {
+        resolve_target(vm, target)?
+            .build(host, &args.extra_bhyve_args)
+            .map_err(VmRunError::SpecErr)
+    };
+    let from_target = from.as_ref().or(args.target.as_ref()).cloned();
+    let from = build(&from_target)?;
+    let to = build(&Some(to_target.to_string()))?;
+
+    let differences = from.diff(&to);
+    for difference in differences.iter() {
+        println!("{difference}");
+    }
+
+    let from_args = bhyve::group_args(&from.bhyve_args().map_err(VmRunError::VmErr)?);
+    let to_args = bhyve::group_args(&to.bhyve_args().map_err(VmRunError::VmErr)?);
+    let lines = vm::diff::diff_args(&from_args, &to_args);
+
+    if !differences.is_empty() {
+        println!();
+    }
+    println!("--- {}", from_target.as_deref().unwrap_or("default"));
+    println!("+++ {to_target}");
+    for line in lines.iter() {
+        println!("{line}");
+    }
+
+    /* the same as diff(1), 1 if there are differences */
+    Ok(if from_args == to_args { 0 } else { 1 })
+}
+
+fn print_fixes_main(args: &VmArgs, host: &dyn Host, vm: &spec::VmSpec) -> Result<i32, VmRunError> {
+    let spec = resolve_target(vm, &args.target)?;
+    let vmrun = spec
+        .build(host, &args.extra_bhyve_args)
+        .map_err(VmRunError::SpecErr)?;
+
+    println!("#!/bin/sh");
+    println!("# fixes for vm {}", vmrun.name);
+    println!("set -e");
+
+    for (_, cond) in vmrun.recoverable_conditions(true) {
+        if let Err(assertion) = cond.check(host) {
+            for (scope, recovery) in assertion.recoveries(cond.name()) {
+                println!("\n# {scope}: {}", recovery.describe());
+                for line in recovery.script() {
+                    println!("{line}");
+                }
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+fn run_main(args: &RunArgs, host: &dyn Host, vm: &spec::VmSpec) -> Result<i32, VmRunError> {
+    let mut events = match &args.event_log {
+        Some(dest) => EventLog::open(dest).unwrap_or_else(|err| {
+            eprintln!("cannot open event log: {}", err);
+            process::exit(4);
+        }),
+        None => EventLog::discard(),
+    };
+
+    if let Some(file) = &args.supervisor_pid_file {
+        if let Err(err) = write_pid_file(file, process::id()) {
+            eprintln!("cannot write supervisor pid file: {}", err);
+            process::exit(4);
+        }
+    }
+
+    events.set_vm_name(&vm.name);
+    events.emit(Event::ConfigLoaded {
+        source: &args.vm.config,
+    });
+
+    if args.print_fixes {
+        return print_fixes_main(&args.vm, host, vm);
+    }
+
+    match &args.simulate {
+        /* a simulated VM never touches the host */
+        Some(script) => vm_main(
+            args,
+            &PlanHost::default(),
+            &mut Simulator::new(script.clone()),
+            vm,
+            &mut events,
+        ),
+        None => vm_main(args, host, &mut BhyveProcess::default(), vm, &mut events),
+    }
+}
+
 fn open_pid_file<P: AsRef<std::path::Path>>(path: P) -> Result<std::fs::File, VmRunError> {
     if let Ok(metadata) = std::fs::metadata(path.as_ref()) {
         if !metadata.is_file() {
@@ -306,7 +1445,20 @@ fn write_pid_file<P: AsRef<std::path::Path>>(path: P, pid: u32) -> Result<(), Vm
 }
 
 fn main() {
-    let args = Arguments::parse();
+    let cli = Cli::parse_from(with_default_command(std::env::args_os()));
+    if let Command::Console {
+        name,
+        com,
+        read_only,
+    } = &cli.command
+    {
+        if let Err(error) = control::attach(name, *com, *read_only) {
+            eprintln!("vmrun console: {error}");
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+    let args = cli.command.vm_args().unwrap();
     let mut content: String = String::new();
 
     content = if args.config.as_str() == "-" {
@@ -319,25 +1471,276 @@ fn main() {
         std::fs::read_to_string(&args.config).expect("fail to read configuration file")
     };
 
-    if let Some(file) = &args.supervisor_pid_file {
-        if let Err(err) = write_pid_file(file, process::id()) {
-            eprintln!("cannot write supervisor pid file: {}", err);
-            process::exit(4);
+    let mut config: serde_json::Value = serde_json::from_str(&content).unwrap_or_else(|err| {
+        eprintln!(
+            "{}",
+            format_serde_error::SerdeError::new(content.to_string(), err)
+        );
+        process::exit(4);
+    });
+
+    let original = config.clone();
+    if !args.set.is_empty() {
+        for assignment in args.set.iter() {
+            if let Err(err) = spec::apply_override(&mut config, assignment) {
+                eprintln!("{err}");
+                process::exit(4);
+            }
         }
+        /* such that the errors below point into the overridden configuration */
+        content = serde_json::to_string_pretty(&config).unwrap();
     }
 
     let vm_err: Result<spec::VmSpec, _> = serde_json::from_str(&content)
         .map_err(|err| format_serde_error::SerdeError::new(content.to_string(), err));
 
     if let Err(e) = vm_err {
+        match spec::failing_override(&original, &args.set) {
+            Some(assignment) => eprintln!("error: the override `{assignment}` is invalid"),
+            None if !args.set.is_empty() => eprintln!(
+                "note: the configuration has the overrides applied: {}",
+                args.set.join(" ")
+            ),
+            None => (),
+        }
         eprintln!("{e}");
         process::exit(4);
     }
 
     let vm = vm_err.unwrap();
 
-    match vm_main(&args, &vm) {
-        Err(error) => println!("vmrun exited with error: {}", error),
+    match ToolPaths::resolve(args.host_profile.as_deref().map(Path::new), &args.tool) {
+        Ok(tools) => tools.install(),
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(4);
+        }
+    }
+
+    let host = NativeHost::default();
+
+    let result = match &cli.command {
+        Command::Run(args) => run_main(args, &host, &vm),
+        Command::Check {
+            vm: args,
+            strict,
+            format,
+        } => check_main(args, &host, &vm, *strict, *format),
+        Command::Args {
+            vm: args,
+            verify,
+            format,
+            explain,
+        } => args_main(args, &host, &vm, &config, *verify, *format, *explain),
+        Command::Show { vm: args } => show_main(args, &vm, &config),
+        Command::Validate { vm: args } => validate_main(args, &vm),
+        Command::Slots { vm: args, all } => slots_main(args, &host, &vm, *all),
+        Command::Diff { vm: args, from, to } => diff_main(args, &host, &vm, from, to),
+        Command::Console { .. } => unreachable!(),
+    };
+
+    match result {
+        Err(error) => {
+            println!("vmrun exited with error: {}", error);
+            process::exit(4);
+        }
         Ok(exit_code) => std::process::exit(exit_code),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::os::fake::FakeHost;
+    use crate::util::os::FileKind;
+    use crate::vm::conditions::Condition;
+
+    fn run_args(extra: &[&str]) -> RunArgs {
+        let argv = ["vmrun", "run", "-c", "vm.json"].iter().chain(extra);
+        match Cli::parse_from(argv).command {
+            Command::Run(args) => args,
+            _ => unreachable!(),
+        }
+    }
+
+    /// A tap interface the recovery creates, which the condition never sees
+    #[derive(Debug)]
+    struct Stubborn;
+
+    impl Condition for Stubborn {
+        fn check(&self, _host: &dyn Host) -> Result<(), Assertion> {
+            self.recoverable(
+                "tap0 does not exist".to_string(),
+                Recovery::CreateTap("tap0".to_string()),
+            )
+        }
+
+        fn name(&self) -> String {
+            "stubborn".to_string()
+        }
+    }
+
+    /// A tap interface that is only a warning once it exists
+    #[derive(Debug)]
+    struct Unopened;
+
+    impl Condition for Unopened {
+        fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+            match host.tap_ifaces().unwrap().iter().any(|tap| tap == "tap0") {
+                true => self.warning("tap0 is not opened".to_string()),
+                false => self.recoverable(
+                    "tap0 does not exist".to_string(),
+                    Recovery::CreateTap("tap0".to_string()),
+                ),
+            }
+        }
+
+        fn name(&self) -> String {
+            "unopened".to_string()
+        }
+    }
+
+    #[test]
+    fn ineffective_recovery() {
+        let args = run_args(&["--force", "--recovery-attempts", "2"]);
+        let host = FakeHost::default();
+        let mut undo = vec![];
+        let result = recover_condition(
+            &args,
+            &host,
+            false,
+            &Stubborn,
+            &mut EventLog::discard(),
+            &mut undo,
+        );
+        match result {
+            Err(VmRunError::RecoveryIneffective { recoveries, .. }) => {
+                assert_eq!(recoveries, "create-tap")
+            }
+            other => panic!("expected the recovery to be ineffective, got {other:?}"),
+        }
+        /* applied once per attempt, reverted once */
+        assert_eq!(host.taps.borrow().len(), 2);
+        assert_eq!(undo, vec![Recovery::DestroyTap("tap0".to_string())]);
+    }
+
+    #[test]
+    fn recovery_leaving_warnings() {
+        let args = run_args(&["--force"]);
+        let host = FakeHost::default();
+        let mut undo = vec![];
+        let result = recover_condition(
+            &args,
+            &host,
+            false,
+            &Unopened,
+            &mut EventLog::discard(),
+            &mut undo,
+        );
+        assert!(result.is_ok());
+        assert_eq!(host.taps.borrow().len(), 1);
+        assert_eq!(undo, vec![Recovery::DestroyTap("tap0".to_string())]);
+
+        /* the warnings are still fatal with --strict */
+        let args = run_args(&["--force", "--strict"]);
+        let result = recover_condition(
+            &args,
+            &FakeHost::default(),
+            false,
+            &Unopened,
+            &mut EventLog::discard(),
+            &mut vec![],
+        );
+        assert!(matches!(
+            result,
+            Err(VmRunError::RecoveryIneffective { .. })
+        ));
+    }
+
+    #[test]
+    fn check_launchable() {
+        let args = run_args(&[]);
+        let vm: spec::VmSpec = serde_json::from_str(
+            r#"{"name": "test", "cpu": 1, "mem": "513M", "emulations": [
+                {"device": "virtio-net", "name": "tap0"},
+                {"device": "virtio-blk", "path": "/vm/disk.img"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let bootrom = "/usr/local/share/uefi-firmware/BHYVE_UEFI.fd";
+
+        /* the disk is missing */
+        let host = FakeHost::default().with_file(bootrom, FileKind::File, vec![]);
+        let vmrun = vm.build(&host, &[]).unwrap();
+        let report = check_report(&args.vm, &host, &vmrun, false);
+        assert!(!report.launchable);
+        assert!(report.devices[1]
+            .assertion
+            .as_ref()
+            .is_some_and(|a| !a.is_recoverable()));
+
+        /* the missing tap is recoverable, the odd memory size a warning */
+        let host = FakeHost::default()
+            .with_file(bootrom, FileKind::File, vec![])
+            .with_file("/vm/disk.img", FileKind::File, vec![]);
+        let report = check_report(&args.vm, &host, &vmrun, false);
+        assert!(report.launchable);
+        assert!(report.devices[0]
+            .assertion
+            .as_ref()
+            .is_some_and(|a| a.has_recovery()));
+        assert!(report.devices[1].assertion.is_none());
+        assert!(report
+            .vm
+            .as_ref()
+            .is_some_and(|a| a.print("vm".to_string()).contains("not a multiple of 2M")));
+    }
+
+    #[test]
+    fn strict_warnings() {
+        let warning = || {
+            Err(Assertion::Warning(
+                "mem".to_string(),
+                "odd size".to_string(),
+            ))
+        };
+
+        /* a warning never blocks the launch */
+        let result = strictly(false, warning());
+        assert!(result
+            .as_ref()
+            .is_err_and(|a| a.is_recoverable() && !a.has_recovery()));
+
+        /* unless it is strict */
+        let result = strictly(true, warning());
+        assert!(result.is_err_and(|a| !a.is_recoverable()));
+        assert!(strictly(true, Ok(())).is_ok());
+    }
+
+    #[test]
+    #[cfg(not(all(feature = "freebsd-host", target_os = "freebsd")))]
+    fn plan_mode() {
+        let vm: spec::VmSpec =
+            serde_json::from_str(r#"{"name": "test", "cpu": 1, "mem": "512M", "emulations": []}"#)
+                .unwrap();
+        let result = vm_main(
+            &run_args(&[]),
+            &PlanHost::default(),
+            &mut BhyveProcess::default(),
+            &vm,
+            &mut EventLog::discard(),
+        );
+        assert!(matches!(result, Err(VmRunError::PlanMode)));
+
+        /* the host probes are skipped on a dry run */
+        let result = vm_main(
+            &run_args(&["--dry-run"]),
+            &PlanHost::default(),
+            &mut BhyveProcess::default(),
+            &vm,
+            &mut EventLog::discard(),
+        );
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::console::{ConsoleEvent, ConsoleHub, PatternMatcher};
+use crate::vm::script::{BootScript, Keys, ScriptStep};
+use crate::vm::TriggerAction;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// A failed step of a boot script, and what the supervisor does about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptFailure {
+    /// The number of the step, from 1
+    pub step: usize,
+    pub reason: String,
+    pub action: TriggerAction,
+}
+
+/// A sink passing the console output to a boot script
+pub struct ChannelSink(pub Sender<Vec<u8>>);
+
+impl Write for ChannelSink {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .send(bytes.to_vec())
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A boot script running next to the VM. Its end is reported to the
+/// supervisor as [`ConsoleEvent::ScriptFinished`], unless it is cancelled
+pub struct ScriptRun {
+    cancelled: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl ScriptRun {
+    pub fn start(
+        script: BootScript,
+        hub: Arc<ConsoleHub>,
+        output: Receiver<Vec<u8>>,
+        events: Sender<ConsoleEvent>,
+    ) -> ScriptRun {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let runner = ScriptRunner {
+            script,
+            hub,
+            output,
+            cancelled: cancelled.clone(),
+            matcher: PatternMatcher::default(),
+        };
+        let thread = std::thread::spawn(move || {
+            if let Some(result) = runner.run() {
+                let _ = events.send(ConsoleEvent::ScriptFinished(result));
+            }
+        });
+        ScriptRun { cancelled, thread }
+    }
+
+    /// Stop the script where it is, once the VM exited
+    pub fn cancel(self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
+
+struct ScriptRunner {
+    script: BootScript,
+    hub: Arc<ConsoleHub>,
+    output: Receiver<Vec<u8>>,
+    cancelled: Arc<AtomicBool>,
+    matcher: PatternMatcher,
+}
+
+/// How often a waiting script checks if it is cancelled
+const TICK: Duration = Duration::from_millis(100);
+
+impl ScriptRunner {
+    /// Run the steps in order, `None` if cancelled
+    fn run(mut self) -> Option<Result<(), ScriptFailure>> {
+        let default_action = self.script.on_failure.clone();
+        for (index, step) in self.script.steps.clone().into_iter().enumerate() {
+            let failure = |reason: String, action: Option<TriggerAction>| ScriptFailure {
+                step: index + 1,
+                reason,
+                action: action.unwrap_or_else(|| default_action.clone()),
+            };
+            let result = match step {
+                ScriptStep::WaitFor {
+                    pattern,
+                    timeout,
+                    on_failure,
+                } => match self.wait_for(&pattern, timeout) {
+                    Some(true) => Ok(()),
+                    Some(false) => Err(failure(
+                        format!("`{pattern}` not printed within {timeout:?}"),
+                        on_failure,
+                    )),
+                    None => return None,
+                },
+                ScriptStep::Send(keys) => match self.send(&keys) {
+                    Some(Ok(())) => Ok(()),
+                    Some(Err(error)) => Err(failure(format!("cannot send keys: {error}"), None)),
+                    None => return None,
+                },
+                ScriptStep::Sleep(duration) => match self.sleep(duration) {
+                    true => Ok(()),
+                    false => return None,
+                },
+            };
+            if let Err(failure) = result {
+                return Some(Err(failure));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// If the pattern is printed before the timeout, `None` if cancelled
+    fn wait_for(&mut self, pattern: &regex::Regex, timeout: Duration) -> Option<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.matcher.find([pattern]).is_some() {
+                return Some(true);
+            }
+            if self.is_cancelled() {
+                return None;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Some(false);
+            }
+            match self.output.recv_timeout(left.min(TICK)) {
+                Ok(bytes) => self.matcher.feed(&bytes),
+                Err(RecvTimeoutError::Timeout) => (),
+                /* the console is gone with the VM */
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    /// Sleep unless cancelled, false if cancelled
+    fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        while !self.is_cancelled() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
+            }
+            std::thread::sleep(left.min(TICK));
+        }
+        false
+    }
+
+    /// Type the keys. The output printed before is not looked at by the next
+    /// `wait_for`, such that it waits for the answer to the keys
+    fn send(&mut self, keys: &[Keys]) -> Option<std::io::Result<()>> {
+        while self.output.try_recv().is_ok() {}
+        self.matcher = PatternMatcher::default();
+
+        for part in keys {
+            match part {
+                Keys::Wait(duration) => {
+                    if !self.sleep(*duration) {
+                        return None;
+                    }
+                }
+                Keys::Bytes(bytes) if self.script.key_delay.is_zero() => {
+                    if let Err(error) = self.hub.send(bytes) {
+                        return Some(Err(error));
+                    }
+                }
+                Keys::Bytes(bytes) => {
+                    for byte in bytes {
+                        if let Err(error) = self.hub.send(&[*byte]) {
+                            return Some(Err(error));
+                        }
+                        if !self.sleep(self.script.key_delay) {
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+        Some(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::script::parse_keys;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+
+    fn script(steps: Vec<ScriptStep>) -> BootScript {
+        BootScript {
+            com: 1,
+            steps,
+            key_delay: Duration::ZERO,
+            on_failure: TriggerAction::Stop { failed: true },
+        }
+    }
+
+    fn wait_for(pattern: &str, millis: u64) -> ScriptStep {
+        ScriptStep::WaitFor {
+            pattern: regex::Regex::new(pattern).unwrap(),
+            timeout: Duration::from_millis(millis),
+            on_failure: None,
+        }
+    }
+
+    #[test]
+    fn run_boot_script() {
+        let hub = Arc::new(ConsoleHub::default());
+        let (guest, mut typed) = UnixStream::pair().unwrap();
+        hub.set_input(Some(Box::new(guest)));
+        let (output, received) = mpsc::channel();
+        hub.subscribe(Box::new(ChannelSink(output)));
+        let (events, finished) = mpsc::channel();
+
+        let run = ScriptRun::start(
+            script(vec![
+                wait_for("Autoboot in", 5000),
+                ScriptStep::Send(parse_keys("<esc>boot -s<enter>")),
+                wait_for("Enter full pathname of shell", 5000),
+                ScriptStep::Sleep(Duration::from_millis(10)),
+                ScriptStep::Send(parse_keys("<enter>")),
+            ]),
+            hub.clone(),
+            received,
+            events,
+        );
+        hub.broadcast(b"Autoboot in 9 seconds");
+        let mut keys = [0u8; 9];
+        typed.read_exact(&mut keys).unwrap();
+        assert_eq!(&keys, b"\x1bboot -s\r");
+        hub.broadcast(b"\r\nEnter full pathname of shell or RETURN for /bin/sh: ");
+        let mut keys = [0u8; 1];
+        typed.read_exact(&mut keys).unwrap();
+        assert_eq!(&keys, b"\r");
+
+        match finished.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ConsoleEvent::ScriptFinished(result) => assert_eq!(result, Ok(())),
+            _ => panic!("expected the end of the script"),
+        }
+        run.cancel();
+    }
+
+    #[test]
+    fn boot_script_timeout() {
+        let hub = Arc::new(ConsoleHub::default());
+        let (output, received) = mpsc::channel();
+        hub.subscribe(Box::new(ChannelSink(output)));
+        let (events, finished) = mpsc::channel();
+
+        let mut steps = vec![wait_for("login:", 5000), wait_for("never", 50)];
+        if let ScriptStep::WaitFor { on_failure, .. } = &mut steps[1] {
+            *on_failure = Some(TriggerAction::Reset);
+        }
+        let run = ScriptRun::start(script(steps), hub.clone(), received, events);
+        hub.broadcast(b"FreeBSD/amd64 (vm) (ttyu0)\n\nlogin: ");
+        match finished.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ConsoleEvent::ScriptFinished(Err(failure)) => {
+                assert_eq!(failure.step, 2);
+                assert_eq!(failure.action, TriggerAction::Reset);
+            }
+            _ => panic!("expected the step to time out"),
+        }
+        run.cancel();
+
+        /* a cancelled script reports nothing */
+        let (_output, received) = mpsc::channel();
+        let (events, finished) = mpsc::channel();
+        let run = ScriptRun::start(script(vec![wait_for("x", 60_000)]), hub, received, events);
+        run.cancel();
+        assert!(finished.recv().is_err());
+    }
+}
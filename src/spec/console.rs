@@ -0,0 +1,573 @@
+use crate::spec::defaults::{default_console_com, default_script_timeout};
+use crate::spec::{FormatError, VmSpec};
+use crate::util::os::{Host, NmdmClaim};
+use crate::vm::script::{parse_keys, BootScript, ScriptStep};
+use crate::vm::{nmdm_number, ComBackend, ConsoleTrigger, TriggerAction};
+use serde::{de, Deserialize, Deserializer};
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The nmdm pairs vmrun allocates from, `/dev/nmdm0A` to `/dev/nmdm255A`
+const NMDM_PAIRS: u32 = 256;
+
+/// The backend of a com port as configured, `nmdm` without a number is
+/// allocated a free nmdm pair when the VM is built
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Console {
+    Stdio,
+    Nmdm(Option<u32>),
+    Device(String),
+    Tcp(String),
+}
+
+impl FromStr for Console {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Console, FormatError> {
+        let invalid = |reason: &str| FormatError::InvalidConsole {
+            value: s.to_string(),
+            reason: reason.to_string(),
+        };
+
+        if s == "stdio" {
+            Ok(Console::Stdio)
+        } else if s == "nmdm" {
+            Ok(Console::Nmdm(None))
+        } else if let Some(number) = s.strip_prefix("nmdm") {
+            number
+                .parse()
+                .map(|number| Console::Nmdm(Some(number)))
+                .map_err(|_| invalid("expected nmdm followed by the number of the pair"))
+        } else if let Some(address) = s.strip_prefix("tcp=") {
+            match address.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok(Console::Tcp(address.to_string()))
+                }
+                _ => Err(invalid("expected tcp=ADDRESS:PORT")),
+            }
+        } else if s.starts_with('/') {
+            Ok(Console::Device(s.to_string()))
+        } else {
+            Err(invalid(
+                "expected stdio, nmdm, nmdmN, tcp=ADDRESS:PORT or the path to a device",
+            ))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Console {
+    fn deserialize<D>(deserializer: D) -> Result<Console, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer).and_then(|value| value.parse().map_err(de::Error::custom))
+    }
+}
+
+/// A console trigger as configured, the action is tagged by `action`
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConsoleTriggerSpec {
+    #[serde(rename = "match")]
+    pattern: String,
+    #[serde(default = "default_console_com")]
+    com: u8,
+    #[serde(flatten, with = "TriggerActionDef")]
+    action: TriggerAction,
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "TriggerAction", tag = "action", rename_all = "snake_case")]
+enum TriggerActionDef {
+    Stop {
+        #[serde(default)]
+        failed: bool,
+    },
+    Reset,
+    SwitchTarget {
+        target: String,
+    },
+    Hook {
+        command: String,
+    },
+    Send {
+        keys: String,
+    },
+}
+
+/// A failure action, as the action of a console trigger
+#[derive(Deserialize, Debug, Clone)]
+struct ActionSpec(#[serde(with = "TriggerActionDef")] TriggerAction);
+
+/// A boot script as configured
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BootScriptSpec {
+    #[serde(default = "default_console_com")]
+    com: u8,
+    /// Seconds a `wait_for` step waits unless the step says
+    #[serde(default = "default_script_timeout")]
+    timeout: f64,
+    /// Seconds between two keys
+    #[serde(default)]
+    key_delay: f64,
+    on_failure: Option<ActionSpec>,
+    steps: Vec<ScriptStepSpec>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged, deny_unknown_fields)]
+enum ScriptStepSpec {
+    WaitFor {
+        wait_for: String,
+        timeout: Option<f64>,
+        on_failure: Option<ActionSpec>,
+    },
+    Send {
+        send: String,
+    },
+    Sleep {
+        sleep: f64,
+    },
+}
+
+fn seconds(value: f64, what: &str) -> Result<Duration, FormatError> {
+    Duration::try_from_secs_f64(value)
+        .map_err(|_| FormatError::InvalidBootScript(format!("invalid {what} `{value}`")))
+}
+
+/// Check that vmrun can type into com port `n` for `field`, through the
+/// other side of a nmdm pair. The input of a stdio com port is the terminal
+pub(super) fn writable_com(
+    com_ports: &[(u8, ComBackend)],
+    n: u8,
+    field: &'static str,
+) -> Result<(), FormatError> {
+    captured_com(com_ports, n, field)?;
+    match com_ports.iter().find(|(com, _)| *com == n) {
+        Some((_, ComBackend::Stdio)) => Err(FormatError::ConsoleNotWritable { field, com: n }),
+        _ => Ok(()),
+    }
+}
+
+/// Check that vmrun can read the output of com port `n` for `field`: the
+/// output of bhyve for stdio, or the other side of a nmdm pair
+pub(super) fn captured_com(
+    com_ports: &[(u8, ComBackend)],
+    n: u8,
+    field: &'static str,
+) -> Result<(), FormatError> {
+    match com_ports.iter().find(|(com, _)| *com == n) {
+        None => Err(FormatError::ConsoleWithoutCom { field, com: n }),
+        Some((_, ComBackend::Stdio)) => Ok(()),
+        /* the TCP backend serves only one client, the operator */
+        Some((_, ComBackend::Tcp(_))) => Err(FormatError::ConsoleNotCaptured { field, com: n }),
+        Some((_, backend)) if backend.peer().is_none() => {
+            Err(FormatError::ConsoleNotCaptured { field, com: n })
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+impl VmSpec {
+    pub(super) fn console_triggers(
+        &self,
+        com_ports: &[(u8, ComBackend)],
+    ) -> Result<Vec<ConsoleTrigger>, FormatError> {
+        let mut triggers = vec![];
+        for trigger in self.console_triggers.iter() {
+            captured_com(com_ports, trigger.com, "console_triggers")?;
+            self.check_action(com_ports, trigger.com, &trigger.action, "console_triggers")?;
+            let pattern = regex::Regex::new(&trigger.pattern).map_err(|error| {
+                FormatError::InvalidTriggerPattern {
+                    pattern: trigger.pattern.to_string(),
+                    reason: error.to_string(),
+                }
+            })?;
+            triggers.push(ConsoleTrigger {
+                pattern,
+                com: trigger.com,
+                action: trigger.action.clone(),
+            });
+        }
+        Ok(triggers)
+    }
+
+    /// Check that the target an action switches to exists, and that the com
+    /// port keys are sent to takes them
+    fn check_action(
+        &self,
+        com_ports: &[(u8, ComBackend)],
+        com: u8,
+        action: &TriggerAction,
+        field: &'static str,
+    ) -> Result<(), FormatError> {
+        match action {
+            TriggerAction::SwitchTarget { target }
+                if target != "default" && !self.has_target(target) =>
+            {
+                Err(FormatError::UnknownTriggerTarget(target.to_string()))
+            }
+            TriggerAction::Send { .. } => writable_com(com_ports, com, field),
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn boot_script(
+        &self,
+        com_ports: &[(u8, ComBackend)],
+    ) -> Result<Option<BootScript>, FormatError> {
+        let script = match &self.boot_script {
+            Some(script) => script,
+            None => return Ok(None),
+        };
+        writable_com(com_ports, script.com, "boot_script")?;
+        let on_failure = match &script.on_failure {
+            Some(ActionSpec(action)) => action.clone(),
+            None => TriggerAction::Stop { failed: true },
+        };
+        self.check_action(com_ports, script.com, &on_failure, "boot_script")?;
+
+        let mut steps = vec![];
+        for step in script.steps.iter() {
+            steps.push(match step {
+                ScriptStepSpec::WaitFor {
+                    wait_for,
+                    timeout,
+                    on_failure,
+                } => {
+                    let on_failure = on_failure.as_ref().map(|ActionSpec(action)| action.clone());
+                    if let Some(action) = &on_failure {
+                        self.check_action(com_ports, script.com, action, "boot_script")?;
+                    }
+                    ScriptStep::WaitFor {
+                        pattern: regex::Regex::new(wait_for).map_err(|error| {
+                            FormatError::InvalidTriggerPattern {
+                                pattern: wait_for.to_string(),
+                                reason: error.to_string(),
+                            }
+                        })?,
+                        timeout: seconds(timeout.unwrap_or(script.timeout), "timeout")?,
+                        on_failure,
+                    }
+                }
+                ScriptStepSpec::Send { send } => ScriptStep::Send(parse_keys(send)),
+                ScriptStepSpec::Sleep { sleep } => ScriptStep::Sleep(seconds(*sleep, "sleep")?),
+            });
+        }
+        Ok(Some(BootScript {
+            com: script.com,
+            steps,
+            key_delay: seconds(script.key_delay, "key_delay")?,
+            on_failure,
+        }))
+    }
+
+    fn consoles_mut(&mut self) -> Vec<&mut Option<Console>> {
+        let mut consoles = vec![
+            &mut self.com1,
+            &mut self.com2,
+            &mut self.com3,
+            &mut self.com4,
+        ];
+        let mut targets: Vec<_> = self.targets.iter_mut().collect();
+        targets.sort_by(|a, b| a.0.cmp(b.0));
+        for (_, target) in targets {
+            consoles.extend([
+                &mut target.com1,
+                &mut target.com2,
+                &mut target.com3,
+                &mut target.com4,
+            ]);
+        }
+        consoles
+    }
+
+    /// Allocate a nmdm pair to each com port asking for one, in the root
+    /// configuration and every target. A pair is free if it is not given in
+    /// the configuration and no other VM recorded it under the run directory.
+    /// With `claim`, the pairs are recorded for this VM such that the
+    /// supervisor keeps them across reboots and targets, and the pairs newly
+    /// recorded are returned for the supervisor to release. On failure, the
+    /// pairs recorded so far are released
+    pub fn allocate_consoles(
+        &mut self,
+        host: &dyn Host,
+        claim: bool,
+    ) -> Result<Vec<u32>, FormatError> {
+        let name = self.name.clone();
+        let mut claimed = vec![];
+        let mut taken: BTreeSet<u32> = self
+            .consoles_mut()
+            .into_iter()
+            .filter_map(|console| match console {
+                Some(Console::Nmdm(Some(n))) => Some(*n),
+                Some(Console::Device(path)) => nmdm_number(path),
+                _ => None,
+            })
+            .collect();
+        let mut allocate = |number: &mut Option<u32>| {
+            for n in (0..NMDM_PAIRS).filter(|n| !taken.contains(n)) {
+                let available = if claim {
+                    match host.claim_nmdm(n, &name) {
+                        Ok(NmdmClaim::Created) => {
+                            claimed.push(n);
+                            true
+                        }
+                        Ok(NmdmClaim::Existing) => true,
+                        Ok(NmdmClaim::Taken) => false,
+                        Err(error) => return Err(FormatError::NmdmUnavailable(error.to_string())),
+                    }
+                } else {
+                    /* without a record to read, the supervisor decides */
+                    host.nmdm_owner(n)
+                        .ok()
+                        .flatten()
+                        .is_none_or(|owner| owner == name)
+                };
+                if available {
+                    taken.insert(n);
+                    *number = Some(n);
+                    return Ok(());
+                }
+            }
+            Err(FormatError::NoFreeNmdm(NMDM_PAIRS))
+        };
+        let result = self
+            .consoles_mut()
+            .into_iter()
+            .try_for_each(|console| match console {
+                Some(Console::Nmdm(number @ None)) => allocate(number),
+                _ => Ok(()),
+            });
+        if let Err(error) = result {
+            for n in claimed {
+                let _ = host.release_nmdm(n);
+            }
+            return Err(error);
+        }
+        Ok(claimed)
+    }
+
+    /// The com ports by their numbers, allocating the nmdm pairs if the
+    /// supervisor has not
+    pub(super) fn com_ports(&self, host: &dyn Host) -> Result<Vec<(u8, ComBackend)>, FormatError> {
+        let mut spec = self.clone();
+        spec.allocate_consoles(host, false)?;
+        Ok([spec.com1, spec.com2, spec.com3, spec.com4]
+            .into_iter()
+            .zip(1..)
+            .filter_map(|(console, n)| {
+                let backend = match console? {
+                    Console::Stdio => ComBackend::Stdio,
+                    Console::Nmdm(number) => ComBackend::Nmdm(number.unwrap_or_default()),
+                    Console::Device(path) => ComBackend::Device(path),
+                    Console::Tcp(address) => ComBackend::Tcp(address),
+                };
+                Some((n, backend))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::os::fake::FakeHost;
+
+    #[test]
+    fn parse_consoles() {
+        assert_eq!("stdio".parse::<Console>().unwrap(), Console::Stdio);
+        assert_eq!("nmdm".parse::<Console>().unwrap(), Console::Nmdm(None));
+        assert_eq!("nmdm3".parse::<Console>().unwrap(), Console::Nmdm(Some(3)));
+        assert_eq!(
+            "tcp=127.0.0.1:4321".parse::<Console>().unwrap(),
+            Console::Tcp("127.0.0.1:4321".to_string())
+        );
+        for invalid in ["nmdmX", "tcp=:1", "tcp=localhost", "ttyu0"] {
+            assert!(invalid.parse::<Console>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn allocate_nmdm_pairs() {
+        let config = r#"{
+            "name": "test",
+            "cpu": 1,
+            "mem": "512M",
+            "com1": "nmdm",
+            "com2": "/dev/nmdm1A",
+            "emulations": [],
+            "targets": {
+                "install": {"com1": "nmdm", "com3": "nmdm", "emulations": []}
+            }
+        }"#;
+        let spec: VmSpec = serde_json::from_str(config).unwrap();
+        /* pair 0 is recorded by another VM, pair 2 by this one before it crashed */
+        let host = FakeHost::default();
+        host.nmdm_pairs.borrow_mut().insert(0, "other".to_string());
+        host.nmdm_pairs.borrow_mut().insert(2, "test".to_string());
+
+        /* a build only reads the records */
+        let ports = spec.com_ports(&host).unwrap();
+        assert_eq!(ports[0], (1, ComBackend::Nmdm(2)));
+        assert_eq!(host.nmdm_pairs.borrow().len(), 2);
+
+        let mut allocated = spec.clone();
+        /* the pair recorded before is reused, but left to release to whoever
+         * recorded it
+         */
+        assert_eq!(allocated.allocate_consoles(&host, true).unwrap(), [3, 4]);
+        assert_eq!(allocated.com1, Some(Console::Nmdm(Some(2))));
+        let install = &allocated.targets["install"];
+        assert_eq!(install.com1, Some(Console::Nmdm(Some(3))));
+        assert_eq!(install.com3, Some(Console::Nmdm(Some(4))));
+        assert_eq!(host.nmdm_pairs.borrow()[&4], "test");
+
+        let ports = allocated.com_ports(&host).unwrap();
+        assert_eq!(ports[0].1.peer().as_deref(), Some("/dev/nmdm2B"));
+        assert_eq!(ports[1].1.peer().as_deref(), Some("/dev/nmdm1B"));
+
+        /* another VM skips the pairs of this one */
+        let mut other = spec.clone();
+        other.name = "other".to_string();
+        assert_eq!(other.allocate_consoles(&host, true).unwrap(), [5, 6]);
+
+        /* the search is bounded, and the pairs recorded before the failure
+         * are released
+         */
+        for n in 1..NMDM_PAIRS {
+            host.nmdm_pairs.borrow_mut().insert(n, "other".to_string());
+        }
+        host.nmdm_pairs.borrow_mut().remove(&0);
+        assert!(matches!(
+            spec.clone().allocate_consoles(&host, true),
+            Err(FormatError::NoFreeNmdm(NMDM_PAIRS))
+        ));
+        assert_eq!(host.nmdm_pairs.borrow().get(&0), None);
+    }
+
+    #[test]
+    fn console_triggers() {
+        let config = r#"{
+            "name": "test",
+            "cpu": 1,
+            "mem": "512M",
+            "com1": "stdio",
+            "com2": "nmdm4",
+            "emulations": [],
+            "console_triggers": [
+                {"match": "panic:|Kernel panic", "action": "stop", "failed": true},
+                {"match": "login: $", "com": 2, "action": "send", "keys": "root\r"}
+            ],
+            "targets": {
+                "install": {
+                    "emulations": [],
+                    "console_triggers": [
+                        {"match": "Installation complete", "action": "switch_target", "target": "default"}
+                    ]
+                }
+            }
+        }"#;
+        let spec: VmSpec = serde_json::from_str(config).unwrap();
+        let host = FakeHost::default();
+        let vmrun = spec.build(&host, &[]).unwrap();
+        assert_eq!(vmrun.console_triggers.len(), 2);
+        assert_eq!(
+            vmrun.console_triggers[0].action,
+            TriggerAction::Stop { failed: true }
+        );
+        assert!(vmrun.console_triggers[0]
+            .pattern
+            .is_match("Kernel panic - not syncing"));
+        assert_eq!(vmrun.console_triggers[1].com, 2);
+
+        /* a target replaces the triggers */
+        let mut install = spec.clone();
+        install.consume_target(&"install".to_string()).unwrap();
+        let vmrun = install.build(&host, &[]).unwrap();
+        assert_eq!(
+            vmrun.console_triggers[0].action,
+            TriggerAction::SwitchTarget {
+                target: "default".to_string()
+            }
+        );
+
+        let invalid = |trigger: &str| {
+            let mut config: serde_json::Value = serde_json::from_str(config).unwrap();
+            config["console_triggers"] = serde_json::from_str(trigger).unwrap();
+            let spec: VmSpec = serde_json::from_value(config).unwrap();
+            spec.build(&host, &[]).unwrap_err().to_string()
+        };
+        assert!(
+            invalid(r#"[{"match": "(", "action": "reset"}]"#).contains("Invalid console trigger")
+        );
+        assert_eq!(
+            invalid(r#"[{"match": "x", "com": 3, "action": "reset"}]"#),
+            "console_triggers reads com3, which is not configured"
+        );
+        assert!(
+            invalid(r#"[{"match": "x", "action": "switch_target", "target": "other"}]"#)
+                .contains("`other`")
+        );
+    }
+
+    #[test]
+    fn boot_script() {
+        let config = r#"{
+            "name": "test",
+            "cpu": 1,
+            "mem": "512M",
+            "com1": "stdio",
+            "com2": "nmdm4",
+            "emulations": [],
+            "targets": {
+                "install": {
+                    "emulations": [],
+                    "boot_script": {
+                        "com": 2,
+                        "timeout": 60,
+                        "steps": [
+                            {"wait_for": "Autoboot in"},
+                            {"send": "<esc>boot -s<enter>"},
+                            {"wait_for": "Welcome", "timeout": 600, "on_failure": {"action": "reset"}},
+                            {"sleep": 0.5}
+                        ]
+                    }
+                }
+            }
+        }"#;
+        let spec: VmSpec = serde_json::from_str(config).unwrap();
+        let host = FakeHost::default();
+        assert!(spec.build(&host, &[]).unwrap().boot_script.is_none());
+
+        let mut install = spec.clone();
+        install.consume_target(&"install".to_string()).unwrap();
+        let script = install.build(&host, &[]).unwrap().boot_script.unwrap();
+        assert_eq!(script.com, 2);
+        assert_eq!(script.on_failure, TriggerAction::Stop { failed: true });
+        assert!(matches!(
+            &script.steps[..],
+            [
+                ScriptStep::WaitFor { timeout: first, on_failure: None, .. },
+                ScriptStep::Send(_),
+                ScriptStep::WaitFor { timeout: second, on_failure: Some(TriggerAction::Reset), .. },
+                ScriptStep::Sleep(_),
+            ] if *first == Duration::from_secs(60) && *second == Duration::from_secs(600)
+        ));
+
+        let invalid = |script: &str| {
+            let mut config: serde_json::Value = serde_json::from_str(config).unwrap();
+            config["boot_script"] = serde_json::from_str(script).unwrap();
+            let spec: VmSpec = serde_json::from_value(config).unwrap();
+            spec.build(&host, &[]).unwrap_err().to_string()
+        };
+        /* the input of stdio is the terminal of the operator */
+        assert!(invalid(r#"{"com": 1, "steps": []}"#).contains("com1"));
+        assert!(invalid(r#"{"com": 2, "steps": [{"sleep": -1}]}"#).contains("`-1`"));
+        assert!(invalid(
+            r#"{"com": 2, "on_failure": {"action": "switch_target", "target": "other"}, "steps": []}"#
+        )
+        .contains("`other`"));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::spec::FormatError;
+use crate::util::os::Host;
 use paste::paste;
 use serde::{Deserialize, Deserializer};
 
@@ -14,11 +15,11 @@ where
     D: Deserializer<'de>,
 {
     let backend = hmap
-        .get(&"name".to_string())
+        .get("name")
         .map(|s| s.as_str())
         .ok_or_else(|| serde::de::Error::missing_field("name"))?;
 
-    let tpe = match hmap.get(&"type".to_string()) {
+    let tpe = match hmap.get("type") {
         None => {
             if backend.starts_with("tap") {
                 Ok(NetBackend::Tap)
@@ -47,10 +48,8 @@ where
         },
     }?;
 
-    let mtu = hmap
-        .get(&"mtu".to_string())
-        .and_then(|s| s.parse::<u32>().ok());
-    let mac = hmap.get(&"mac".to_string()).map(|s| s.to_string());
+    let mtu = hmap.get("mtu").and_then(|s| s.parse::<u32>().ok());
+    let mac = hmap.get("mac").map(|s| s.to_string());
 
     Ok(VirtioNet {
         tpe,
@@ -179,6 +178,7 @@ pub struct VirtioConsoleDef {
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct PciPassthruX {
+    #[serde(default, deserialize_with = "crate::spec::host_pci_slot")]
     src: Option<PciSlot>,
     lookup: Option<PciLookup>,
     rom: Option<String>,
@@ -191,14 +191,11 @@ struct PciLookup {
 }
 
 impl PciPassthruX {
-    fn to_pci_passthru(self) -> Option<PciPassthru> {
+    fn into_pci_passthru(self, host: &dyn Host) -> Option<PciPassthru> {
         match self.src {
             Some(src) => Some(PciPassthru { src, rom: self.rom }),
             None => {
-                if self.lookup.is_none() {
-                    None
-                } else {
-                    let lookup = self.lookup.unwrap();
+                if let Some(lookup) = self.lookup {
                     let vendor = lookup.vendor;
                     let device = lookup.device;
 
@@ -216,7 +213,7 @@ impl PciPassthruX {
 
                         println!("v1: {v1:x?}, v2: {v2:x?}, d1: {d1:x?}, d2: {d2:x?}");
 
-                        let devices = crate::util::os::pci::PciDevice::from_pciconf_l();
+                        let devices = host.pci_devices().ok()?;
 
                         for device in devices.iter() {
                             if device.vendor == v1
@@ -233,6 +230,8 @@ impl PciPassthruX {
 
                         None
                     }
+                } else {
+                    None
                 }
             }
         }
@@ -293,6 +292,9 @@ fn serde_default_emulation_fix() -> bool {
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Emulation {
+    /// Identity of the device for the stable slot assignment, derived from
+    /// the device if not given
+    pub id: Option<String>,
     pub slot: Option<PciSlot>,
     #[serde(default = "serde_default_emulation_fix")]
     pub fix: bool,
@@ -301,7 +303,48 @@ pub struct Emulation {
 }
 
 impl Emulation {
-    pub fn to_vm_emu(&self) -> Result<Box<dyn EmulatedPci>, FormatError> {
+    /// The bhyve device model of the emulation
+    pub fn model(&self) -> &'static str {
+        match &self.emulation {
+            Emulations::VirtioBlk(_) => "virtio-blk",
+            Emulations::VirtioNet(_) => "virtio-net",
+            Emulations::AhciCd(_) => "ahci-cd",
+            Emulations::AhciHd(_) => "ahci-hd",
+            Emulations::VirtioConsole(_) => "virtio-console",
+            Emulations::Nvme(_) => "nvme",
+            Emulations::Passthru(_) => "passthru",
+            Emulations::Raw { .. } => "raw",
+        }
+    }
+
+    /// The identity of the device, which is the `id` if given, otherwise the
+    /// model and what the device is backed by, for example
+    /// `virtio-blk:disk.img`
+    pub fn key(&self) -> String {
+        if let Some(id) = &self.id {
+            return id.to_string();
+        }
+        let model = self.model();
+        match &self.emulation {
+            Emulations::VirtioBlk(x) => format!("{model}:{}", x.path),
+            Emulations::VirtioNet(x) => format!("{model}:{}", x.name),
+            Emulations::AhciCd(x) => format!("{model}:{}", x.path),
+            Emulations::AhciHd(x) => format!("{model}:{}", x.path),
+            Emulations::VirtioConsole(x) => format!("{model}:{}", x.ports.join(",")),
+            Emulations::Nvme(x) => match &x.backend {
+                NvmeBackend::Ram(_) => format!("{model}:ram"),
+                NvmeBackend::Path(path) => format!("{model}:{path}"),
+            },
+            Emulations::Passthru(x) => match (&x.src, &x.lookup) {
+                (Some(src), _) => format!("{model}:{}", src.as_bhyve_arg()),
+                (None, Some(lookup)) => format!("{model}:{}:{}", lookup.vendor, lookup.device),
+                (None, None) => model.to_string(),
+            },
+            Emulations::Raw { value } => format!("{model}:{value}"),
+        }
+    }
+
+    pub fn to_vm_emu(&self, host: &dyn Host) -> Result<Box<dyn EmulatedPci>, FormatError> {
         match &self.emulation {
             Emulations::VirtioBlk(x) => Ok(Box::new(x.clone())),
             Emulations::VirtioNet(x) => Ok(Box::new(x.clone())),
@@ -309,8 +352,8 @@ impl Emulation {
             Emulations::AhciHd(x) => Ok(Box::new(x.clone())),
             Emulations::VirtioConsole(x) => Ok(Box::new(x.clone())),
             Emulations::Nvme(x) => Ok(Box::new(x.clone())),
-            Emulations::Passthru(x) => match x.clone().to_pci_passthru() {
-                None => Err(FormatError::InvalidUnit("".to_string())),
+            Emulations::Passthru(x) => match x.clone().into_pci_passthru(host) {
+                None => Err(FormatError::PassthruDeviceNotFound),
                 Some(passthru) => Ok(Box::new(passthru)),
             },
 
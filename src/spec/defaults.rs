@@ -11,3 +11,19 @@ pub fn default_bootopt() -> BootOptions {
 pub fn default_hostbridge() -> String {
     "hostbridge".to_string()
 }
+
+pub fn default_console_com() -> u8 {
+    1
+}
+
+pub fn default_script_timeout() -> f64 {
+    300.0
+}
+
+pub fn default_console_log_max_size() -> String {
+    "10M".to_string()
+}
+
+pub fn default_console_log_keep() -> usize {
+    5
+}
@@ -0,0 +1,335 @@
+use crate::spec::resolve::replaced_by_target;
+use crate::spec::VmSpec;
+use crate::vm::{ArgSource, LpcDevice, VmRun};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+
+/// Where a value of the VM comes from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Origin {
+    /// A field of the configuration file, `target` is set if the field is
+    /// from the patch of a target
+    Config {
+        file: String,
+        path: String,
+        target: Option<String>,
+    },
+    /// An override given with `--set`
+    Override { assignment: String },
+    /// A default of vmrun, named after the field or the default it comes from
+    Default { name: String },
+    /// The trailing arguments passed to bhyve on the command line
+    CommandLine,
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Config {
+                file,
+                path,
+                target: None,
+            } => write!(f, "{file}: {path}"),
+            Origin::Config {
+                file,
+                path,
+                target: Some(target),
+            } => write!(f, "{file}: {path} (target {target})"),
+            Origin::Override { assignment } => write!(f, "--set {assignment}"),
+            Origin::Default { name } => write!(f, "default ({name})"),
+            Origin::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// The origin of a group of bhyve arguments, and if the PCI slot of the
+/// device is assigned by vmrun rather than the configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Provenance {
+    pub origin: Origin,
+    pub auto_slot: bool,
+}
+
+impl Display for Provenance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.origin)?;
+        if self.auto_slot {
+            write!(f, ", auto-assigned slot")?;
+        }
+        Ok(())
+    }
+}
+
+/// Trace the fields of the configuration as JSON to the VM resolved from it,
+/// following the fields the target replaced during the resolution
+pub struct Explainer<'a> {
+    file: String,
+    config: &'a Value,
+    spec: &'a VmSpec,
+    target: Option<(&'a str, &'a Value)>,
+    overrides: &'a [String],
+}
+
+fn present(value: &Value, key: &str) -> bool {
+    value.get(key).is_some_and(|value| !value.is_null())
+}
+
+/// If an override to `assigned` changes the field at `path`, that is, one of
+/// the paths contains the other
+fn overlaps(assigned: &str, path: &str) -> bool {
+    let contains = |outer: &str, inner: &str| {
+        inner
+            .strip_prefix(outer)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+    };
+    contains(assigned, path) || contains(path, assigned)
+}
+
+impl<'a> Explainer<'a> {
+    /// `file` is the configuration file as given on the command line, `-` is
+    /// shown as stdin, and `spec` is the configuration resolved to the
+    /// target booted
+    pub fn new(
+        file: &str,
+        config: &'a Value,
+        spec: &'a VmSpec,
+        overrides: &'a [String],
+    ) -> Explainer<'a> {
+        let target = spec.consumed_target.as_deref().and_then(|target| {
+            config
+                .get("targets")
+                .and_then(|targets| targets.get(target))
+                .map(|patch| (target, patch))
+        });
+        Explainer {
+            file: if file == "-" { "stdin" } else { file }.to_string(),
+            config,
+            spec,
+            target,
+            overrides,
+        }
+    }
+
+    fn config(&self, path: String, target: Option<&str>) -> Origin {
+        let full_path = match target {
+            Some(target) => format!("targets.{target}.{path}"),
+            None => path,
+        };
+        let assignment = self.overrides.iter().rev().find(|assignment| {
+            let (assigned, _) = assignment.split_once('=').unwrap_or_default();
+            overlaps(assigned, &full_path)
+        });
+        match assignment {
+            Some(assignment) => Origin::Override {
+                assignment: assignment.to_string(),
+            },
+            None => Origin::Config {
+                file: self.file.to_string(),
+                path: full_path,
+                target: target.map(|target| target.to_string()),
+            },
+        }
+    }
+
+    /// The origin of a top level field, `default` names the default used
+    /// when the field is not given
+    fn field(&self, key: &str, default: &str) -> Origin {
+        if let Some((target, patch)) = self.target {
+            if replaced_by_target(self.spec, key) && present(patch, key) {
+                return self.config(key.to_string(), Some(target));
+            }
+        }
+        if present(self.config, key) {
+            self.config(key.to_string(), None)
+        } else {
+            Origin::Default {
+                name: default.to_string(),
+            }
+        }
+    }
+
+    fn emulations(value: &Value) -> &[Value] {
+        value
+            .get("emulations")
+            .and_then(|emulations| emulations.as_array())
+            .map_or(&[], |emulations| emulations.as_slice())
+    }
+
+    fn emulation(&self, index: usize) -> Provenance {
+        let root = Explainer::emulations(self.config);
+        let patch = match self.target {
+            Some((_, patch)) if self.spec.target_emulations > 0 => Explainer::emulations(patch),
+            _ => &[],
+        };
+
+        let (emulation, path, target) = if index < root.len() {
+            (&root[index], format!("emulations[{index}]"), None)
+        } else if index < root.len() + patch.len() {
+            let index = index - root.len();
+            let target = self.target.map(|(target, _)| target);
+            (&patch[index], format!("emulations[{index}]"), target)
+        } else {
+            /* the framebuffer and its tablet are added after the devices */
+            let key = if index == root.len() + patch.len() {
+                "graphic"
+            } else {
+                "graphic.xhci_table"
+            };
+            return Provenance {
+                origin: self.field("graphic", key),
+                auto_slot: true,
+            };
+        };
+
+        Provenance {
+            origin: self.config(path, target),
+            auto_slot: !present(emulation, "slot"),
+        }
+    }
+
+    fn extra_option(&self, index: usize) -> Origin {
+        let origin = self.field("extra_options", "extra_options");
+        let configured = match &origin {
+            Origin::Config { target: None, .. } => self.config.get("extra_options"),
+            Origin::Config { .. } => self
+                .target
+                .and_then(|(_, patch)| patch.get("extra_options")),
+            _ => None,
+        };
+        let count = configured
+            .and_then(|options| options.as_str())
+            .map_or(0, |options| {
+                options.split(' ').filter(|s| !s.is_empty()).count()
+            });
+        if index < count {
+            origin
+        } else {
+            Origin::CommandLine
+        }
+    }
+
+    fn source(&self, vmrun: &VmRun, source: ArgSource) -> Provenance {
+        let origin = match source {
+            ArgSource::Flag(field) => self.field(field, field),
+            ArgSource::Cpu => self.field("cpu", "cpu"),
+            ArgSource::Mem => self.field("mem", "mem"),
+            ArgSource::Gdb => self.field("gdb", "gdb"),
+            ArgSource::Uuid => self.field("uuid", "uuid"),
+            ArgSource::Hostbridge => {
+                return Provenance {
+                    origin: self.field("hostbridge", "default_hostbridge"),
+                    auto_slot: true,
+                }
+            }
+            ArgSource::Lpc => {
+                return Provenance {
+                    origin: self.field("lpc_slot", "lpc_slot"),
+                    auto_slot: !present(self.config, "lpc_slot"),
+                }
+            }
+            ArgSource::Emulation(index) => return self.emulation(index),
+            ArgSource::LpcDevice(index) => match &vmrun.lpc_devices[index] {
+                LpcDevice::Bootrom(..) => self.field("bootrom", "default_bootopt"),
+                LpcDevice::Com(n, _) => self.field(&format!("com{n}"), "com"),
+                LpcDevice::TestDev => Origin::Default {
+                    name: "pc-testdev".to_string(),
+                },
+            },
+            ArgSource::ExtraOption(index) => self.extra_option(index),
+            ArgSource::Name => self.field("name", "name"),
+        };
+        Provenance {
+            origin,
+            auto_slot: false,
+        }
+    }
+
+    /// The bhyve arguments of the VM built from the configuration, grouped
+    /// with their provenance
+    pub fn explain(&self, vmrun: &VmRun) -> Vec<(Vec<String>, Provenance)> {
+        vmrun
+            .bhyve_arg_groups()
+            .into_iter()
+            .map(|(source, args)| (args, self.source(vmrun, source)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::{apply_override, resolve_json};
+    use crate::util::os::plan::PlanHost;
+
+    #[test]
+    fn explain_args() {
+        let mut config: Value = serde_json::from_str(
+            r#"{
+                "name": "test",
+                "cpu": 2,
+                "mem": "512M",
+                "com1": "stdio",
+                "extra_options": "-P",
+                "emulations": [{"device": "virtio-blk", "path": "disk.img", "slot": "0:5:0"}],
+                "targets": {
+                    "install": {
+                        "cpu": 4,
+                        "emulations": [{"device": "ahci-cd", "path": "install.iso"}]
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let overrides = vec!["mem=1G".to_string()];
+        apply_override(&mut config, &overrides[0]).unwrap();
+
+        let mut spec: VmSpec = serde_json::from_value(config.clone()).unwrap();
+        spec.consume_target(&"install".to_string()).unwrap();
+        assert_eq!(resolve_json(&config, &spec)["cpu"], 4);
+        let vmrun = spec
+            .build(&PlanHost::default(), &["-x".to_string()])
+            .unwrap();
+
+        let explainer = Explainer::new("vm.json", &config, &spec, &overrides);
+        let explained: Vec<(String, String)> = explainer
+            .explain(&vmrun)
+            .into_iter()
+            .map(|(args, provenance)| (args.join(" "), provenance.to_string()))
+            .collect();
+        let explained: Vec<(&str, &str)> = explained
+            .iter()
+            .map(|(args, provenance)| (args.as_str(), provenance.as_str()))
+            .collect();
+
+        assert_eq!(
+            explained,
+            vec![
+                ("-A", "default (generate_acpi)"),
+                ("-H", "default (yield_on_hlt)"),
+                ("-u", "default (utc_clock)"),
+                ("-c 4", "vm.json: targets.install.cpu (target install)"),
+                ("-m 1048576K", "--set mem=1G"),
+                (
+                    "-s 0:0:0,hostbridge",
+                    "default (default_hostbridge), auto-assigned slot"
+                ),
+                ("-s 0:31:0,lpc", "default (lpc_slot), auto-assigned slot"),
+                ("-s 0:5:0,virtio-blk,disk.img", "vm.json: emulations[0]"),
+                (
+                    "-s 0:1:0,ahci-cd,install.iso",
+                    "vm.json: targets.install.emulations[0] (target install), auto-assigned slot"
+                ),
+                (
+                    "-l bootrom,/usr/local/share/uefi-firmware/BHYVE_UEFI.fd",
+                    "default (default_bootopt)"
+                ),
+                ("-l com1,stdio", "vm.json: com1"),
+                ("-P", "vm.json: extra_options"),
+                ("-x", "command line"),
+                ("test", "vm.json: name"),
+            ]
+        );
+    }
+}
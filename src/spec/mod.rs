@@ -1,18 +1,32 @@
+mod console;
 mod decoding;
 mod defaults;
+mod explain;
+mod overrides;
+mod resolve;
+mod slots;
 mod util;
 
 use crate::spec::util::PciSlotGenerator;
+use crate::util::os::Host;
 use crate::util::{parse_mem_in_kb, vec_sequence_map};
-use crate::vm::{CpuSpec, EmulatedPciDevice, LpcDevice, PciSlot, UefiBoot, VmRun};
+use crate::vm::loader::{Bhyveload, GrubBoot, Loader};
+use crate::vm::{
+    ConsoleLogging, CpuSpec, EmulatedPciDevice, LpcDevice, PciSlot, UefiBoot, VarFile, VmRun,
+};
 
 use decoding::Emulation;
 use serde::{de, Deserialize, Deserializer};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
 use thiserror::Error;
 
+pub use console::{BootScriptSpec, Console, ConsoleTriggerSpec};
 use defaults::*;
+pub use explain::{Explainer, Provenance};
+pub use overrides::{apply_override, failing_override};
+pub use resolve::resolve_json;
+use slots::SlotAssignment;
 
 #[derive(Error, Debug)]
 pub enum FormatError {
@@ -38,11 +52,68 @@ pub enum FormatError {
     #[error("Cannot find a slot on bus 0 for hostbridge")]
     HostbridgeSlotNotSatisfy,
 
-    #[error("Run out of all Pcie slots")]
-    RunOutOfSlots,
+    #[error("Run out of PCI slots, {0} device(s) cannot be placed")]
+    RunOutOfSlots(usize),
 
     #[error("Selected target not found")]
     ProfileNotFound,
+
+    #[error("Cannot find the passthru device, lookup by vendor and device requires a bhyve host")]
+    PassthruDeviceNotFound,
+
+    #[error("PCI slot {0} is reserved, but assigned to a device")]
+    ReservedPciSlot(String),
+
+    #[error("PCI slot {0} is assigned to more than one device")]
+    DuplicatedPciSlot(String),
+
+    #[error("PCI slot {0} has devices on functions other than 0, but none on function 0")]
+    MissingPciFunctionZero(String),
+
+    #[error("pack_functions cannot be used with the stable slot assignment")]
+    PackingWithStableSlots,
+
+    #[error("grub `kernel` is on the `root` device, but no `root` is given")]
+    GrubKernelWithoutRoot,
+
+    #[error("grub needs at least one device in `devices`")]
+    GrubWithoutDevice,
+
+    #[error("reset_vars needs a varfile with a template to reset the variable store from")]
+    ResetVarsWithoutTemplate,
+
+    #[error("No free nmdm pair among the first {0}")]
+    NoFreeNmdm(u32),
+
+    #[error("Cannot record the nmdm pair of a console: {0}")]
+    NmdmUnavailable(String),
+
+    #[error("Cannot read the recorded PCI slots: {0}")]
+    UnreadableSlotRecord(String),
+
+    #[error("Invalid com port backend `{value}`: {reason}")]
+    InvalidConsole { value: String, reason: String },
+
+    #[error("{field} reads com{com}, which is not configured")]
+    ConsoleWithoutCom { field: &'static str, com: u8 },
+
+    #[error("{field} cannot read com{com}, only stdio and nmdm com ports can be read by vmrun")]
+    ConsoleNotCaptured { field: &'static str, com: u8 },
+
+    #[error("{field} types into com{com}, only nmdm com ports can be typed into by vmrun")]
+    ConsoleNotWritable { field: &'static str, com: u8 },
+
+    #[error("Invalid boot_script: {0}")]
+    InvalidBootScript(String),
+
+    #[error("Invalid console trigger pattern `{pattern}`: {reason}")]
+    InvalidTriggerPattern { pattern: String, reason: String },
+
+    #[error("console trigger switches to target `{0}`, which is not defined")]
+    UnknownTriggerTarget(String),
+
+    #[error("Cannot apply override `{assignment}`: {reason}")]
+    InvalidOverride { assignment: String, reason: String },
 }
 
 fn yes() -> bool {
@@ -73,6 +144,38 @@ pub struct VmSpec {
 
     pub lpc_slot: Option<PciSlot>,
 
+    /// Slots never given to a device without an explicit slot, for example
+    /// to keep them for a passthru device plugged in later
+    #[serde(default)]
+    pub reserved_slots: Vec<PciSlot>,
+
+    #[serde(default)]
+    pub slot_assignment: SlotAssignment,
+
+    /// Pack the devices of the same model without an explicit slot into the
+    /// functions of a slot, instead of a slot for each
+    #[serde(default = "no")]
+    pub pack_functions: bool,
+
+    /// Copy the template of the UEFI variable store over the store before
+    /// booting, for example for a target installing the guest
+    #[serde(default = "no")]
+    pub reset_vars: bool,
+
+    /// Number of the devices at the end of `emulations` added by the targets
+    /// consumed
+    #[serde(skip)]
+    pub target_emulations: usize,
+
+    /// The target consumed, if any
+    #[serde(skip)]
+    pub consumed_target: Option<String>,
+
+    /// The fields replaced by the targets consumed, by their names in
+    /// `VmSpecMod`
+    #[serde(skip)]
+    pub replaced: Vec<&'static str>,
+
     /* currently bhyve supports only up to 4 console ports,
      * if we implement a general N console port model, the model may ended up
      * pretty ugly with the extra nest levels and require extra effort for
@@ -80,10 +183,10 @@ pub struct VmSpec {
      * 8 lines is probably a good trade off.
      */
     /// TODO: implement checks
-    pub com1: Option<String>,
-    pub com2: Option<String>,
-    pub com3: Option<String>,
-    pub com4: Option<String>,
+    pub com1: Option<Console>,
+    pub com2: Option<Console>,
+    pub com3: Option<Console>,
+    pub com4: Option<Console>,
 
     /// TODO: implement check
     pub gdb: Option<String>,
@@ -122,6 +225,16 @@ pub struct VmSpec {
     pub next_target: Option<String>,
 
     pub post_start_script: Option<String>,
+
+    /// Log the output of a com port, the com port stays interactive
+    pub console_log: Option<ConsoleLogSpec>,
+
+    /// Actions on the patterns printed by the guest on its com ports
+    #[serde(default)]
+    pub console_triggers: Vec<ConsoleTriggerSpec>,
+
+    /// Keys typed into a com port as the guest boots
+    pub boot_script: Option<BootScriptSpec>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -132,10 +245,10 @@ pub struct VmSpecMod {
     pub bootopt: Option<BootOptions>,
     pub emulations: Vec<Emulation>,
     pub gdb: Option<String>,
-    pub com1: Option<String>,
-    pub com2: Option<String>,
-    pub com3: Option<String>,
-    pub com4: Option<String>,
+    pub com1: Option<Console>,
+    pub com2: Option<Console>,
+    pub com3: Option<Console>,
+    pub com4: Option<Console>,
     pub utc_clock: Option<bool>,
     pub yield_on_hlt: Option<bool>,
     pub generate_acpi: Option<bool>,
@@ -146,22 +259,33 @@ pub struct VmSpecMod {
     pub next_target: Option<String>,
     pub post_start_script: Option<String>,
     pub graphic: Option<GraphicOption>,
+    pub reset_vars: Option<bool>,
+    pub console_triggers: Option<Vec<ConsoleTriggerSpec>>,
+    pub boot_script: Option<BootScriptSpec>,
 }
 
 macro_rules! replace_if_some {
     ($self:expr, $other:expr, $field:ident) => {
         if let Some(value) = &$other.$field {
             $self.$field = value.clone();
+            $self.record_replaced(stringify!($field));
         }
     };
     ($self:expr, $other:expr, ?$field:ident) => {
         if $other.$field.is_some() {
             $self.$field = $other.$field.clone();
+            $self.record_replaced(stringify!($field));
         }
     };
 }
 
 impl VmSpec {
+    fn record_replaced(&mut self, field: &'static str) {
+        if !self.replaced.contains(&field) {
+            self.replaced.push(field);
+        }
+    }
+
     pub fn consume(&mut self, patch: &VmSpecMod) {
         replace_if_some!(self, patch, cpu);
         replace_if_some!(self, patch, mem);
@@ -182,8 +306,12 @@ impl VmSpec {
         replace_if_some!(self, patch, ?next_target);
         replace_if_some!(self, patch, ?post_start_script);
         replace_if_some!(self, patch, ?graphic);
+        replace_if_some!(self, patch, reset_vars);
+        replace_if_some!(self, patch, console_triggers);
+        replace_if_some!(self, patch, ?boot_script);
 
         self.emulations.extend(patch.emulations.clone());
+        self.target_emulations += patch.emulations.len();
     }
 
     #[allow(dead_code)]
@@ -193,19 +321,41 @@ impl VmSpec {
         clone
     }
 
-    pub fn build(&self, extra_opts: &[String]) -> Result<VmRun, FormatError> {
+    /// Build the VM, the `host` is used to resolve passthru devices specified
+    /// by vendor and device id
+    pub fn build(&self, host: &dyn Host, extra_opts: &[String]) -> Result<VmRun, FormatError> {
         let mut argv: Vec<String> = Vec::new();
 
         let mut emus: Vec<crate::vm::EmulatedPciDevice> = Vec::new();
         let mut lpcs: Vec<crate::vm::LpcDevice> = Vec::new();
 
-        /* slots explicitly specified in the configuration */
-        let mut slots_taken: Vec<PciSlot> = self.emulations.iter().filter_map(|e| e.slot).collect();
+        /* slots explicitly specified in the configuration, in the stable mode
+         * the slots of the devices of every target are kept free
+         */
+        let mut slots_taken: Vec<PciSlot> = match self.slot_assignment {
+            SlotAssignment::Sequential => self.emulations.iter().filter_map(|e| e.slot).collect(),
+            SlotAssignment::Stable => self.reserved_slots(),
+        };
 
         if let Some(lpc_slot) = self.lpc_slot {
             slots_taken.push(lpc_slot);
         }
 
+        /* a reserved slot is reserved with all its functions */
+        let reserved = |slot: &PciSlot| {
+            self.reserved_slots
+                .iter()
+                .any(|r| (r.bus, r.slot) == (slot.bus, slot.slot))
+        };
+        if let Some(slot) = self.emulations.iter().filter_map(|e| e.slot).find(reserved) {
+            return Err(FormatError::ReservedPciSlot(slot.as_bhyve_arg()));
+        }
+        slots_taken.extend(
+            self.reserved_slots
+                .iter()
+                .map(|slot| PciSlot { func: 0, ..*slot }),
+        );
+
         let mut slot_gen = PciSlotGenerator::build(0, 0, slots_taken);
 
         let hostbdg_slot = slot_gen
@@ -224,9 +374,27 @@ impl VmSpec {
 
         let bootopt = self.bootopt.clone().unwrap_or_else(default_bootopt);
 
-        match bootopt {
+        let loader = match bootopt {
             BootOptions::Uefi(UefiBoot { bootrom, varfile }) => {
-                lpcs.push(LpcDevice::Bootrom(bootrom, varfile))
+                let varfile = varfile.map(|varfile| VarFile {
+                    reset: self.reset_vars,
+                    ..varfile
+                });
+                if self.reset_vars && varfile.as_ref().and_then(|v| v.template.as_ref()).is_none() {
+                    return Err(FormatError::ResetVarsWithoutTemplate);
+                }
+                lpcs.push(LpcDevice::Bootrom(bootrom, varfile));
+                None
+            }
+            BootOptions::Bhyveload { bhyveload } => Some(Loader::Bhyveload(bhyveload)),
+            BootOptions::Grub { grub } => {
+                if grub.devices.is_empty() {
+                    return Err(FormatError::GrubWithoutDevice);
+                }
+                if grub.kernel.is_some() && grub.root.is_none() {
+                    return Err(FormatError::GrubKernelWithoutRoot);
+                }
+                Some(Loader::Grub(grub))
             }
         };
 
@@ -246,56 +414,110 @@ impl VmSpec {
 
         extra_options.extend(extra_opts.to_owned());
 
-        for emulation in &self.emulations {
-            let the_slot = if let Some(slot) = emulation.slot {
-                Ok(slot)
-            } else {
-                slot_gen.next_slot().ok_or(FormatError::RunOutOfSlots)
-            }?;
+        let stable_slots = match self.slot_assignment {
+            SlotAssignment::Sequential => None,
+            SlotAssignment::Stable if self.pack_functions => {
+                return Err(FormatError::PackingWithStableSlots)
+            }
+            SlotAssignment::Stable => Some(self.stable_slots(host, &mut slot_gen)?),
+        };
+
+        /* the slot each device model is being packed into */
+        let mut packed: HashMap<&'static str, PciSlot> = HashMap::new();
+
+        let mut next_slot = |key: &str, model: Option<&'static str>| {
+            if let Some(slots) = &stable_slots {
+                return slots.get(key).copied();
+            }
+            let packing = model.filter(|_| self.pack_functions);
+            if let Some(slot) = packing.and_then(|model| packed.get_mut(model)) {
+                if let Some(next) = slot_gen.next_function(*slot) {
+                    *slot = next;
+                    return Some(next);
+                }
+            }
+            let slot = slot_gen.next_slot()?;
+            if let Some(model) = packing {
+                packed.insert(model, slot);
+            }
+            Some(slot)
+        };
+
+        /* keep placing after running out, to report how many do not fit */
+        let mut unplaced = 0;
+
+        for (emulation, key) in self.emulations.iter().zip(self.emulation_keys()) {
+            let the_slot = match emulation
+                .slot
+                .or_else(|| next_slot(&key, Some(emulation.model())))
+            {
+                Some(slot) => slot,
+                None => {
+                    unplaced += 1;
+                    continue;
+                }
+            };
 
             emus.push(crate::vm::EmulatedPciDevice {
                 slot: the_slot,
                 want_fix: emulation.fix,
-                emulation: emulation.to_vm_emu()?,
+                emulation: emulation.to_vm_emu(host)?,
             });
         }
 
-        if let Some(com) = &self.com1 {
-            lpcs.push(LpcDevice::Com(1, com.to_string()));
-        }
-
-        if let Some(com) = &self.com2 {
-            lpcs.push(LpcDevice::Com(2, com.to_string()));
-        }
-
-        if let Some(com) = &self.com3 {
-            lpcs.push(LpcDevice::Com(3, com.to_string()));
-        }
-
-        if let Some(com) = &self.com4 {
-            lpcs.push(LpcDevice::Com(4, com.to_string()));
+        let com_ports = self.com_ports(host)?;
+        let console_log = match &self.console_log {
+            None => None,
+            Some(log) => {
+                console::captured_com(&com_ports, log.com, "console_log")?;
+                Some(ConsoleLogging {
+                    path: log.path.to_string(),
+                    com: log.com,
+                    max_bytes: parse_mem_in_kb(&log.max_size)? as u64 * 1024,
+                    keep: log.keep,
+                })
+            }
+        };
+        let console_triggers = self.console_triggers(&com_ports)?;
+        let boot_script = self.boot_script(&com_ports)?;
+        for (n, backend) in com_ports {
+            lpcs.push(LpcDevice::Com(n, backend));
         }
 
         if let Some(graphic) = &self.graphic {
-            let slot = slot_gen.next_slot().ok_or(FormatError::RunOutOfSlots)?;
-            emus.push(EmulatedPciDevice {
-                slot,
-                want_fix: false,
-                emulation: Box::new(graphic.to_emulated()),
-            });
+            match next_slot(slots::GRAPHIC_KEY, None) {
+                None => unplaced += 1,
+                Some(slot) => emus.push(EmulatedPciDevice {
+                    slot,
+                    want_fix: false,
+                    emulation: Box::new(graphic.to_emulated()),
+                }),
+            }
 
             if graphic.xhci_table {
-                let slot = slot_gen.next_slot().ok_or(FormatError::RunOutOfSlots)?;
-                emus.push(EmulatedPciDevice {
-                    want_fix: false,
-                    slot,
-                    emulation: Box::new(crate::vm::emulation::Xhci {}),
-                })
+                match next_slot(slots::XHCI_KEY, None) {
+                    None => unplaced += 1,
+                    Some(slot) => emus.push(EmulatedPciDevice {
+                        want_fix: false,
+                        slot,
+                        emulation: Box::new(crate::vm::emulation::Xhci {}),
+                    }),
+                }
             }
         }
 
+        if unplaced > 0 {
+            return Err(FormatError::RunOutOfSlots(unplaced));
+        }
+
         argv.push(self.name.clone());
 
+        check_slot_layout(
+            [hostbdg_slot, lpc_slot]
+                .into_iter()
+                .chain(emus.iter().map(|emu| emu.slot)),
+        )?;
+
         Ok(crate::vm::VmRun {
             cpu: self.cpu,
             mem_kb: self.mem.kb,
@@ -315,6 +537,15 @@ impl VmSpec {
             disable_mptable_gen: self.disable_mptable_gen,
             power_off_destroy_vm: self.power_off_destroy_vm,
             extra_options,
+            /* assume a modern bhyve if it cannot be probed */
+            config_syntax: host
+                .bhyve_capabilities()
+                .map_or(true, |capabilities| capabilities.config_options),
+            loader,
+            console_log,
+            console_triggers,
+            boot_script,
+            stable_slots: stable_slots.unwrap_or_default(),
         })
     }
 
@@ -338,10 +569,36 @@ impl VmSpec {
             .ok_or(FormatError::ProfileNotFound)?
             .clone();
         self.consume(&modification);
+        self.consumed_target = Some(target.to_string());
         Ok(())
     }
 }
 
+/// Every device must be on its own function, and a slot with any device on a
+/// function other than 0 must have a device on function 0 for the guest to
+/// find the others
+fn check_slot_layout(slots: impl Iterator<Item = PciSlot>) -> Result<(), FormatError> {
+    let mut seen: Vec<PciSlot> = vec![];
+    for slot in slots {
+        if seen.contains(&slot) {
+            return Err(FormatError::DuplicatedPciSlot(slot.as_bhyve_arg()));
+        }
+        seen.push(slot);
+    }
+
+    for slot in seen.iter().filter(|slot| slot.func > 0) {
+        let function_zero = PciSlot { func: 0, ..*slot };
+        if !seen.contains(&function_zero) {
+            return Err(FormatError::MissingPciFunctionZero(format!(
+                "{}:{}",
+                slot.bus, slot.slot
+            )));
+        }
+    }
+
+    Ok(())
+}
+
 /// Right biased `Either`, like in Scala and Haskell
 /// When deserialize, Right will be prioritized, and only if
 /// Deserialize as Right failed, Left will be deserialized
@@ -356,7 +613,74 @@ enum Either<L, R> {
 #[serde(remote = "UefiBoot")]
 struct UefiBootDef {
     bootrom: String,
-    varfile: Option<String>,
+    #[serde(default, deserialize_with = "varfile")]
+    varfile: Option<VarFile>,
+}
+
+/// The variable store is either a path, or a path with the template it is
+/// copied from
+fn varfile<'de, D>(deserializer: D) -> Result<Option<VarFile>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Templated {
+        template: String,
+        path: String,
+    }
+
+    Ok(
+        Option::<Either<String, Templated>>::deserialize(deserializer)?.map(
+            |either| match either {
+                Either::Left(path) => VarFile {
+                    path,
+                    template: None,
+                    reset: false,
+                },
+                Either::Right(Templated { template, path }) => VarFile {
+                    path,
+                    template: Some(template),
+                    reset: false,
+                },
+            },
+        ),
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "Bhyveload", deny_unknown_fields)]
+struct BhyveloadDef {
+    disk: String,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    console: Option<String>,
+    host_dir: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "GrubBoot", deny_unknown_fields)]
+struct GrubBootDef {
+    devices: BTreeMap<String, String>,
+    root: Option<String>,
+    directory: Option<String>,
+    kernel: Option<String>,
+    initrd: Option<String>,
+    cmdline: Option<String>,
+    console: Option<String>,
+    work_dir: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ConsoleLogSpec {
+    path: String,
+    #[serde(default = "default_console_com")]
+    com: u8,
+    #[serde(default = "default_console_log_max_size")]
+    max_size: String,
+    #[serde(default = "default_console_log_keep")]
+    keep: usize,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -388,27 +712,40 @@ impl GraphicOption {
 }
 
 impl PciSlot {
-    #[allow(dead_code)]
+    /// Parse a slot of the virtual PCI bus of bhyve, where the bus, slot and
+    /// func are limited to `MAX_BUS`, `MAX_SLOT` and `MAX_FUNC`
     fn from_bhyve_vpci_slot(s: &str) -> Result<PciSlot, FormatError> {
         let pci = PciSlot::from_str(s)?;
-        if pci.slot > 31 {
-            Err(FormatError::PciSlotValueOverflow {
-                component: "slot",
-                value: pci.slot,
-                max: 31,
-            })
-        } else if pci.func > 7 {
-            Err(FormatError::PciSlotValueOverflow {
-                component: "func",
-                value: pci.slot,
-                max: 7,
-            })
-        } else {
-            Ok(pci)
+        let limits = [
+            ("bus", pci.bus, PciSlot::MAX_BUS),
+            ("slot", pci.slot, PciSlot::MAX_SLOT),
+            ("func", pci.func, PciSlot::MAX_FUNC),
+        ];
+        for (component, value, max) in limits {
+            if value > max {
+                return Err(FormatError::PciSlotValueOverflow {
+                    component,
+                    value,
+                    max,
+                });
+            }
         }
+        Ok(pci)
     }
 }
 
+/// A slot on the host rather than the virtual PCI bus, for example the source
+/// of a passthru device
+pub(crate) fn host_pci_slot<'de, D>(deserializer: D) -> Result<Option<PciSlot>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let str_value: Option<String> = Option::deserialize(deserializer)?;
+    str_value
+        .map(|s| PciSlot::from_str(s.as_str()).map_err(de::Error::custom))
+        .transpose()
+}
+
 impl FromStr for PciSlot {
     type Err = FormatError;
     fn from_str(s: &str) -> Result<PciSlot, Self::Err> {
@@ -449,16 +786,17 @@ impl<'de> Deserialize<'de> for PciSlot {
         D: Deserializer<'de>,
     {
         let str_value: String = String::deserialize(deserializer)?;
-        PciSlot::from_str(str_value.as_str()).map_err(de::Error::custom)
+        PciSlot::from_bhyve_vpci_slot(str_value.as_str()).map_err(de::Error::custom)
     }
 }
 
 impl CpuSpec {
-    pub fn from_flat(threads: usize) -> CpuSpec {
+    /// The topology bhyve gives to `-c N`, a socket for every vCPU
+    pub fn from_flat(cpus: usize) -> CpuSpec {
         CpuSpec {
-            threads,
+            threads: 1,
             cores: 1,
-            sockets: 1,
+            sockets: cpus,
         }
     }
 }
@@ -476,7 +814,7 @@ impl<'de> Deserialize<'de> for CpuSpec {
         }
 
         Either::<usize, ProxyCpuSpec>::deserialize(deserializer).map(|either| match either {
-            Either::Left(threads) => CpuSpec::from_flat(threads),
+            Either::Left(cpus) => CpuSpec::from_flat(cpus),
             Either::Right(spec) => CpuSpec {
                 threads: spec.threads,
                 cores: spec.cores,
@@ -511,4 +849,12 @@ impl<'de> Deserialize<'de> for MemorySpec {
 pub enum BootOptions {
     #[serde(with = "UefiBootDef")]
     Uefi(UefiBoot),
+    Bhyveload {
+        #[serde(with = "BhyveloadDef")]
+        bhyveload: Bhyveload,
+    },
+    Grub {
+        #[serde(with = "GrubBootDef")]
+        grub: GrubBoot,
+    },
 }
@@ -0,0 +1,186 @@
+use crate::spec::{FormatError, VmSpec};
+use serde_json::{Map, Value};
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment<'_>>, String> {
+    let mut segments = vec![];
+    for part in path.split('.') {
+        let (key, mut indices) = part.split_at(part.find('[').unwrap_or(part.len()));
+        if key.is_empty() {
+            return Err(format!("empty key in `{path}`"));
+        }
+        segments.push(Segment::Key(key));
+        while !indices.is_empty() {
+            let (index, rest) = indices
+                .strip_prefix('[')
+                .and_then(|s| s.split_once(']'))
+                .ok_or_else(|| format!("unbalanced `[` in `{part}`"))?;
+            let index = index
+                .parse::<usize>()
+                .map_err(|_| format!("invalid index `{index}` in `{part}`"))?;
+            segments.push(Segment::Index(index));
+            indices = rest;
+        }
+    }
+    Ok(segments)
+}
+
+fn describe(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+fn set(config: &mut Value, path: &[Segment], value: Value) -> Result<(), String> {
+    let mut node = config;
+    let mut walked = String::new();
+
+    for (i, segment) in path.iter().enumerate() {
+        let last = i + 1 == path.len();
+        node = match segment {
+            Segment::Key(key) => {
+                /* missing objects on the way are created, such as a new target */
+                if node.is_null() {
+                    *node = Value::Object(Map::new());
+                }
+                let kind = describe(node);
+                let object = node
+                    .as_object_mut()
+                    .ok_or_else(|| format!("`{walked}` is {kind}, not an object"))?;
+                if !walked.is_empty() {
+                    walked.push('.');
+                }
+                walked.push_str(key);
+                object.entry(key.to_string()).or_insert(Value::Null)
+            }
+            Segment::Index(index) => {
+                let kind = describe(node);
+                let array = node
+                    .as_array_mut()
+                    .ok_or_else(|| format!("`{walked}` is {kind}, not an array"))?;
+                let len = array.len();
+                walked.push_str(&format!("[{index}]"));
+                array.get_mut(*index).ok_or_else(|| {
+                    format!("`{walked}` is out of range, the array has {len} items")
+                })?
+            }
+        };
+        if last {
+            *node = value;
+            return Ok(());
+        }
+    }
+
+    Err("empty path".to_string())
+}
+
+/// Apply an override in the form of `PATH=VALUE` to the configuration as
+/// JSON, where `PATH` is the dotted path to a field such as `mem` or
+/// `emulations[2].path`. `VALUE` is parsed as JSON, and taken as a string if
+/// it is not valid JSON, such that `mem=4G` and `cpu=4` both work
+pub fn apply_override(config: &mut Value, assignment: &str) -> Result<(), FormatError> {
+    let error = |reason: String| FormatError::InvalidOverride {
+        assignment: assignment.to_string(),
+        reason,
+    };
+
+    let (path, value) = assignment
+        .split_once('=')
+        .ok_or_else(|| error("expected PATH=VALUE".to_string()))?;
+    let path = parse_path(path).map_err(error)?;
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+
+    set(config, &path, value).map_err(error)
+}
+
+/// The first of `assignments` after which `config` no longer reads as a VM,
+/// replaying them in order. `None` if the configuration is invalid without
+/// the overrides, or only the overrides together break it
+pub fn failing_override<'a>(config: &Value, assignments: &'a [String]) -> Option<&'a str> {
+    let valid = |config: &Value| serde_json::from_value::<VmSpec>(config.clone()).is_ok();
+    if !valid(config) {
+        return None;
+    }
+    let mut config = config.clone();
+    for assignment in assignments {
+        apply_override(&mut config, assignment).ok()?;
+        if !valid(&config) {
+            return Some(assignment);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn override_paths() {
+        let mut config = json!({
+            "mem": "512M",
+            "emulations": [{"device": "ahci-cd", "path": "old.iso"}],
+            "targets": {"install": {"next_target": "install"}}
+        });
+
+        apply_override(&mut config, "mem=4G").unwrap();
+        apply_override(&mut config, "cpu=4").unwrap();
+        apply_override(&mut config, "emulations[0].path=/iso/new.iso").unwrap();
+        apply_override(&mut config, "targets.install.next_target=default").unwrap();
+        apply_override(&mut config, "targets.debug.gdb=\"localhost:1234\"").unwrap();
+
+        assert_eq!(config["mem"], "4G");
+        assert_eq!(config["cpu"], 4);
+        assert_eq!(config["emulations"][0]["path"], "/iso/new.iso");
+        assert_eq!(config["targets"]["install"]["next_target"], "default");
+        assert_eq!(config["targets"]["debug"]["gdb"], "localhost:1234");
+
+        for (assignment, reason) in [
+            ("mem", "expected PATH=VALUE"),
+            (
+                "emulations[1].path=x",
+                "`emulations[1]` is out of range, the array has 1 items",
+            ),
+            ("mem.unit=M", "`mem` is a string, not an object"),
+            ("targets[0]=x", "`targets` is an object, not an array"),
+            ("emulations[x]=1", "invalid index `x` in `emulations[x]`"),
+        ] {
+            match apply_override(&mut config, assignment) {
+                Err(FormatError::InvalidOverride {
+                    assignment: failed,
+                    reason: actual,
+                }) => {
+                    assert_eq!(failed, assignment);
+                    assert_eq!(actual, reason);
+                }
+                other => panic!("{assignment}: unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn blame_override() {
+        let config = json!({
+            "name": "test", "cpu": 1, "mem": "512M",
+            "emulations": [{"device": "ahci-cd", "path": "old.iso"}]
+        });
+        let assignments = ["mem=4G", "emulations[0].path=2024", "cpu=2"].map(String::from);
+        assert_eq!(
+            failing_override(&config, &assignments),
+            Some("emulations[0].path=2024")
+        );
+        assert_eq!(failing_override(&config, &assignments[..1]), None);
+        assert_eq!(failing_override(&json!({"cpu": 1}), &assignments), None);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::spec::VmSpec;
+use serde_json::{Map, Value};
+
+/// Fields of the boot options, which are replaced as a whole
+const BOOT_OPTIONS: [&str; 4] = ["bootrom", "varfile", "bhyveload", "grub"];
+
+/// The field of `VmSpecMod` holding the key of the configuration, the boot
+/// options are flattened into their own keys
+fn field_of(key: &str) -> &str {
+    if BOOT_OPTIONS.contains(&key) {
+        "bootopt"
+    } else {
+        key
+    }
+}
+
+/// If the key of the configuration is taken from the target consumed by
+/// `spec` rather than from the root configuration
+pub(super) fn replaced_by_target(spec: &VmSpec, key: &str) -> bool {
+    spec.replaced.contains(&field_of(key))
+}
+
+/// The configuration as JSON of the VM resolved into `spec`, such that the
+/// result can be shown as it is written. The fields are taken from the
+/// target wherever `VmSpec::consume` took them from it
+pub fn resolve_json(config: &Value, spec: &VmSpec) -> Value {
+    let mut resolved = config.as_object().cloned().unwrap_or_default();
+    let targets = resolved.remove("targets").unwrap_or(Value::Null);
+
+    let patch = spec
+        .consumed_target
+        .as_ref()
+        .and_then(|target| targets.get(target))
+        .and_then(|patch| patch.as_object());
+    if let Some(patch) = patch {
+        consume(&mut resolved, patch, spec);
+    }
+    Value::Object(resolved)
+}
+
+fn consume(resolved: &mut Map<String, Value>, patch: &Map<String, Value>, spec: &VmSpec) {
+    for field in spec.replaced.iter() {
+        let keys = match field {
+            &"bootopt" => &BOOT_OPTIONS[..],
+            field => std::slice::from_ref(field),
+        };
+        for key in keys {
+            match patch.get(*key) {
+                Some(value) => resolved.insert(key.to_string(), value.clone()),
+                None => resolved.remove(*key),
+            };
+        }
+    }
+
+    /* the devices of the target are added after those of the root */
+    if let (true, Some(Value::Array(added))) = (spec.target_emulations > 0, patch.get("emulations"))
+    {
+        match resolved.get_mut("emulations") {
+            Some(Value::Array(base)) => base.extend(added.iter().cloned()),
+            _ => {
+                resolved.insert("emulations".to_string(), Value::Array(added.clone()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::FormatError;
+    use crate::util::os::plan::PlanHost;
+
+    fn resolve(config: &Value, target: Option<&str>) -> Result<Value, FormatError> {
+        let mut spec: VmSpec = serde_json::from_value(config.clone()).unwrap();
+        if let Some(target) = target.filter(|t| *t != "default" || spec.has_target(&t.to_string()))
+        {
+            spec.consume_target(&target.to_string())?;
+        }
+        Ok(resolve_json(config, &spec))
+    }
+
+    const CONFIG: &str = r#"{
+        "name": "test",
+        "cpu": 2,
+        "mem": "512M",
+        "com1": "stdio",
+        "bootrom": "/boot/uefi.fd",
+        "varfile": "/vm/vars.fd",
+        "emulations": [{"device": "virtio-blk", "path": "disk.img"}],
+        "targets": {
+            "install": {
+                "cpu": 4,
+                "bootrom": "/boot/other.fd",
+                "emulations": [{"device": "ahci-cd", "path": "install.iso"}],
+                "next_target": "default"
+            }
+        }
+    }"#;
+
+    fn argv(spec: &VmSpec) -> Vec<String> {
+        spec.build(&PlanHost::default(), &[])
+            .unwrap()
+            .bhyve_args()
+            .unwrap()
+    }
+
+    #[test]
+    fn resolve_as_consume() {
+        let config: Value = serde_json::from_str(CONFIG).unwrap();
+        let spec: VmSpec = serde_json::from_value(config.clone()).unwrap();
+
+        for target in [None, Some("install"), Some("default")] {
+            let resolved = resolve(&config, target).unwrap();
+            assert!(resolved.get("targets").is_none());
+
+            let mut expected = spec.clone();
+            if let Some("install") = target {
+                expected.consume_target(&"install".to_string()).unwrap();
+            }
+            let resolved: VmSpec = serde_json::from_value(resolved).unwrap();
+            assert_eq!(argv(&resolved), argv(&expected));
+        }
+
+        assert!(resolve(&config, Some("missing")).is_err());
+    }
+
+    #[test]
+    fn reset_vars_per_target() {
+        let config = r#"{
+            "name": "test",
+            "cpu": 1,
+            "mem": "512M",
+            "bootrom": "/boot/uefi.fd",
+            "varfile": {"template": "/boot/vars.fd", "path": "/vm/vars.fd"},
+            "emulations": [],
+            "targets": {
+                "install": {"reset_vars": true, "emulations": []},
+                "plain": {"bootrom": "/boot/uefi.fd", "reset_vars": true, "emulations": []}
+            }
+        }"#;
+        let config: Value = serde_json::from_str(config).unwrap();
+        let host = PlanHost::default();
+
+        let resets = |target: Option<&str>| {
+            let resolved = resolve(&config, target).unwrap();
+            let spec: VmSpec = serde_json::from_value(resolved).unwrap();
+            spec.build(&host, &[])
+                .map(|vmrun| vmrun.varfile().and_then(|v| v.reset_recovery()).is_some())
+        };
+        assert!(matches!(resets(None), Ok(false)));
+        assert!(matches!(resets(Some("install")), Ok(true)));
+        assert!(matches!(
+            resets(Some("plain")),
+            Err(FormatError::ResetVarsWithoutTemplate)
+        ));
+    }
+}
@@ -0,0 +1,426 @@
+use crate::spec::decoding::Emulation;
+use crate::spec::util::PciSlotGenerator;
+use crate::spec::{FormatError, VmSpec};
+use crate::util::os::Host;
+use crate::vm::PciSlot;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// How the devices without an explicit slot are placed
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlotAssignment {
+    /// In the order of the devices, such that adding a device shifts the
+    /// devices after it
+    #[default]
+    Sequential,
+    /// By the identity of the devices, such that a device keeps its slot
+    /// regardless of the target selected and the devices around it
+    Stable,
+}
+
+pub const GRAPHIC_KEY: &str = "graphic";
+pub const XHCI_KEY: &str = "graphic:xhci";
+
+/// FNV-1a, the slots must not change across builds of vmrun, which the
+/// hasher of std does not promise
+fn fnv1a(key: &str) -> u32 {
+    key.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// The identities of the devices, a device repeated in the same list is told
+/// apart by the order it appears, counted from `seen`
+fn keys(emulations: &[Emulation], seen: &mut HashMap<String, usize>) -> Vec<String> {
+    emulations
+        .iter()
+        .map(|emulation| {
+            let key = emulation.key();
+            let count = seen.entry(key.clone()).or_insert(0);
+            *count += 1;
+            match *count {
+                1 => key,
+                n => format!("{key}#{n}"),
+            }
+        })
+        .collect()
+}
+
+impl VmSpec {
+    /// The devices of the root configuration, without those added by the
+    /// targets consumed
+    fn root_emulations(&self) -> &[Emulation] {
+        &self.emulations[..self.emulations.len() - self.target_emulations]
+    }
+
+    /// The identities of the devices currently in the configuration, in the
+    /// same order as `emulations`
+    pub(super) fn emulation_keys(&self) -> Vec<String> {
+        let mut seen = HashMap::new();
+        let mut all = keys(self.root_emulations(), &mut seen);
+        let added = &self.emulations[self.emulations.len() - self.target_emulations..];
+        all.extend(keys(added, &mut seen));
+        all
+    }
+
+    /// Every device of the root configuration and of every target, with the
+    /// slots given explicitly. The result is the same whichever target is
+    /// consumed
+    fn slot_universe(&self) -> (BTreeSet<String>, Vec<PciSlot>) {
+        let mut auto = BTreeSet::new();
+        let mut explicit = vec![];
+        let mut graphic = self.graphic.is_some();
+
+        let mut place = |emulations: &[Emulation], keys: Vec<String>| {
+            for (emulation, key) in emulations.iter().zip(keys) {
+                match emulation.slot {
+                    Some(slot) => explicit.push(slot),
+                    None => {
+                        auto.insert(key);
+                    }
+                }
+            }
+        };
+
+        let mut root_seen = HashMap::new();
+        let root = self.root_emulations();
+        place(root, keys(root, &mut root_seen));
+
+        for patch in self.targets.values() {
+            let mut seen = root_seen.clone();
+            place(&patch.emulations, keys(&patch.emulations, &mut seen));
+            graphic = graphic || patch.graphic.is_some();
+        }
+
+        if graphic {
+            auto.insert(GRAPHIC_KEY.to_string());
+            auto.insert(XHCI_KEY.to_string());
+        }
+
+        (auto, explicit)
+    }
+
+    /// The slots explicitly given to any device of any target, which are
+    /// kept free for them in the stable mode
+    pub(super) fn reserved_slots(&self) -> Vec<PciSlot> {
+        self.slot_universe().1
+    }
+
+    /// Place every device of the root configuration and of the targets. A
+    /// device keeps the slot recorded by the last launch when it is still
+    /// free, the others are hashed to a slot of bus 0 and take the next
+    /// free slot from there. The new devices are placed in the order of
+    /// their identities, such that the result does not depend on the order
+    /// in the configuration
+    pub(super) fn stable_slots(
+        &self,
+        host: &dyn Host,
+        slot_gen: &mut PciSlotGenerator,
+    ) -> Result<BTreeMap<String, PciSlot>, FormatError> {
+        let (auto, _) = self.slot_universe();
+        let recorded = host
+            .recorded_slots(&self.name)
+            .map_err(|error| FormatError::UnreadableSlotRecord(error.to_string()))?;
+
+        let mut candidates = vec![];
+        while let Some(slot) = slot_gen.next_slot() {
+            if slot.bus > 0 && candidates.len() >= auto.len() {
+                break;
+            }
+            candidates.push(slot);
+        }
+        if candidates.len() < auto.len() {
+            return Err(FormatError::RunOutOfSlots(auto.len() - candidates.len()));
+        }
+
+        let mut taken = vec![false; candidates.len()];
+        let mut slots = BTreeMap::new();
+        for key in auto.iter() {
+            let index = recorded
+                .get(key)
+                .and_then(|slot| candidates.iter().position(|c| c == slot));
+            if let Some(index) = index.filter(|index| !taken[*index]) {
+                taken[index] = true;
+                slots.insert(key.clone(), candidates[index]);
+            }
+        }
+
+        /* the home of a device does not depend on the other devices, such
+         * that a new device only moves past those already there
+         */
+        for key in auto {
+            if slots.contains_key(&key) {
+                continue;
+            }
+            let home = (fnv1a(&key) % 32) as u8;
+            let mut index = candidates
+                .iter()
+                .position(|slot| slot.bus == 0 && slot.slot >= home)
+                .unwrap_or(0);
+            while taken[index] {
+                index = (index + 1) % candidates.len();
+            }
+            taken[index] = true;
+            slots.insert(key, candidates[index]);
+        }
+        Ok(slots)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::spec::{FormatError, VmSpec};
+    use crate::util::os::fake::FakeHost;
+    use crate::util::os::plan::PlanHost;
+    use crate::util::os::Host;
+    use crate::vm::PciSlot;
+    use std::collections::BTreeMap;
+
+    const CONFIG: &str = r#"{
+        "name": "test",
+        "cpu": 2,
+        "mem": "512M",
+        "slot_assignment": "stable",
+        "emulations": [
+            {"device": "virtio-blk", "path": "disk.img"},
+            {"device": "virtio-net", "name": "tap0"},
+            {"device": "virtio-blk", "path": "data.img", "slot": "0:5:0"}
+        ],
+        "targets": {
+            "install": {
+                "emulations": [
+                    {"device": "ahci-cd", "path": "install.iso"},
+                    {"device": "virtio-net", "name": "tap0"}
+                ]
+            },
+            "rescue": {
+                "emulations": [{"device": "ahci-cd", "path": "rescue.iso", "id": "cd"}]
+            }
+        }
+    }"#;
+
+    fn slots(spec: &VmSpec) -> BTreeMap<PciSlot, String> {
+        spec.build(&PlanHost::default(), &[]).unwrap().slot_map()
+    }
+
+    #[test]
+    fn stable_slots() {
+        let root: VmSpec = serde_json::from_str(CONFIG).unwrap();
+        let mut install = root.clone();
+        install.consume_target(&"install".to_string()).unwrap();
+        let mut rescue = root.clone();
+        rescue.consume_target(&"rescue".to_string()).unwrap();
+
+        let (root, install, rescue) = (slots(&root), slots(&install), slots(&rescue));
+
+        /* the devices of the root configuration keep their slots */
+        for (slot, device) in root.iter() {
+            assert_eq!(install.get(slot), Some(device));
+            assert_eq!(rescue.get(slot), Some(device));
+        }
+        assert_eq!(root.len() + 2, install.len());
+        assert_eq!(root.len() + 1, rescue.len());
+
+        /* devices only in different targets do not share a slot */
+        let cd = |map: &BTreeMap<PciSlot, String>, iso: &str| {
+            map.iter()
+                .find(|(_, device)| device.contains(iso))
+                .map(|(slot, _)| *slot)
+                .unwrap()
+        };
+        assert_ne!(cd(&install, "install.iso"), cd(&rescue, "rescue.iso"));
+
+        /* adding a device in front does not move the others */
+        let mut config: serde_json::Value = serde_json::from_str(CONFIG).unwrap();
+        config["emulations"].as_array_mut().unwrap().insert(
+            0,
+            serde_json::json!({"device": "virtio-blk", "path": "new.img"}),
+        );
+        let grown: VmSpec = serde_json::from_value(config).unwrap();
+        let grown = slots(&grown);
+        for (slot, device) in root.iter() {
+            assert_eq!(grown.get(slot), Some(device), "{device} moved");
+        }
+    }
+
+    /// The slots of the devices by their images, placed on `host`
+    fn disks(config: &serde_json::Value, host: &FakeHost) -> BTreeMap<String, String> {
+        let spec: VmSpec = serde_json::from_value(config.clone()).unwrap();
+        let vmrun = spec.build(host, &[]).unwrap();
+        vmrun
+            .slot_map()
+            .into_iter()
+            .filter_map(|(slot, device)| {
+                let image = device.strip_prefix("virtio-blk,")?;
+                Some((image.to_string(), slot.as_bhyve_arg()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stable_slots_collision() {
+        let mut config = serde_json::json!({
+            "name": "test", "cpu": 1, "mem": "512M", "slot_assignment": "stable",
+            "emulations": [{"device": "virtio-blk", "path": "disk.img"}]
+        });
+        let host = FakeHost::default();
+        let alone = disks(&config, &host);
+        assert_eq!(alone["disk.img"], "0:2:0");
+
+        /* a59.img hashes to the same slot as disk.img, and sorts first */
+        config["emulations"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({"device": "virtio-blk", "path": "a59.img"}));
+        let fresh = disks(&config, &host);
+        assert_eq!(fresh["a59.img"], "0:2:0");
+        assert_eq!(fresh["disk.img"], "0:3:0");
+
+        /* once recorded, disk.img stays and the new device moves instead */
+        let spec: VmSpec = serde_json::from_value(serde_json::json!({
+            "name": "test", "cpu": 1, "mem": "512M", "slot_assignment": "stable",
+            "emulations": [{"device": "virtio-blk", "path": "disk.img"}]
+        }))
+        .unwrap();
+        let vmrun = spec.build(&host, &[]).unwrap();
+        host.record_slots("test", &vmrun.stable_slots).unwrap();
+        let grown = disks(&config, &host);
+        assert_eq!(grown["disk.img"], "0:2:0");
+        assert_eq!(grown["a59.img"], "0:3:0");
+
+        /* a slot given explicitly in a target moves nothing else */
+        let before = disks(&config, &FakeHost::default());
+        config["targets"] = serde_json::json!({"data": {"emulations": [
+            {"device": "virtio-blk", "path": "data.img", "slot": "0:9:0"}
+        ]}});
+        assert_eq!(disks(&config, &FakeHost::default()), before);
+    }
+
+    fn build(emulations: &str, pack: bool) -> Result<BTreeMap<PciSlot, String>, FormatError> {
+        let config = format!(
+            r#"{{"name": "test", "cpu": 1, "mem": "512M", "pack_functions": {pack}, "emulations": {emulations}}}"#
+        );
+        let spec: VmSpec = serde_json::from_str(&config).unwrap();
+        spec.build(&PlanHost::default(), &[])
+            .map(|vmrun| vmrun.slot_map())
+    }
+
+    #[test]
+    fn multi_function_slots() {
+        let disks = r#"[
+            {"device": "virtio-blk", "path": "a.img"},
+            {"device": "virtio-net", "name": "tap0"},
+            {"device": "virtio-blk", "path": "b.img"},
+            {"device": "virtio-blk", "path": "c.img", "slot": "0:1:2"},
+            {"device": "virtio-blk", "path": "d.img"}
+        ]"#;
+        let packed: Vec<(String, String)> = build(disks, true)
+            .unwrap()
+            .into_iter()
+            .map(|(slot, device)| (slot.as_bhyve_arg(), device))
+            .collect();
+        let packed: Vec<(&str, &str)> = packed
+            .iter()
+            .map(|(slot, device)| (slot.as_str(), device.as_str()))
+            .collect();
+        assert_eq!(
+            packed,
+            vec![
+                ("0:0:0", "hostbridge"),
+                ("0:1:0", "virtio-blk,a.img"),
+                ("0:1:1", "virtio-blk,b.img"),
+                ("0:1:2", "virtio-blk,c.img"),
+                ("0:1:3", "virtio-blk,d.img"),
+                ("0:2:0", "virtio-net,tap0,type=tap"),
+                ("0:31:0", "lpc"),
+            ]
+        );
+        assert_eq!(build(disks, false).unwrap().len(), 7);
+
+        let duplicated = r#"[
+            {"device": "virtio-blk", "path": "a.img", "slot": "0:4:0"},
+            {"device": "virtio-blk", "path": "b.img", "slot": "4"}
+        ]"#;
+        assert!(matches!(
+            build(duplicated, false),
+            Err(FormatError::DuplicatedPciSlot(slot)) if slot == "0:4:0"
+        ));
+
+        let orphan = r#"[{"device": "virtio-blk", "path": "a.img", "slot": "0:4:1"}]"#;
+        assert!(matches!(
+            build(orphan, false),
+            Err(FormatError::MissingPciFunctionZero(slot)) if slot == "0:4"
+        ));
+    }
+
+    #[test]
+    fn slot_limits() {
+        for (slot, component) in [
+            ("256:0:0", "256:0:0"),
+            ("0:32:0", "slot"),
+            ("0:1:8", "func"),
+        ] {
+            let emulations =
+                format!(r#"[{{"device": "virtio-blk", "path": "a.img", "slot": "{slot}"}}]"#);
+            let config = format!(
+                r#"{{"name": "test", "cpu": 1, "mem": "512M", "emulations": {emulations}}}"#
+            );
+            let error = serde_json::from_str::<VmSpec>(&config).unwrap_err();
+            assert!(error.to_string().contains(component), "{slot}: {error}");
+        }
+
+        /* bhyve has 256 buses */
+        let last_bus = r#"[{"device": "virtio-blk", "path": "a.img", "slot": "255:0:0"}]"#;
+        assert!(build(last_bus, false).is_ok());
+
+        /* slot 31 is only kept for the lpc on bus 0 */
+        let disks: Vec<String> = (0..62)
+            .map(|i| format!(r#"{{"device": "virtio-blk", "path": "{i}.img"}}"#))
+            .collect();
+        let placed = build(&format!("[{}]", disks.join(",")), false).unwrap();
+        assert_eq!(
+            placed
+                .keys()
+                .last()
+                .map(|slot| slot.as_bhyve_arg())
+                .as_deref(),
+            Some("1:31:0")
+        );
+
+        /* 8192 slots on the 256 buses, less the hostbridge and the lpc */
+        let disks: Vec<String> = (0..8193)
+            .map(|i| format!(r#"{{"device": "virtio-blk", "path": "{i}.img"}}"#))
+            .collect();
+        assert!(matches!(
+            build(&format!("[{}]", disks.join(",")), false),
+            Err(FormatError::RunOutOfSlots(3))
+        ));
+
+        let config = r#"{
+            "name": "test", "cpu": 1, "mem": "512M",
+            "reserved_slots": ["0:1:0", "0:3:0"],
+            "emulations": [
+                {"device": "virtio-blk", "path": "a.img"},
+                {"device": "virtio-blk", "path": "b.img"}
+            ]
+        }"#;
+        let spec: VmSpec = serde_json::from_str(config).unwrap();
+        let slots: Vec<String> = slots(&spec)
+            .keys()
+            .map(|slot| slot.as_bhyve_arg())
+            .collect();
+        assert_eq!(slots, vec!["0:0:0", "0:2:0", "0:4:0", "0:31:0"]);
+
+        let mut spec = spec;
+        spec.emulations[0].slot = Some(PciSlot {
+            bus: 0,
+            slot: 3,
+            func: 1,
+        });
+        assert!(matches!(
+            spec.build(&PlanHost::default(), &[]),
+            Err(FormatError::ReservedPciSlot(slot)) if slot == "0:3:1"
+        ));
+    }
+}
@@ -65,27 +65,36 @@ impl PciSlotGenerator {
         }
     }
 
+    /// The next function free in the same slot as `slot`, for packing
+    /// devices into a multi-function slot
+    pub fn next_function(&mut self, slot: PciSlot) -> Option<PciSlot> {
+        let next = (slot.func + 1..=7)
+            .map(|func| PciSlot { func, ..slot })
+            .find(|next| !self.skip.contains(next))?;
+        self.skip.push(next);
+        Some(next)
+    }
+
+    /// The next free slot, every slot of a bus including slot 31 before the
+    /// next bus
     pub fn next_slot(&mut self) -> Option<PciSlot> {
-        if self.bus == 255 && self.slot == 31 {
-            None
-        } else {
-            if self.slot == 31 {
+        loop {
+            if self.slot > PciSlot::MAX_SLOT {
+                if self.bus == PciSlot::MAX_BUS {
+                    return None;
+                }
                 self.bus += 1;
                 self.slot = 0;
-                self.next_slot()
-            } else {
-                let ret = PciSlot {
-                    bus: self.bus,
-                    slot: self.slot,
-                    func: 0,
-                };
-                self.slot += 1;
+            }
+            let ret = PciSlot {
+                bus: self.bus,
+                slot: self.slot,
+                func: 0,
+            };
+            self.slot += 1;
 
-                if self.skip.contains(&ret) {
-                    self.next_slot()
-                } else {
-                    Some(ret)
-                }
+            if !self.skip.contains(&ret) {
+                return Some(ret);
             }
         }
     }
@@ -1,10 +1,14 @@
-pub type Recovery = dyn Fn();
+use crate::util::os::Host;
+use crate::vm::recovery::{Recovery, RecoveryError};
+use serde::ser::{Serialize, SerializeMap, Serializer};
 
 pub enum Assertion {
     /// An error that fixes are possible and can be performed by the supervisor
-    Recoverable(String, String, Box<Recovery>),
+    Recoverable(String, String, Recovery),
     /// An error that is fatal and require explicit operator action to clear
     Fatal(String, String),
+    /// An advisory finding that never blocks the launch of the VM
+    Warning(String, String),
     /// This is a combination of list of other assertions
     Container(Vec<(String, Assertion)>),
 }
@@ -22,6 +26,11 @@ impl std::fmt::Display for Assertion {
                 .field(&scope)
                 .field(&description)
                 .finish(),
+            Assertion::Warning(scope, description) => f
+                .debug_tuple("Assertion::Warning")
+                .field(&scope)
+                .field(&description)
+                .finish(),
             Assertion::Container(items) => {
                 let mut debug = f.debug_struct("Assertion::Container");
                 for (key, assertion) in items.iter() {
@@ -48,6 +57,11 @@ impl std::fmt::Debug for Assertion {
                 .field(&scope)
                 .field(&description)
                 .finish(),
+            Assertion::Warning(scope, description) => f
+                .debug_tuple("Assertion::Warning")
+                .field(&scope)
+                .field(&description)
+                .finish(),
             Assertion::Container(items) => {
                 let mut debug = f.debug_struct("Assertion::Container");
                 for (key, assertion) in items.iter() {
@@ -61,11 +75,57 @@ impl std::fmt::Debug for Assertion {
     }
 }
 
+/// Assertions are serialized as a tree of tagged objects, for example
+/// `{"kind": "fatal", "scope": "exists", "reason": "..."}`, where containers
+/// carry their children as a list of `{"name": ..., "assertion": ...}` and
+/// whether all of their children are recoverable
+impl Serialize for Assertion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(serde::Serialize)]
+        struct Item<'a> {
+            name: &'a str,
+            assertion: &'a Assertion,
+        }
+
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            Assertion::Recoverable(scope, reason, recovery) => {
+                map.serialize_entry("kind", "recoverable")?;
+                map.serialize_entry("scope", scope)?;
+                map.serialize_entry("reason", reason)?;
+                map.serialize_entry("recovery", recovery)?;
+            }
+            Assertion::Fatal(scope, reason) => {
+                map.serialize_entry("kind", "fatal")?;
+                map.serialize_entry("scope", scope)?;
+                map.serialize_entry("reason", reason)?;
+            }
+            Assertion::Warning(scope, reason) => {
+                map.serialize_entry("kind", "warning")?;
+                map.serialize_entry("scope", scope)?;
+                map.serialize_entry("reason", reason)?;
+            }
+            Assertion::Container(list) => {
+                let items: Vec<_> = list
+                    .iter()
+                    .map(|(name, assertion)| Item { name, assertion })
+                    .collect();
+                map.serialize_entry("kind", "container")?;
+                map.serialize_entry("recoverable", &self.is_recoverable())?;
+                map.serialize_entry("items", &items)?;
+            }
+        }
+        map.end()
+    }
+}
+
 impl Assertion {
+    /// If the assertion does not contain any fatal failure. Warnings are
+    /// always considered recoverable as they never block the launch
     pub fn is_recoverable(&self) -> bool {
         match self {
             Assertion::Fatal(_, _) => false,
-            Assertion::Recoverable(_, _, _) => true,
+            Assertion::Recoverable(_, _, _) | Assertion::Warning(_, _) => true,
             Assertion::Container(list) => {
                 for l in list.iter() {
                     if !l.1.is_recoverable() {
@@ -77,17 +137,41 @@ impl Assertion {
         }
     }
 
+    /// If there is any recovery can be performed by the supervisor
+    pub fn has_recovery(&self) -> bool {
+        match self {
+            Assertion::Recoverable(_, _, _) => true,
+            Assertion::Fatal(_, _) | Assertion::Warning(_, _) => false,
+            Assertion::Container(list) => list.iter().any(|l| l.1.has_recovery()),
+        }
+    }
+
+    /// Turn every warning into a fatal failure
+    pub fn promote_warnings(self) -> Assertion {
+        match self {
+            Assertion::Warning(scope, why) => Assertion::Fatal(scope, why),
+            Assertion::Container(list) => Assertion::Container(
+                list.into_iter()
+                    .map(|(name, assertion)| (name, assertion.promote_warnings()))
+                    .collect(),
+            ),
+            otherwise => otherwise,
+        }
+    }
+
     pub fn recovery_prompt(&self) -> String {
         let mut base = String::new();
 
         match self {
-            Assertion::Fatal(_, _) => (),
-            Assertion::Recoverable(obj, why, _) => base.push_str(format!("{obj}: {why}").as_str()),
+            Assertion::Fatal(_, _) | Assertion::Warning(_, _) => (),
+            Assertion::Recoverable(obj, why, recovery) => {
+                base.push_str(format!("{obj}: {why} (fix: {})", recovery.describe()).as_str())
+            }
             Assertion::Container(list) => {
                 for l in list.iter() {
                     if !l.1.is_recoverable() {
                         return base;
-                    } else {
+                    } else if l.1.has_recovery() {
                         base.push_str(format!("{}:", l.0).as_str());
                         for line in l.1.recovery_prompt().lines() {
                             base.push_str(format!("\n  {}", line).as_str());
@@ -100,22 +184,42 @@ impl Assertion {
         base
     }
 
-    pub fn recover(&self) {
+    /// Apply all recoveries in this assertion, the recoveries that revert
+    /// the applied changes are pushed to `undo`
+    pub fn recover(&self, host: &dyn Host, undo: &mut Vec<Recovery>) -> Result<(), RecoveryError> {
         match self {
-            Assertion::Fatal(_, _) => (),
-            Assertion::Recoverable(_, _, f) => f(),
+            Assertion::Fatal(_, _) | Assertion::Warning(_, _) => (),
+            Assertion::Recoverable(_, _, recovery) => {
+                if let Some(revert) = recovery.apply(host)? {
+                    undo.push(revert);
+                }
+            }
             Assertion::Container(list) => {
                 for l in list.iter() {
-                    l.1.recover();
+                    l.1.recover(host, undo)?;
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Collect all recoveries in this assertion along with the path to them
+    pub fn recoveries(&self, scope: String) -> Vec<(String, &Recovery)> {
+        match self {
+            Assertion::Fatal(_, _) | Assertion::Warning(_, _) => vec![],
+            Assertion::Recoverable(loc, _, recovery) => vec![(format!("{scope}/{loc}"), recovery)],
+            Assertion::Container(list) => list
+                .iter()
+                .flat_map(|(name, assertion)| assertion.recoveries(format!("{scope}/{name}")))
+                .collect(),
+        }
     }
 
     pub fn print(&self, scope: String) -> String {
         match self {
             Assertion::Fatal(loc, why) => format!("[fatal] {loc}: {why}"),
             Assertion::Recoverable(loc, why, _) => format!("[recoverable] {loc}: {why}"),
+            Assertion::Warning(loc, why) => format!("[warning] {loc}: {why}"),
             Assertion::Container(list) => {
                 let mut value = format!("{scope}\n");
                 for i in 0..list.len() {
@@ -150,3 +254,68 @@ impl Assertion {
         Assertion::Fatal("std::io::error".to_string(), format!("{:#?}", err))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn warning(scope: &str) -> Assertion {
+        Assertion::Warning(scope.to_string(), format!("{scope} looks odd"))
+    }
+
+    fn tap() -> Assertion {
+        Assertion::Recoverable(
+            "tap0".to_string(),
+            "tap0 does not exist".to_string(),
+            Recovery::CreateTap("tap0".to_string()),
+        )
+    }
+
+    #[test]
+    fn warnings_are_recoverable() {
+        let warnings = Assertion::Container(vec![
+            ("mem".to_string(), warning("mem")),
+            (
+                "vpci".to_string(),
+                Assertion::Container(vec![("cd".to_string(), warning("cd"))]),
+            ),
+        ]);
+        assert!(warnings.is_recoverable());
+        assert!(!warnings.has_recovery());
+
+        let mixed = Assertion::Container(vec![
+            ("mem".to_string(), warning("mem")),
+            ("tap".to_string(), tap()),
+        ]);
+        assert!(mixed.is_recoverable());
+        assert!(mixed.has_recovery());
+    }
+
+    #[test]
+    fn promote_warnings() {
+        let promoted = Assertion::Container(vec![
+            ("mem".to_string(), warning("mem")),
+            (
+                "vpci".to_string(),
+                Assertion::Container(vec![
+                    ("cd".to_string(), warning("cd")),
+                    ("tap".to_string(), tap()),
+                ]),
+            ),
+        ])
+        .promote_warnings();
+        assert!(!promoted.is_recoverable());
+        assert!(promoted.has_recovery());
+
+        let Assertion::Container(list) = &promoted else {
+            panic!("expected a container, got {promoted}");
+        };
+        assert!(matches!(&list[0].1, Assertion::Fatal(scope, _) if scope == "mem"));
+        let Assertion::Container(vpci) = &list[1].1 else {
+            panic!("expected a container, got {}", list[1].1);
+        };
+        assert!(matches!(&vpci[0].1, Assertion::Fatal(scope, _) if scope == "cd"));
+        /* the recoveries are left alone */
+        assert!(matches!(&vpci[1].1, Assertion::Recoverable(..)));
+    }
+}
@@ -3,6 +3,54 @@ pub mod os;
 
 use crate::spec::FormatError;
 use num_traits::Num;
+use std::path::PathBuf;
+
+/// The directory of the runtime state of the supervisors, `VMRUN_RUN_DIR` or
+/// `/var/run/vmrun`
+pub fn run_dir() -> PathBuf {
+    std::env::var_os("VMRUN_RUN_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/var/run/vmrun"))
+}
+
+/// Quote the argument for POSIX shells, if necessary
+pub fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Format the time as an UTC timestamp in ISO 8601 with milliseconds, such as
+/// `2022-06-01T08:30:00.000Z`
+pub fn utc_timestamp(time: std::time::SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    /* the civil date of the days since 1970-01-01, by Howard Hinnant */
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
 
 pub fn vec_exists<T, F>(vec: &[T], cond: F) -> bool
 where
@@ -100,7 +148,7 @@ pub fn take_numeric<N: Num<FromStrRadixErr = std::num::ParseIntError>>(
     Ok((value, &input[index..]))
 }
 
-pub fn parse_mem_in_kb(input: &String) -> Result<usize, FormatError> {
+pub fn parse_mem_in_kb(input: &str) -> Result<usize, FormatError> {
     let (value, rest) = take_numeric::<usize>(true, input)?;
     let multipier: usize = (match rest {
         "K" | "KB" | "kb" | "Kb" => Ok(1),
@@ -0,0 +1,165 @@
+use crate::util::os::pci::PciDevice;
+use crate::util::os::{BhyveCapabilities, FileKind, Host, HostError, NmdmClaim};
+use crate::vm::PciSlot;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// An in-memory host for testing conditions and recoveries. Recoveries
+/// modify the state of the fake host the same way they modify a real host
+#[derive(Debug, Default)]
+pub struct FakeHost {
+    /// tap interfaces and if they are opened
+    pub taps: RefCell<Vec<(String, bool)>>,
+    pub pci_devices: RefCell<Vec<PciDevice>>,
+    pub klds: Vec<String>,
+    pub files: RefCell<HashMap<PathBuf, (FileKind, Vec<u8>)>>,
+    pub vms: RefCell<Vec<String>>,
+    /// nmdm pairs by the VMs that recorded them
+    pub nmdm_pairs: RefCell<HashMap<u32, String>>,
+    /// The VMs with a running supervisor
+    pub supervised: RefCell<Vec<String>>,
+    /// The slots recorded by VM
+    pub slots: RefCell<HashMap<String, BTreeMap<String, PciSlot>>>,
+    pub ncpu: Option<usize>,
+    pub capabilities: BhyveCapabilities,
+}
+
+impl FakeHost {
+    pub fn with_file(self, path: &str, kind: FileKind, content: Vec<u8>) -> FakeHost {
+        self.files
+            .borrow_mut()
+            .insert(PathBuf::from(path), (kind, content));
+        self
+    }
+}
+
+impl Host for FakeHost {
+    fn tap_ifaces(&self) -> Result<Vec<String>, HostError> {
+        Ok(self.taps.borrow().iter().map(|t| t.0.to_string()).collect())
+    }
+
+    fn is_tap_opened(&self, name: &str) -> Result<bool, HostError> {
+        Ok(self.taps.borrow().iter().any(|t| t.0 == name && t.1))
+    }
+
+    fn create_tap(&self, name: &str) -> Result<(), HostError> {
+        self.taps.borrow_mut().push((name.to_string(), false));
+        Ok(())
+    }
+
+    fn destroy_tap(&self, name: &str) -> Result<(), HostError> {
+        self.taps.borrow_mut().retain(|t| t.0 != name);
+        Ok(())
+    }
+
+    fn pci_devices(&self) -> Result<Vec<PciDevice>, HostError> {
+        Ok(self.pci_devices.borrow().clone())
+    }
+
+    fn set_pci_driver(&self, slot: &PciSlot, driver: Option<&str>) -> Result<(), HostError> {
+        let mut devices = self.pci_devices.borrow_mut();
+        let device = devices
+            .iter_mut()
+            .find(|device| device.slot == *slot)
+            .ok_or_else(|| HostError::Failed(format!("no device at {}", slot.as_bhyve_arg())))?;
+        device.device_name = format!("{}0", driver.unwrap_or("none"));
+        Ok(())
+    }
+
+    fn kld_loaded(&self, kmod: &str) -> Result<Option<bool>, HostError> {
+        Ok(Some(self.klds.iter().any(|k| k == kmod)))
+    }
+
+    fn file_kind(&self, path: &Path) -> Result<Option<FileKind>, HostError> {
+        Ok(self.files.borrow().get(path).map(|f| f.0))
+    }
+
+    fn read_file_at(&self, path: &Path, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let files = self.files.borrow();
+        let content = &files
+            .get(path)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?
+            .1;
+        let start = offset as usize;
+        content
+            .get(start..start + len)
+            .map(|bytes| bytes.to_vec())
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    }
+
+    fn file_size(&self, path: &Path) -> Result<Option<u64>, HostError> {
+        Ok(self.files.borrow().get(path).map(|f| f.1.len() as u64))
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<(), HostError> {
+        let mut files = self.files.borrow_mut();
+        let file = files
+            .get(from)
+            .cloned()
+            .ok_or_else(|| HostError::Failed(format!("{from:?} does not exist")))?;
+        files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), HostError> {
+        self.files
+            .borrow_mut()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| HostError::Failed(format!("{path:?} does not exist")))
+    }
+
+    fn vmm_exists(&self, name: &str) -> Result<bool, HostError> {
+        Ok(self.vms.borrow().iter().any(|vm| vm == name))
+    }
+
+    fn destroy_vm(&self, name: &str) -> Result<(), HostError> {
+        self.vms.borrow_mut().retain(|vm| vm != name);
+        Ok(())
+    }
+
+    fn supervisor_running(&self, name: &str) -> Result<bool, HostError> {
+        Ok(self.supervised.borrow().iter().any(|vm| vm == name))
+    }
+
+    fn nmdm_owner(&self, n: u32) -> Result<Option<String>, HostError> {
+        Ok(self.nmdm_pairs.borrow().get(&n).cloned())
+    }
+
+    fn claim_nmdm(&self, n: u32, vm: &str) -> Result<NmdmClaim, HostError> {
+        let mut pairs = self.nmdm_pairs.borrow_mut();
+        Ok(match pairs.get(&n) {
+            None => {
+                pairs.insert(n, vm.to_string());
+                NmdmClaim::Created
+            }
+            Some(owner) if owner == vm => NmdmClaim::Existing,
+            Some(_) => NmdmClaim::Taken,
+        })
+    }
+
+    fn release_nmdm(&self, n: u32) -> Result<(), HostError> {
+        self.nmdm_pairs.borrow_mut().remove(&n);
+        Ok(())
+    }
+
+    fn recorded_slots(&self, vm: &str) -> Result<BTreeMap<String, PciSlot>, HostError> {
+        Ok(self.slots.borrow().get(vm).cloned().unwrap_or_default())
+    }
+
+    fn record_slots(&self, vm: &str, slots: &BTreeMap<String, PciSlot>) -> Result<(), HostError> {
+        self.slots
+            .borrow_mut()
+            .insert(vm.to_string(), slots.clone());
+        Ok(())
+    }
+
+    fn ncpu(&self) -> Option<usize> {
+        self.ncpu
+    }
+
+    fn bhyve_capabilities(&self) -> Result<BhyveCapabilities, HostError> {
+        Ok(self.capabilities.clone())
+    }
+}
@@ -0,0 +1,294 @@
+use crate::control;
+use crate::spec::FormatError;
+use crate::util::os::iface::{get_tap_ifaces, is_tap_opened};
+use crate::util::os::pci::PciDevice;
+use crate::util::os::tools::ToolPaths;
+use crate::util::os::{BhyveCapabilities, FileKind, Host, HostError, NmdmClaim};
+use crate::util::run_dir;
+use crate::vm::PciSlot;
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The directory of the state kept across launches and host reboots,
+/// `VMRUN_DB_DIR` or `/var/db/vmrun`
+fn db_dir() -> PathBuf {
+    std::env::var_os("VMRUN_DB_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/var/db/vmrun"))
+}
+
+/// The record of the slots the devices of the VM `vm` were placed on
+fn slot_record(vm: &str) -> PathBuf {
+    db_dir().join(format!("{vm}.slots.json"))
+}
+
+/// The record of the VM using the nmdm pair `n` as its console
+fn nmdm_record(n: u32) -> PathBuf {
+    run_dir().join("nmdm").join(n.to_string())
+}
+
+#[link(name = "c")]
+extern "C" {
+    fn kldfind(file: *const std::os::raw::c_char) -> std::os::raw::c_int;
+}
+
+pub fn exists_kld(file: &str) -> Option<bool> {
+    unsafe {
+        let c_str = std::ffi::CString::new(file).ok()?;
+        match kldfind(c_str.as_ptr()) {
+            -1 => Some(false),
+            _ => Some(true),
+        }
+    }
+}
+
+/// The host vmrun is running on, backed by the FreeBSD base system utilities
+#[derive(Debug, Default)]
+pub struct FreeBsdHost {
+    /// Probing bhyve spawns a few processes, so it is only done once
+    capabilities: OnceCell<BhyveCapabilities>,
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), HostError> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| HostError::Failed(format!("cannot spawn {program}: {e}")))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(HostError::Failed(format!(
+            "`{program} {}` exited with {status}",
+            args.join(" ")
+        )))
+    }
+}
+
+impl Host for FreeBsdHost {
+    fn tap_ifaces(&self) -> Result<Vec<String>, HostError> {
+        get_tap_ifaces().map_err(|s| HostError::Failed(s.to_string()))
+    }
+
+    fn is_tap_opened(&self, name: &str) -> Result<bool, HostError> {
+        is_tap_opened(name).map_err(|s| HostError::Failed(s.to_string()))
+    }
+
+    fn create_tap(&self, name: &str) -> Result<(), HostError> {
+        run(
+            &ToolPaths::current().ifconfig,
+            &["tap", "create", "name", name],
+        )
+    }
+
+    fn destroy_tap(&self, name: &str) -> Result<(), HostError> {
+        run(&ToolPaths::current().ifconfig, &[name, "destroy"])
+    }
+
+    fn pci_devices(&self) -> Result<Vec<PciDevice>, HostError> {
+        Ok(PciDevice::from_pciconf_l())
+    }
+
+    fn pci_device(&self, slot: &PciSlot) -> Result<Option<PciDevice>, HostError> {
+        Ok(PciDevice::from_pciconf(slot))
+    }
+
+    fn set_pci_driver(&self, slot: &PciSlot, driver: Option<&str>) -> Result<(), HostError> {
+        let selector = format!("pci0:{}", slot.as_bhyve_arg());
+        let devctl = &ToolPaths::current().devctl;
+        match driver {
+            Some(driver) => run(devctl, &["set", "driver", "-f", &selector, driver]),
+            None => run(devctl, &["clear", "driver", "-f", &selector]),
+        }
+    }
+
+    fn kld_loaded(&self, kmod: &str) -> Result<Option<bool>, HostError> {
+        Ok(exists_kld(kmod))
+    }
+
+    fn file_kind(&self, path: &Path) -> Result<Option<FileKind>, HostError> {
+        Ok(std::fs::metadata(path).ok().map(|metadata| {
+            if metadata.is_file() {
+                FileKind::File
+            } else if metadata.is_dir() {
+                FileKind::Directory
+            } else {
+                FileKind::Other
+            }
+        }))
+    }
+
+    fn read_file_at(&self, path: &Path, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = std::fs::File::open(path)?;
+        let mut buf = vec![0u8; len];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn file_size(&self, path: &Path) -> Result<Option<u64>, HostError> {
+        Ok(std::fs::metadata(path).ok().map(|metadata| metadata.len()))
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<(), HostError> {
+        std::fs::copy(from, to)
+            .map(|_| ())
+            .map_err(|e| HostError::Failed(format!("{from:?} -> {to:?}: {e}")))
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), HostError> {
+        std::fs::remove_file(path).map_err(|e| HostError::Failed(format!("{path:?}: {e}")))
+    }
+
+    fn vmm_exists(&self, name: &str) -> Result<bool, HostError> {
+        Ok(Path::new(&format!("/dev/vmm/{name}")).exists())
+    }
+
+    fn destroy_vm(&self, name: &str) -> Result<(), HostError> {
+        run(
+            &ToolPaths::current().bhyvectl,
+            &["--destroy", &format!("--vm={name}")],
+        )
+    }
+
+    fn supervisor_running(&self, name: &str) -> Result<bool, HostError> {
+        Ok(std::os::unix::net::UnixStream::connect(control::socket_path(name)).is_ok())
+    }
+
+    fn nmdm_owner(&self, n: u32) -> Result<Option<String>, HostError> {
+        match std::fs::read_to_string(nmdm_record(n)) {
+            Ok(owner) => Ok(Some(owner.trim().to_string())),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(HostError::Failed(format!("{:?}: {error}", nmdm_record(n)))),
+        }
+    }
+
+    fn claim_nmdm(&self, n: u32, vm: &str) -> Result<NmdmClaim, HostError> {
+        use std::io::Write;
+        let path = nmdm_record(n);
+        let failed = |error: std::io::Error| HostError::Failed(format!("{path:?}: {error}"));
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(failed)?;
+        }
+        /* O_EXCL such that two supervisors never claim the same pair */
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut record) => {
+                writeln!(record, "{vm}").map_err(failed)?;
+                Ok(NmdmClaim::Created)
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {
+                match self.nmdm_owner(n)?.as_deref() == Some(vm) {
+                    true => Ok(NmdmClaim::Existing),
+                    false => Ok(NmdmClaim::Taken),
+                }
+            }
+            Err(error) => Err(failed(error)),
+        }
+    }
+
+    fn release_nmdm(&self, n: u32) -> Result<(), HostError> {
+        let path = nmdm_record(n);
+        std::fs::remove_file(&path).map_err(|e| HostError::Failed(format!("{path:?}: {e}")))
+    }
+
+    fn recorded_slots(&self, vm: &str) -> Result<BTreeMap<String, PciSlot>, HostError> {
+        let path = slot_record(vm);
+        let failed = |error: String| HostError::Failed(format!("{path:?}: {error}"));
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(BTreeMap::new())
+            }
+            Err(error) => return Err(failed(error.to_string())),
+        };
+        let slots: BTreeMap<String, String> =
+            serde_json::from_str(&content).map_err(|error| failed(error.to_string()))?;
+        slots
+            .into_iter()
+            .map(|(key, slot)| {
+                Ok((
+                    key,
+                    slot.parse()
+                        .map_err(|e: FormatError| failed(e.to_string()))?,
+                ))
+            })
+            .collect()
+    }
+
+    fn record_slots(&self, vm: &str, slots: &BTreeMap<String, PciSlot>) -> Result<(), HostError> {
+        let path = slot_record(vm);
+        let failed = |error: std::io::Error| HostError::Failed(format!("{path:?}: {error}"));
+        let slots: BTreeMap<&String, String> = slots
+            .iter()
+            .map(|(key, slot)| (key, slot.as_bhyve_arg()))
+            .collect();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(failed)?;
+        }
+        std::fs::write(&path, serde_json::to_string_pretty(&slots).unwrap()).map_err(failed)
+    }
+
+    fn ncpu(&self) -> Option<usize> {
+        std::thread::available_parallelism().ok().map(|n| n.get())
+    }
+
+    fn bhyve_capabilities(&self) -> Result<BhyveCapabilities, HostError> {
+        Ok(self.capabilities.get_or_init(probe_bhyve).clone())
+    }
+}
+
+/// Probe the capabilities of bhyve from its usage and device model listing.
+/// Anything that cannot be probed is left to be assumed by the conditions
+fn probe_bhyve() -> BhyveCapabilities {
+    let tools = ToolPaths::current();
+    let output = |program: &str, args: &[&str]| {
+        Command::new(program)
+            .args(args)
+            .output()
+            .ok()
+            .map(|output| {
+                let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+                text.push_str(&String::from_utf8_lossy(&output.stderr));
+                (output.status.success(), text)
+            })
+    };
+
+    /* bhyve prints the usage to stderr with each option as `-x: ...` */
+    let usage = output(&tools.bhyve, &["-h"])
+        .map(|(_, text)| text)
+        .unwrap_or_default();
+
+    let device_models = match output(&tools.bhyve, &["-s", "help"]) {
+        Some((true, text)) if !text.trim().is_empty() => Some(
+            text.lines()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect(),
+        ),
+        _ => None,
+    };
+
+    let max_vcpus = output(&tools.sysctl, &["-n", "hw.vmm.maxcpu"])
+        .filter(|(success, _)| *success)
+        .and_then(|(_, text)| text.trim().parse::<usize>().ok());
+
+    /* the TCP backend of the com ports comes with FreeBSD 14.0 */
+    let tcp_console = output(&tools.sysctl, &["-n", "kern.osreldate"])
+        .filter(|(success, _)| *success)
+        .and_then(|(_, text)| text.trim().parse::<u32>().ok())
+        .map(|osreldate| osreldate >= 1400000);
+
+    BhyveCapabilities {
+        config_options: usage.contains("-o:"),
+        config_file: usage.contains("-k:"),
+        device_models,
+        max_vcpus,
+        tcp_console,
+    }
+}
@@ -1,7 +1,8 @@
+use crate::util::os::tools::ToolPaths;
 use command_macros::cmd;
 
 pub fn get_tap_ifaces() -> Result<Vec<String>, &'static str> {
-    let output = cmd!(ifconfig ("-g") tap)
+    let output = cmd!((ToolPaths::current().ifconfig) ("-g") tap)
         .output()
         .map_err(|_| "cannot spawn ifconfig")?;
     let stdout = std::str::from_utf8(&output.stdout)
@@ -13,7 +14,7 @@ pub fn get_tap_ifaces() -> Result<Vec<String>, &'static str> {
 // flews, for example if the interface is not a tap interface at all this will
 // also report true
 pub fn is_tap_opened(name: &str) -> Result<bool, &'static str> {
-    let output = cmd!(ifconfig(name))
+    let output = cmd!((ToolPaths::current().ifconfig)(name))
         .output()
         .map_err(|_| "cannot spawn ifconfig $name")?;
     let stdout =
@@ -1,17 +1,168 @@
+// In plan mode, the facilities to talk to a real host are never used
+#![cfg_attr(
+    not(all(feature = "freebsd-host", target_os = "freebsd")),
+    allow(dead_code)
+)]
+
+#[cfg(all(feature = "freebsd-host", target_os = "freebsd"))]
+pub mod freebsd;
+#[cfg(all(feature = "freebsd-host", target_os = "freebsd"))]
 pub mod iface;
 pub mod pci;
+pub mod plan;
+pub mod tools;
+
+#[cfg(test)]
+pub mod fake;
+
+use crate::util::os::pci::PciDevice;
+use crate::vm::PciSlot;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use thiserror::Error;
+
+/// If vmrun is built to probe and run VMs on a bhyve host. Otherwise vmrun runs
+/// in plan mode, where configurations can be built and inspected, but nothing
+/// on the host can be checked or launched
+pub const BHYVE_HOST: bool = cfg!(all(feature = "freebsd-host", target_os = "freebsd"));
+
+#[cfg(all(feature = "freebsd-host", target_os = "freebsd"))]
+pub use freebsd::FreeBsdHost as NativeHost;
+#[cfg(not(all(feature = "freebsd-host", target_os = "freebsd")))]
+pub use plan::PlanHost as NativeHost;
+
+#[derive(Error, Debug)]
+pub enum HostError {
+    #[error("{0}")]
+    Failed(String),
+    #[error("not on a bhyve host")]
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    /// Anything else, for example device nodes and sockets
+    Other,
+}
 
-#[link(name = "c")]
-extern "C" {
-    fn kldfind(file: *const std::os::raw::c_char) -> std::os::raw::c_int;
+/// What the bhyve installed on the host supports
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BhyveCapabilities {
+    /// bhyve accepts `-o key=value`, otherwise devices are rendered with the
+    /// legacy `-s` syntax
+    pub config_options: bool,
+    /// bhyve accepts `-k` configuration files
+    pub config_file: bool,
+    /// The device models listed by `bhyve -s help`, `None` if this bhyve cannot
+    /// list them
+    pub device_models: Option<Vec<String>>,
+    /// The maximum number of vCPUs of a VM, `None` if unknown
+    pub max_vcpus: Option<usize>,
+    /// bhyve accepts `tcp=ADDRESS:PORT` as the backend of a com port, `None`
+    /// if unknown
+    pub tcp_console: Option<bool>,
 }
 
-pub fn exists_kld(file: &str) -> Option<bool> {
-    unsafe {
-        let c_str = std::ffi::CString::new(file).ok()?;
-        match kldfind(c_str.as_ptr()) {
-            -1 => Some(false),
-            _ => Some(true),
-        }
+impl BhyveCapabilities {
+    /// If the device model is supported, assuming it is if the models are
+    /// unknown
+    pub fn supports_model(&self, model: &str) -> bool {
+        self.device_models
+            .as_ref()
+            .is_none_or(|models| models.iter().any(|m| m == model))
+    }
+}
+
+/// What recording a nmdm pair for a VM found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NmdmClaim {
+    /// The pair was free and is now recorded for the VM, the caller releases
+    /// it
+    Created,
+    /// The VM recorded the pair before, the record is left to whoever
+    /// created it
+    Existing,
+    /// Another VM recorded the pair
+    Taken,
+}
+
+/// The host facilities vmrun inspect and modify to check and recover the
+/// conditions of a VM. All interactions with the host operating system from
+/// `Condition` and `Recovery` go through this trait, such that they can be
+/// exercised against a fake host
+pub trait Host {
+    /// Names of all tap interfaces
+    fn tap_ifaces(&self) -> Result<Vec<String>, HostError>;
+
+    /// If the tap interface is opened by a process
+    fn is_tap_opened(&self, name: &str) -> Result<bool, HostError>;
+
+    fn create_tap(&self, name: &str) -> Result<(), HostError>;
+
+    fn destroy_tap(&self, name: &str) -> Result<(), HostError>;
+
+    fn pci_devices(&self) -> Result<Vec<PciDevice>, HostError>;
+
+    fn pci_device(&self, slot: &PciSlot) -> Result<Option<PciDevice>, HostError> {
+        Ok(self
+            .pci_devices()?
+            .into_iter()
+            .find(|device| device.slot == *slot))
     }
+
+    /// Force the PCI device to attach to `driver`, or let the kernel probe
+    /// for a driver if `driver` is `None`
+    fn set_pci_driver(&self, slot: &PciSlot, driver: Option<&str>) -> Result<(), HostError>;
+
+    /// If the kernel module is loaded, `None` if the module name is invalid
+    fn kld_loaded(&self, kmod: &str) -> Result<Option<bool>, HostError>;
+
+    /// The kind of the filesystem object at `path`, `None` if it does not
+    /// exist or not accessible
+    fn file_kind(&self, path: &Path) -> Result<Option<FileKind>, HostError>;
+
+    /// Read `len` bytes at `offset` of the file
+    fn read_file_at(&self, path: &Path, offset: u64, len: usize) -> std::io::Result<Vec<u8>>;
+
+    /// The size of the file at `path` in bytes, `None` if it does not exist
+    /// or not accessible
+    fn file_size(&self, path: &Path) -> Result<Option<u64>, HostError>;
+
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<(), HostError>;
+
+    fn remove_file(&self, path: &Path) -> Result<(), HostError>;
+
+    /// If a VM instance of `name` exists in the kernel
+    fn vmm_exists(&self, name: &str) -> Result<bool, HostError>;
+
+    /// If a supervisor of the VM `name` is running, that is, its control
+    /// socket answers
+    fn supervisor_running(&self, name: &str) -> Result<bool, HostError>;
+
+    fn destroy_vm(&self, name: &str) -> Result<(), HostError>;
+
+    /// The VM that recorded the nmdm pair `n` as its console, `None` if the
+    /// pair is free. The nmdm devices are never looked up, since devfs
+    /// creates a pair on any lookup of its name
+    fn nmdm_owner(&self, n: u32) -> Result<Option<String>, HostError>;
+
+    /// Record the nmdm pair `n` as a console of the VM `vm`
+    fn claim_nmdm(&self, n: u32, vm: &str) -> Result<NmdmClaim, HostError>;
+
+    fn release_nmdm(&self, n: u32) -> Result<(), HostError>;
+
+    /// The PCI slots of the devices of the VM `vm` by their identities, as
+    /// recorded by the last launch
+    fn recorded_slots(&self, vm: &str) -> Result<BTreeMap<String, PciSlot>, HostError>;
+
+    fn record_slots(&self, vm: &str, slots: &BTreeMap<String, PciSlot>) -> Result<(), HostError>;
+
+    /// Number of CPUs available in the host
+    fn ncpu(&self) -> Option<usize>;
+
+    /// Capabilities of the bhyve vmrun launches
+    fn bhyve_capabilities(&self) -> Result<BhyveCapabilities, HostError>;
 }
@@ -1,7 +1,10 @@
+#[cfg(all(feature = "freebsd-host", target_os = "freebsd"))]
+use crate::util::os::tools::ToolPaths;
 use crate::vm::PciSlot;
+#[cfg(all(feature = "freebsd-host", target_os = "freebsd"))]
 use command_macros::cmd;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PciDevice {
     pub device_name: String,
     pub domain: u8,
@@ -21,6 +24,7 @@ enum PassthruCheck {
     WrongHdr { hdr: u8, rev: u8, class: u32 }
 }
 */
+#[cfg(all(feature = "freebsd-host", target_os = "freebsd"))]
 impl PciDevice {
     /*
         fn check_passthru(&self) -> PassthruCheck {
@@ -47,27 +51,8 @@ impl PciDevice {
             }
         }
     */
-    pub fn force_passthru(&mut self) {
-        let domain = self.domain;
-        let bus = self.slot.bus;
-        let slot = self.slot.slot;
-        let func = self.slot.func;
-        let selector = format!("pci{domain}:{bus}:{slot}:{func}");
-
-        if !self.device_name.starts_with("none") {
-            cmd!(devctl detach (selector)).status().unwrap();
-        }
-
-        cmd!(devctl set driver (selector) ppt).status().unwrap();
-
-        let refreshed = cmd!(pciconf("-l")(selector)).output().unwrap();
-        let dev = PciDevice::from_pciconf_l_line(std::str::from_utf8(&refreshed.stdout).unwrap());
-
-        *self = dev;
-    }
-
     pub fn from_pciconf(slot: &PciSlot) -> Option<PciDevice> {
-        let out = cmd!(pciconf("-l")(format!(
+        let out = cmd!((ToolPaths::current().pciconf)("-l")(format!(
             "pci0:{}:{}:{}",
             slot.bus, slot.slot, slot.func
         )))
@@ -83,14 +68,16 @@ impl PciDevice {
 
     #[allow(dead_code)]
     pub fn from_pciconf_l() -> Vec<PciDevice> {
-        let output = std::process::Command::new("pciconf")
+        let output = std::process::Command::new(&ToolPaths::current().pciconf)
             .arg("-l")
             .output()
             .unwrap();
         let stdout = std::str::from_utf8(&output.stdout).unwrap();
         stdout.lines().map(PciDevice::from_pciconf_l_line).collect()
     }
+}
 
+impl PciDevice {
     pub fn from_pciconf_l_line(line: &str) -> PciDevice {
         const ERR: &str = "Invalid pciconf -l output";
 
@@ -0,0 +1,102 @@
+use crate::util::os::pci::PciDevice;
+use crate::util::os::{BhyveCapabilities, FileKind, Host, HostError, NmdmClaim};
+use crate::vm::PciSlot;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The host when vmrun is not running on a bhyve host. Every query and
+/// modification is unsupported, such that host dependent conditions are
+/// reported as skipped
+#[derive(Debug, Default)]
+pub struct PlanHost {}
+
+impl Host for PlanHost {
+    fn tap_ifaces(&self) -> Result<Vec<String>, HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn is_tap_opened(&self, _name: &str) -> Result<bool, HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn create_tap(&self, _name: &str) -> Result<(), HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn destroy_tap(&self, _name: &str) -> Result<(), HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn pci_devices(&self) -> Result<Vec<PciDevice>, HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn set_pci_driver(&self, _slot: &PciSlot, _driver: Option<&str>) -> Result<(), HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn kld_loaded(&self, _kmod: &str) -> Result<Option<bool>, HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn file_kind(&self, _path: &Path) -> Result<Option<FileKind>, HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn read_file_at(&self, _path: &Path, _offset: u64, _len: usize) -> std::io::Result<Vec<u8>> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    fn file_size(&self, _path: &Path) -> Result<Option<u64>, HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn copy_file(&self, _from: &Path, _to: &Path) -> Result<(), HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn remove_file(&self, _path: &Path) -> Result<(), HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn vmm_exists(&self, _name: &str) -> Result<bool, HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn destroy_vm(&self, _name: &str) -> Result<(), HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn supervisor_running(&self, _name: &str) -> Result<bool, HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    /* no VM runs on a host that is not a bhyve host */
+    fn nmdm_owner(&self, _n: u32) -> Result<Option<String>, HostError> {
+        Ok(None)
+    }
+
+    fn claim_nmdm(&self, _n: u32, _vm: &str) -> Result<NmdmClaim, HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn release_nmdm(&self, _n: u32) -> Result<(), HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn recorded_slots(&self, _vm: &str) -> Result<BTreeMap<String, PciSlot>, HostError> {
+        Ok(BTreeMap::new())
+    }
+
+    fn record_slots(&self, _vm: &str, _slots: &BTreeMap<String, PciSlot>) -> Result<(), HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    fn ncpu(&self) -> Option<usize> {
+        None
+    }
+
+    fn bhyve_capabilities(&self) -> Result<BhyveCapabilities, HostError> {
+        Err(HostError::Unsupported)
+    }
+}
@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Paths of the host utilities vmrun runs. The profile is resolved once at
+/// startup, from the defaults, a host profile file, the `VMRUN_<TOOL>`
+/// environment variables and `--tool` in order, where the later wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolPaths {
+    pub bhyve: String,
+    pub bhyvectl: String,
+    pub bhyveload: String,
+    pub ifconfig: String,
+    pub pciconf: String,
+    pub devctl: String,
+    pub sysctl: String,
+    pub grub_bhyve: String,
+}
+
+impl Default for ToolPaths {
+    fn default() -> ToolPaths {
+        ToolPaths {
+            bhyve: std::option_env!("BHYVE_EXEC")
+                .unwrap_or("bhyve")
+                .to_string(),
+            bhyvectl: "bhyvectl".to_string(),
+            bhyveload: "bhyveload".to_string(),
+            ifconfig: "ifconfig".to_string(),
+            pciconf: "pciconf".to_string(),
+            devctl: "devctl".to_string(),
+            sysctl: "sysctl".to_string(),
+            grub_bhyve: "grub-bhyve".to_string(),
+        }
+    }
+}
+
+static TOOLS: OnceLock<ToolPaths> = OnceLock::new();
+
+impl ToolPaths {
+    const NAMES: [&'static str; 8] = [
+        "bhyve",
+        "bhyvectl",
+        "bhyveload",
+        "ifconfig",
+        "pciconf",
+        "devctl",
+        "sysctl",
+        "grub_bhyve",
+    ];
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut String> {
+        match name {
+            "bhyve" => Some(&mut self.bhyve),
+            "bhyvectl" => Some(&mut self.bhyvectl),
+            "bhyveload" => Some(&mut self.bhyveload),
+            "ifconfig" => Some(&mut self.ifconfig),
+            "pciconf" => Some(&mut self.pciconf),
+            "devctl" => Some(&mut self.devctl),
+            "sysctl" => Some(&mut self.sysctl),
+            "grub_bhyve" => Some(&mut self.grub_bhyve),
+            _ => None,
+        }
+    }
+
+    /// The help of `--tool`, listing the tools by their names
+    pub fn override_help() -> &'static str {
+        static HELP: OnceLock<String> = OnceLock::new();
+        HELP.get_or_init(|| {
+            let (last, names) = ToolPaths::NAMES.split_last().unwrap();
+            format!(
+                "Override the path of a host utility, one of {} and {last}. Takes \
+                 precedence over the host profile and `VMRUN_<TOOL>` environment variables",
+                names.join(", ")
+            )
+        })
+    }
+
+    /// Override the path of a tool with `NAME=PATH`
+    pub fn set(&mut self, assignment: &str) -> Result<(), String> {
+        let (name, path) = assignment
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=PATH, got `{assignment}`"))?;
+        let tool = self.get_mut(name).ok_or_else(|| {
+            format!(
+                "unknown tool `{name}`, expected one of {}",
+                ToolPaths::NAMES.join(", ")
+            )
+        })?;
+        *tool = path.to_string();
+        Ok(())
+    }
+
+    /// Resolve the tool paths from the host profile, the environment and the
+    /// overrides from the command line. The host profile is read from
+    /// `VMRUN_HOST_PROFILE` if `profile` is not given
+    pub fn resolve(profile: Option<&Path>, overrides: &[String]) -> Result<ToolPaths, String> {
+        let env_profile = std::env::var_os("VMRUN_HOST_PROFILE").map(std::path::PathBuf::from);
+        let mut tools = match profile.or(env_profile.as_deref()) {
+            None => ToolPaths::default(),
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| format!("cannot read host profile {path:?}: {e}"))?;
+                serde_json::from_str(&content)
+                    .map_err(|e| format!("invalid host profile {path:?}: {e}"))?
+            }
+        };
+
+        for name in ToolPaths::NAMES {
+            if let Ok(path) = std::env::var(format!("VMRUN_{}", name.to_uppercase())) {
+                *tools.get_mut(name).unwrap() = path;
+            }
+        }
+
+        for assignment in overrides {
+            tools.set(assignment)?;
+        }
+
+        Ok(tools)
+    }
+
+    /// Make the tool paths effective for the rest of the process, this can
+    /// only be done once
+    pub fn install(self) {
+        let _ = TOOLS.set(self);
+    }
+
+    /// The tool paths in effect, the defaults if none were installed
+    pub fn current() -> &'static ToolPaths {
+        TOOLS.get_or_init(ToolPaths::default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_overrides() {
+        let mut tools = ToolPaths::default();
+        tools.set("bhyvectl=/opt/bin/bhyvectl").unwrap();
+        assert_eq!(tools.bhyvectl, "/opt/bin/bhyvectl");
+        assert!(tools.set("bhyvectl").is_err());
+        assert!(tools.set("qemu=/usr/bin/qemu").is_err());
+
+        let tools: ToolPaths = serde_json::from_str(r#"{"devctl": "/sbin/devctl"}"#).unwrap();
+        assert_eq!(tools.devctl, "/sbin/devctl");
+        assert_eq!(tools.pciconf, "pciconf");
+
+        /* every tool of the profile can be overridden by its name */
+        let profile = serde_json::to_value(ToolPaths::default()).unwrap();
+        let fields: Vec<&str> = profile
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .collect();
+        let mut names = ToolPaths::NAMES.to_vec();
+        names.sort();
+        assert_eq!(fields, names);
+        assert!(ToolPaths::override_help().contains("bhyveload, ifconfig"));
+    }
+}
@@ -0,0 +1,370 @@
+use crate::util::assertion::Assertion;
+use crate::util::os::tools::ToolPaths;
+use crate::vm::Resource;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// How the supervisor launches a VM and waits for it to exit
+pub trait Backend {
+    /// The program the VM is launched with, for display purpose
+    fn program(&self) -> &str;
+
+    /// Launch a VM with the bhyve arguments. With `capture_console`, the
+    /// output of bhyve, the console of a stdio com port, is kept for the
+    /// supervisor to take with [`Instance::take_console`]
+    fn spawn(
+        &mut self,
+        args: &[String],
+        capture_console: bool,
+    ) -> std::io::Result<Box<dyn Instance>>;
+
+    /// Run a program preparing the VM for launch, such as a loader, to
+    /// completion. `None` if the program is terminated by a signal
+    fn run(&mut self, program: &str, args: &[String]) -> std::io::Result<Option<i32>>;
+
+    /// Release a resource left by the VM after it exited
+    fn release(&mut self, resource: &Resource) -> Result<(), Assertion> {
+        resource.release()
+    }
+
+    /// If the VM is simulated, in which case nothing on the host should be
+    /// changed on behalf of the VM
+    fn is_simulated(&self) -> bool {
+        false
+    }
+}
+
+/// A launched VM
+pub trait Instance {
+    fn id(&self) -> u32;
+
+    /// Wait for the VM to exit, `None` if the VM is terminated by a signal
+    fn wait(&mut self) -> std::io::Result<Option<i32>>;
+
+    /// The exit of the VM as [`Instance::wait`] if it exited, without
+    /// blocking
+    fn try_wait(&mut self) -> std::io::Result<Option<Option<i32>>>;
+
+    /// Make the VM exit now, as if the guest reset (`reset`) or powered off
+    fn force_exit(&mut self, reset: bool) -> std::io::Result<()>;
+
+    /// The captured console output of the VM, if any
+    fn take_console(&mut self) -> Option<Box<dyn std::io::Read + Send>> {
+        None
+    }
+}
+
+/// Run the VM as a bhyve process
+#[derive(Debug)]
+pub struct BhyveProcess {
+    pub program: String,
+}
+
+impl Default for BhyveProcess {
+    fn default() -> BhyveProcess {
+        BhyveProcess {
+            program: ToolPaths::current().bhyve.to_string(),
+        }
+    }
+}
+
+impl Backend for BhyveProcess {
+    fn program(&self) -> &str {
+        &self.program
+    }
+
+    fn spawn(
+        &mut self,
+        args: &[String],
+        capture_console: bool,
+    ) -> std::io::Result<Box<dyn Instance>> {
+        let mut command = std::process::Command::new(&self.program);
+        command.args(args);
+        if capture_console {
+            command.stdout(std::process::Stdio::piped());
+        }
+        Ok(Box::new(BhyveInstance {
+            child: command.spawn()?,
+            name: args.last().cloned().unwrap_or_default(),
+        }))
+    }
+
+    fn run(&mut self, program: &str, args: &[String]) -> std::io::Result<Option<i32>> {
+        std::process::Command::new(program)
+            .args(args)
+            .status()
+            .map(|status| status.code())
+    }
+}
+
+/// A bhyve process running the VM `name`
+struct BhyveInstance {
+    child: std::process::Child,
+    name: String,
+}
+
+impl Instance for BhyveInstance {
+    fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    fn wait(&mut self) -> std::io::Result<Option<i32>> {
+        self.child.wait().map(|status| status.code())
+    }
+
+    fn try_wait(&mut self) -> std::io::Result<Option<Option<i32>>> {
+        self.child
+            .try_wait()
+            .map(|status| status.map(|status| status.code()))
+    }
+
+    fn force_exit(&mut self, reset: bool) -> std::io::Result<()> {
+        let how = if reset {
+            "--force-reset"
+        } else {
+            "--force-poweroff"
+        };
+        let status = std::process::Command::new(&ToolPaths::current().bhyvectl)
+            .args([how, &format!("--vm={}", self.name)])
+            .status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(std::io::Error::other(format!(
+                "bhyvectl {how} exited with {status}"
+            )))
+        }
+    }
+
+    fn take_console(&mut self) -> Option<Box<dyn std::io::Read + Send>> {
+        self.child
+            .stdout
+            .take()
+            .map(|stdout| Box::new(stdout) as Box<dyn std::io::Read + Send>)
+    }
+}
+
+/// A scripted exit of a simulated VM
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimulatedExit {
+    pub code: i32,
+    /// How long the VM runs before it exits
+    pub delay: Duration,
+}
+
+/// A sequence of exits, in the form of `CODE[@SECONDS],...`, for example
+/// `0,0@1.5,1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationScript(pub Vec<SimulatedExit>);
+
+impl FromStr for SimulationScript {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SimulationScript, String> {
+        let mut exits = vec![];
+        for part in s.split(',') {
+            let (code, delay) = match part.split_once('@') {
+                Some((code, delay)) => (code, Some(delay)),
+                None => (part, None),
+            };
+            let code = code
+                .trim()
+                .parse::<i32>()
+                .map_err(|_| format!("invalid exit code `{code}`"))?;
+            let delay = match delay {
+                None => Duration::ZERO,
+                Some(delay) => delay
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .ok_or_else(|| format!("invalid delay `{delay}`"))?,
+            };
+            exits.push(SimulatedExit { code, delay });
+        }
+        Ok(SimulationScript(exits))
+    }
+}
+
+/// A backend that does not launch anything, but validates the arguments and
+/// exits as scripted, such that the behaviour of the supervisor (reboots,
+/// target switching and cleanup) can be observed without a hypervisor
+#[derive(Debug)]
+pub struct Simulator {
+    script: VecDeque<SimulatedExit>,
+    launches: u32,
+}
+
+impl Simulator {
+    pub fn new(script: SimulationScript) -> Simulator {
+        Simulator {
+            script: script.0.into(),
+            launches: 0,
+        }
+    }
+}
+
+/// Sanity check of the bhyve arguments, the last argument is the name of the
+/// VM and none of the arguments is empty
+fn validate_args(args: &[String]) -> Result<(), String> {
+    let (name, options) = args.split_last().ok_or("empty argument list")?;
+    if name.is_empty() || name.starts_with('-') {
+        return Err(format!(
+            "expected vm name as the last argument, got `{name}`"
+        ));
+    }
+    if options.iter().any(|arg| arg.is_empty()) {
+        return Err("empty argument".to_string());
+    }
+    Ok(())
+}
+
+impl Backend for Simulator {
+    fn program(&self) -> &str {
+        "bhyve"
+    }
+
+    fn spawn(
+        &mut self,
+        args: &[String],
+        _capture_console: bool,
+    ) -> std::io::Result<Box<dyn Instance>> {
+        validate_args(args).map_err(|reason| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid bhyve arguments: {reason}"),
+            )
+        })?;
+
+        let exit = self.script.pop_front().ok_or_else(|| {
+            std::io::Error::other("simulation script exhausted, no more exit to simulate")
+        })?;
+
+        self.launches += 1;
+        eprintln!(
+            "simulate: [{}] {} {}",
+            self.launches,
+            self.program(),
+            args.join(" ")
+        );
+
+        Ok(Box::new(SimulatedInstance {
+            id: self.launches,
+            exit,
+            started: Instant::now(),
+        }))
+    }
+
+    fn run(&mut self, program: &str, args: &[String]) -> std::io::Result<Option<i32>> {
+        eprintln!("simulate: {program} {}", args.join(" "));
+        Ok(Some(0))
+    }
+
+    fn release(&mut self, resource: &Resource) -> Result<(), Assertion> {
+        eprintln!("simulate: release {}", resource.to_string());
+        Ok(())
+    }
+
+    fn is_simulated(&self) -> bool {
+        true
+    }
+}
+
+struct SimulatedInstance {
+    id: u32,
+    exit: SimulatedExit,
+    started: Instant,
+}
+
+impl SimulatedInstance {
+    fn exited(&self) -> Option<i32> {
+        eprintln!(
+            "simulate: [{}] exited with {} after {:?}",
+            self.id, self.exit.code, self.exit.delay
+        );
+        Some(self.exit.code)
+    }
+}
+
+impl Instance for SimulatedInstance {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn wait(&mut self) -> std::io::Result<Option<i32>> {
+        std::thread::sleep(self.exit.delay.saturating_sub(self.started.elapsed()));
+        Ok(self.exited())
+    }
+
+    fn try_wait(&mut self) -> std::io::Result<Option<Option<i32>>> {
+        if self.started.elapsed() < self.exit.delay {
+            return Ok(None);
+        }
+        Ok(Some(self.exited()))
+    }
+
+    fn force_exit(&mut self, reset: bool) -> std::io::Result<()> {
+        eprintln!(
+            "simulate: [{}] force {}",
+            self.id,
+            if reset { "reset" } else { "poweroff" }
+        );
+        /* bhyve exits with 0 on reset and 1 on power off */
+        self.exit = SimulatedExit {
+            code: if reset { 0 } else { 1 },
+            delay: self.started.elapsed(),
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulation_script() {
+        assert_eq!(
+            "0,0@1.5,1".parse::<SimulationScript>(),
+            Ok(SimulationScript(vec![
+                SimulatedExit {
+                    code: 0,
+                    delay: Duration::ZERO
+                },
+                SimulatedExit {
+                    code: 0,
+                    delay: Duration::from_millis(1500)
+                },
+                SimulatedExit {
+                    code: 1,
+                    delay: Duration::ZERO
+                },
+            ]))
+        );
+        assert!("0,x".parse::<SimulationScript>().is_err());
+        assert!("0@-1".parse::<SimulationScript>().is_err());
+    }
+
+    #[test]
+    fn simulator_follows_script() {
+        let mut simulator = Simulator::new("0,2".parse().unwrap());
+        let args = vec!["-c".to_string(), "1".to_string(), "vm".to_string()];
+
+        let mut instance = simulator.spawn(&args, false).unwrap();
+        assert_eq!(instance.wait().unwrap(), Some(0));
+        let mut instance = simulator.spawn(&args, false).unwrap();
+        assert_eq!(instance.wait().unwrap(), Some(2));
+        assert!(simulator.spawn(&args, false).is_err());
+
+        /* a forced exit ends the scripted delay */
+        let mut simulator = Simulator::new("2@60".parse().unwrap());
+        let mut instance = simulator.spawn(&args, false).unwrap();
+        assert_eq!(instance.try_wait().unwrap(), None);
+        instance.force_exit(false).unwrap();
+        assert_eq!(instance.try_wait().unwrap(), Some(Some(1)));
+        assert!(Simulator::new("0".parse().unwrap())
+            .spawn(&["-c".to_string()], false)
+            .is_err());
+    }
+}
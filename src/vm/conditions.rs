@@ -1,16 +1,16 @@
-use crate::util::assertion::{Assertion, Recovery};
-use crate::util::os::iface::{get_tap_ifaces, is_tap_opened};
-use crate::util::os::pci::PciDevice;
+use crate::util::assertion::Assertion;
+use crate::util::os::{FileKind, Host, HostError};
+use crate::vm::recovery::Recovery;
 use crate::vm::{NetBackend, PciSlot};
-use command_macros::cmd;
 use std::path::PathBuf;
 
 /// A abstract interface to define rules that assert if a system is capable
 /// to run the given VM configuration. Given a VM configuration, we can generate
 /// a number of `Condition` base on the configuration itself, regardless of the
 /// capability and architecture of the host generating it, a list of conditions
-/// can then run on a real enviornment by applying check(). These checks produce
-/// a collection of `Assertion` that determine if the given host can launch the VM
+/// can then run on a real enviornment by applying check() with the `Host`. These
+/// checks produce a collection of `Assertion` that determine if the given host
+/// can launch the VM
 ///
 /// Notice that if there are incorrectness in the configuration itself, a condition
 /// that always fail can be used such that the validation always failed (which is
@@ -20,7 +20,7 @@ use std::path::PathBuf;
 pub trait Condition: std::fmt::Debug {
     /// Check if this condition is satisfied, and provide failure reason and
     /// recoverability information
-    fn check(&self) -> Result<(), Assertion>;
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion>;
 
     /// The name of this condition,
     fn name(&self) -> String;
@@ -29,16 +29,29 @@ pub trait Condition: std::fmt::Debug {
         Err(Assertion::Fatal(self.name(), why))
     }
 
-    fn recoverable(&self, why: String, how: Box<Recovery>) -> Result<(), Assertion> {
+    fn recoverable(&self, why: String, how: Recovery) -> Result<(), Assertion> {
         Err(Assertion::Recoverable(self.name(), why, how))
     }
+
+    fn warning(&self, why: String) -> Result<(), Assertion> {
+        Err(Assertion::Warning(self.name(), why))
+    }
+
+    /// The assertion when the host failed to answer the query of the condition.
+    /// When not running on a bhyve host, the condition is reported as skipped
+    fn host_error(&self, error: HostError) -> Assertion {
+        match error {
+            HostError::Unsupported => Assertion::Warning(self.name(), format!("skipped: {error}")),
+            HostError::Failed(_) => Assertion::Fatal(self.name(), error.to_string()),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct NoCond {}
 
 impl Condition for NoCond {
-    fn check(&self) -> Result<(), Assertion> {
+    fn check(&self, _host: &dyn Host) -> Result<(), Assertion> {
         Ok(())
     }
 
@@ -69,7 +82,7 @@ impl Condition for GenericFatalCondition {
         self.name.to_string()
     }
 
-    fn check(&self) -> Result<(), Assertion> {
+    fn check(&self, _host: &dyn Host) -> Result<(), Assertion> {
         Err(Assertion::Fatal(
             self.name.to_string(),
             self.message.to_string(),
@@ -96,7 +109,7 @@ impl Condition for FatalIoError {
         "std::io::error".to_string()
     }
 
-    fn check(&self) -> Result<(), Assertion> {
+    fn check(&self, _host: &dyn Host) -> Result<(), Assertion> {
         Err(Assertion::Fatal(self.name(), format!("{:#?}", self.inner)))
     }
 }
@@ -108,10 +121,10 @@ pub struct NestedConditions {
 }
 
 impl Condition for NestedConditions {
-    fn check(&self) -> Result<(), Assertion> {
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
         let mut v = vec![];
         for cond in self.conditions.iter() {
-            match cond.check() {
+            match cond.check(host) {
                 Ok(()) => (),
                 Err(condition) => v.push((cond.name(), condition)),
             }
@@ -143,30 +156,36 @@ pub enum FsEntity {
 }
 
 impl FsEntity {
-    pub fn exists(&self) -> std::result::Result<(), String> {
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            FsEntity::File(path)
+            | FsEntity::Directory(path)
+            | FsEntity::Node(path)
+            | FsEntity::FsItem(path) => path,
+        }
+    }
+
+    /// Check if the entity exists, assuming the host is able to answer
+    pub fn exists(&self, host: &dyn Host) -> std::result::Result<(), String> {
         macro_rules! iml {
-            ($path:expr, $func:ident, $msg:expr) => {{
-                let path = std::path::Path::new($path);
-                if path.exists() {
-                    if path.$func() {
-                        Ok(())
-                    } else {
-                        Err(format!("Entity {path:?} exists but is not a {}", $msg))
-                    }
-                } else {
-                    Err(format!(
+            ($path:expr, $kind:pat, $msg:expr) => {{
+                let path = $path;
+                match host.file_kind(path).ok().flatten() {
+                    Some($kind) => Ok(()),
+                    Some(_) => Err(format!("Entity {path:?} exists but is not a {}", $msg)),
+                    None => Err(format!(
                         "Entity at {path:?} does not exists or do not have permission to access"
-                    ))
+                    )),
                 }
             }};
         }
 
         match self {
-            FsEntity::File(path) => iml!(path, is_file, "regular file"),
-            FsEntity::Directory(path) => iml!(path, is_dir, "directory"),
-            FsEntity::Node(path) => iml!(path, is_file, "regular file"),
+            FsEntity::File(path) => iml!(path, FileKind::File, "regular file"),
+            FsEntity::Directory(path) => iml!(path, FileKind::Directory, "directory"),
+            FsEntity::Node(path) => iml!(path, FileKind::Other, "device node"),
             FsEntity::FsItem(path) => {
-                if std::path::Path::new(path).exists() {
+                if host.file_kind(path).ok().flatten().is_some() {
                     Ok(())
                 } else {
                     Err("Entity does not exists or do not have permission to access".to_string())
@@ -188,9 +207,11 @@ impl Condition for Existence {
         "exists".to_string()
     }
 
-    fn check(&self) -> Result<(), Assertion> {
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+        host.file_kind(self.resource.path())
+            .map_err(|e| self.host_error(e))?;
         self.resource
-            .exists()
+            .exists(host)
             .map_err(|reason| Assertion::Fatal("exists".to_string(), reason))
     }
 }
@@ -209,17 +230,27 @@ impl Condition for Absence {
         "absence".to_string()
     }
 
-    fn check(&self) -> Result<(), Assertion> {
-        match self.resource.exists() {
-            Err(_) => Ok(()),
-            Ok(()) => self.assert_failure("Resource alreday exists".to_string()),
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+        let kind = host
+            .file_kind(self.resource.path())
+            .map_err(|e| self.host_error(e))?;
+        match (self.resource.exists(host), kind) {
+            (Err(_), _) => Ok(()),
+            /* only a socket left by a previous run is removed, never a file */
+            (Ok(()), Some(FileKind::Other)) if !matches!(self.resource, FsEntity::Directory(_)) => {
+                self.recoverable(
+                    "Resource alreday exists".to_string(),
+                    Recovery::RemoveStaleFile(self.resource.path().clone()),
+                )
+            }
+            (Ok(()), _) => self.assert_failure("Resource alreday exists".to_string()),
         }
     }
 }
 
 /// The Bhyve hypervisor emulate a number of virutal PCI device for the guest.
 /// The PCI selector, the slot and func number of the PCI device BHyve emulated
-/// are constrainted to slot# \in [0,31] and bus# \in [0,7]
+/// are constrainted to bus# \in [0,255], slot# \in [0,31] and func# \in [0,7]
 #[derive(Debug, Clone)]
 pub struct ValidBhyveVPciSlot {
     pub slot: PciSlot,
@@ -230,13 +261,17 @@ impl Condition for ValidBhyveVPciSlot {
         "valid_bhyve_vpci_slot".to_string()
     }
 
-    fn check(&self) -> Result<(), Assertion> {
-        if self.slot.slot > 31 {
-            self.assert_failure("Invalid vPCI slot. Allowed values are between 0 to 31".to_string())
-        } else if self.slot.func > 7 {
-            self.assert_failure("Invalid vPCI slot. Allowed values are between 0 to 7".to_string())
-        } else {
-            Ok(())
+    fn check(&self, _host: &dyn Host) -> Result<(), Assertion> {
+        let limits = [
+            ("bus", self.slot.bus, PciSlot::MAX_BUS),
+            ("slot", self.slot.slot, PciSlot::MAX_SLOT),
+            ("func", self.slot.func, PciSlot::MAX_FUNC),
+        ];
+        match limits.into_iter().find(|(_, value, max)| value > max) {
+            Some((component, _, max)) => self.assert_failure(format!(
+                "Invalid vPCI {component}. Allowed values are between 0 to {max}"
+            )),
+            None => Ok(()),
         }
     }
 }
@@ -254,8 +289,11 @@ impl Condition for ValidPassthruDevice {
         )
     }
 
-    fn check(&self) -> Result<(), Assertion> {
-        match PciDevice::from_pciconf(&self.slot) {
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+        match host
+            .pci_device(&self.slot)
+            .map_err(|e| self.host_error(e))?
+        {
             None => self.assert_failure("Invalid PCI device".to_string()),
             Some(dev) => {
                 if dev.header_type != 0x00 {
@@ -278,16 +316,10 @@ impl Condition for ValidPassthruDevice {
                 } else if dev.device_name.starts_with("ppt") {
                     Ok(())
                 } else {
-                    let slot = Box::new(self.slot);
-
-                    Err(Assertion::Recoverable(
-                        self.name(),
-                        "pci-attach-ppt".to_string(),
-                        Box::new(move || {
-                            let mut cc = PciDevice::from_pciconf(&slot).unwrap();
-                            cc.force_passthru();
-                        }),
-                    ))
+                    self.recoverable(
+                        format!("device is attached to {} instead of ppt", dev.device_name),
+                        Recovery::AttachPpt(self.slot),
+                    )
                 }
             }
         }
@@ -307,13 +339,13 @@ impl Condition for NetworkBackendAvailable {
         "network-backend-available".to_string()
     }
 
-    fn check(&self) -> Result<(), Assertion> {
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
         if let NetBackend::Tap = self.backend {
-            let ifaces =
-                get_tap_ifaces().map_err(|s| Assertion::Fatal(self.name(), s.to_string()))?;
+            let ifaces = host.tap_ifaces().map_err(|e| self.host_error(e))?;
             if ifaces.contains(&self.name) {
-                if is_tap_opened(&self.name)
-                    .map_err(|s| Assertion::Fatal(self.name(), s.to_string()))?
+                if host
+                    .is_tap_opened(&self.name)
+                    .map_err(|e| self.host_error(e))?
                 {
                     self.assert_failure(
                         "Tap device exists but is already opened by another process".to_string(),
@@ -322,15 +354,10 @@ impl Condition for NetworkBackendAvailable {
                     Ok(())
                 }
             } else {
-                let name = Box::new(self.name());
                 Err(Assertion::Recoverable(
                     "tap-iface".to_string(),
-                    "create-tap".to_string(),
-                    Box::new(move || {
-                        cmd!(ifconfig tap create name (name.as_str()))
-                            .status()
-                            .unwrap();
-                    }),
+                    format!("tap interface {} does not exist", self.name),
+                    Recovery::CreateTap(self.name.to_string()),
                 ))
             }
         } else {
@@ -339,6 +366,84 @@ impl Condition for NetworkBackendAvailable {
     }
 }
 
+/// Condition where no VM instance of the same name exists in the kernel, the
+/// instance is usually left by a previous bhyve process that did not exit
+/// cleanly
+#[derive(Debug, Clone)]
+pub struct VmInstanceAbsent {
+    pub name: String,
+}
+
+impl Condition for VmInstanceAbsent {
+    fn name(&self) -> String {
+        "vm-instance".to_string()
+    }
+
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+        if host
+            .vmm_exists(&self.name)
+            .map_err(|e| self.host_error(e))?
+        {
+            /* only the instance left by a supervisor that is gone is stale */
+            if host
+                .supervisor_running(&self.name)
+                .map_err(|e| self.host_error(e))?
+            {
+                return self
+                    .assert_failure(format!("vm instance {} is run by a supervisor", self.name));
+            }
+            self.recoverable(
+                format!("vm instance {} already exists", self.name),
+                Recovery::DestroyVm(self.name.to_string()),
+            )
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The UEFI variable store of the VM, copied from `template` on the first
+/// boot. The firmware only accepts a store of the size of its template, a
+/// store of another firmware is rejected before bhyve sees it
+#[derive(Debug, Clone)]
+pub struct UefiVarStore {
+    pub path: PathBuf,
+    pub template: PathBuf,
+}
+
+impl Condition for UefiVarStore {
+    fn name(&self) -> String {
+        "uefi-vars".to_string()
+    }
+
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+        let size = |path: &PathBuf| host.file_size(path).map_err(|e| self.host_error(e));
+        let expected = match size(&self.template)? {
+            None => {
+                return self.assert_failure(format!(
+                    "template {:?} does not exist or is not accessible",
+                    self.template
+                ))
+            }
+            Some(expected) => expected,
+        };
+        match size(&self.path)? {
+            None => self.recoverable(
+                format!("variable store {:?} does not exist", self.path),
+                Recovery::CopyFile {
+                    from: self.template.clone(),
+                    to: self.path.clone(),
+                },
+            ),
+            Some(actual) if actual != expected => self.assert_failure(format!(
+                "variable store {:?} is {actual} bytes, but the template is {expected} bytes",
+                self.path
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct LpcSlotAssignment {
     pub slot: PciSlot,
@@ -349,7 +454,7 @@ impl Condition for LpcSlotAssignment {
         "lpc_bus".to_string()
     }
 
-    fn check(&self) -> Result<(), Assertion> {
+    fn check(&self, _host: &dyn Host) -> Result<(), Assertion> {
         if self.slot.bus != 0 {
             self.assert_failure("Lpc device can only configure on bus 0".to_string())
         } else {
@@ -369,7 +474,7 @@ impl Condition for ValidResolution {
         "fbuf_resolution".to_string()
     }
 
-    fn check(&self) -> Result<(), Assertion> {
+    fn check(&self, _host: &dyn Host) -> Result<(), Assertion> {
         if self.h.is_some() && self.w.is_some() {
             let h = self.h.unwrap();
             let w = self.w.unwrap();
@@ -408,8 +513,11 @@ impl Condition for KernelFeature {
         format!("kmod:{}", self.kmod)
     }
 
-    fn check(&self) -> Result<(), Assertion> {
-        match crate::util::os::exists_kld(&self.kmod) {
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+        match host
+            .kld_loaded(&self.kmod)
+            .map_err(|e| self.host_error(e))?
+        {
             Some(true) => Ok(()),
             Some(false) => {
                 self.assert_failure(format!("kernel module {} has not loaded", self.kmod))
@@ -418,3 +526,373 @@ impl Condition for KernelFeature {
         }
     }
 }
+
+/// Advise if the guest memory is not a multiple of 2M, in which case bhyve
+/// cannot back the guest memory with superpages
+#[derive(Debug)]
+pub struct MemoryAlignment {
+    pub mem_kb: usize,
+}
+
+impl Condition for MemoryAlignment {
+    fn name(&self) -> String {
+        "mem_alignment".to_string()
+    }
+
+    fn check(&self, _host: &dyn Host) -> Result<(), Assertion> {
+        if !self.mem_kb.is_multiple_of(2 * 1024) {
+            self.warning(format!(
+                "memory size {}K is not a multiple of 2M",
+                self.mem_kb
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Advise if the guest is configured with more vCPUs than the host has
+#[derive(Debug)]
+pub struct HostCpuCount {
+    pub vcpus: usize,
+}
+
+impl Condition for HostCpuCount {
+    fn name(&self) -> String {
+        "host_cpu_count".to_string()
+    }
+
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+        match host.ncpu() {
+            Some(ncpu) if self.vcpus > ncpu => self.warning(format!(
+                "{} vCPUs configured but the host only has {} cores",
+                self.vcpus, ncpu
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Fail if the guest is configured with more vCPUs than bhyve supports
+#[derive(Debug)]
+pub struct VcpuLimit {
+    pub vcpus: usize,
+}
+
+impl Condition for VcpuLimit {
+    fn name(&self) -> String {
+        "vcpu_limit".to_string()
+    }
+
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+        let capabilities = host.bhyve_capabilities().map_err(|e| self.host_error(e))?;
+        match capabilities.max_vcpus {
+            Some(max) if self.vcpus > max => self.assert_failure(format!(
+                "{} vCPUs configured but bhyve supports at most {max}",
+                self.vcpus
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Fail if the bhyve on the host does not support the device model
+#[derive(Debug)]
+pub struct DeviceModelSupported {
+    pub model: String,
+}
+
+impl Condition for DeviceModelSupported {
+    fn name(&self) -> String {
+        "device_model".to_string()
+    }
+
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+        let capabilities = host.bhyve_capabilities().map_err(|e| self.host_error(e))?;
+        if capabilities.supports_model(&self.model) {
+            Ok(())
+        } else {
+            self.assert_failure(format!(
+                "device model {} is not supported by the bhyve on this host",
+                self.model
+            ))
+        }
+    }
+}
+
+/// The TCP backend of the com ports is only available in newer bhyve
+#[derive(Debug)]
+pub struct TcpConsoleSupported {}
+
+impl Condition for TcpConsoleSupported {
+    fn name(&self) -> String {
+        "tcp_console".to_string()
+    }
+
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+        let capabilities = host.bhyve_capabilities().map_err(|e| self.host_error(e))?;
+        match capabilities.tcp_console {
+            Some(false) => self.assert_failure(
+                "the bhyve on this host does not support TCP com port backends".to_string(),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Advise if a CD image does not look like an ISO9660 image, which usually
+/// means a disk image is attached as a CD by mistake
+#[derive(Debug)]
+pub struct Iso9660Signature {
+    pub path: PathBuf,
+}
+
+impl Condition for Iso9660Signature {
+    fn name(&self) -> String {
+        "iso9660".to_string()
+    }
+
+    fn check(&self, host: &dyn Host) -> Result<(), Assertion> {
+        // The primary volume descriptor is at sector 16, with the standard
+        // identifier "CD001" following the type code
+        match host.read_file_at(&self.path, 16 * 2048 + 1, 5) {
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                Err(self.host_error(HostError::Unsupported))
+            }
+            // Failed to open or too short, the existence check covers the
+            // former and the latter is definitely not an ISO9660 image
+            Err(e) if e.kind() != std::io::ErrorKind::UnexpectedEof => Ok(()),
+            Ok(signature) if signature == b"CD001" => Ok(()),
+            _ => self.warning(format!(
+                "{:?} does not have an ISO9660 signature",
+                self.path
+            )),
+        }
+    }
+}
+
+/// Advise if the framebuffer is set to wait for a VNC connection while vmrun
+/// is not attached to a terminal, as the guest will not boot until someone
+/// connects
+#[derive(Debug)]
+pub struct FramebufferWait {
+    pub wait: bool,
+}
+
+impl Condition for FramebufferWait {
+    fn name(&self) -> String {
+        "fbuf_wait".to_string()
+    }
+
+    fn check(&self, _host: &dyn Host) -> Result<(), Assertion> {
+        use std::io::IsTerminal;
+
+        if self.wait && !std::io::stdin().is_terminal() {
+            self.warning(
+                "wait is set while running detached, the guest will not boot \
+                until a VNC client connects"
+                    .to_string(),
+            )
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::os::fake::FakeHost;
+    use crate::util::os::pci::PciDevice;
+    use crate::util::os::plan::PlanHost;
+    use crate::util::os::BhyveCapabilities;
+
+    fn recover(assertion: Assertion, host: &FakeHost) -> Vec<Recovery> {
+        let mut undo = vec![];
+        assertion.recover(host, &mut undo).unwrap();
+        undo
+    }
+
+    #[test]
+    fn network_backend_available() {
+        let host = FakeHost::default();
+        let cond = NetworkBackendAvailable {
+            backend: NetBackend::Tap,
+            name: "tap3".to_string(),
+        };
+
+        let assertion = cond.check(&host).unwrap_err();
+        assert!(assertion.is_recoverable());
+        let undo = recover(assertion, &host);
+        assert!(cond.check(&host).is_ok());
+        assert_eq!(undo, vec![Recovery::DestroyTap("tap3".to_string())]);
+
+        host.taps.borrow_mut()[0].1 = true;
+        assert!(!cond.check(&host).unwrap_err().is_recoverable());
+    }
+
+    #[test]
+    fn valid_passthru_device() {
+        let host = FakeHost::default();
+        let cond = ValidPassthruDevice {
+            slot: PciSlot {
+                bus: 114,
+                slot: 0,
+                func: 0,
+            },
+        };
+
+        assert!(!cond.check(&host).unwrap_err().is_recoverable());
+
+        host.pci_devices
+            .borrow_mut()
+            .push(PciDevice::from_pciconf_l_line(
+                "iwlwifi0@pci0:114:0:0: class=0x028000 rev=0x1a hdr=0x00 vendor=0x8086 \
+            device=0x2725 subvendor=0x8086 subdevice=0x0024",
+            ));
+        let assertion = cond.check(&host).unwrap_err();
+        assert!(assertion.is_recoverable());
+        recover(assertion, &host);
+        assert!(cond.check(&host).is_ok());
+
+        host.pci_devices.borrow_mut()[0].header_type = 0x7f;
+        assert!(!cond.check(&host).unwrap_err().is_recoverable());
+    }
+
+    #[test]
+    fn kernel_feature() {
+        let host = FakeHost {
+            klds: vec!["nmdm".to_string()],
+            ..FakeHost::default()
+        };
+        assert!(KernelFeature::new_boxed("nmdm").check(&host).is_ok());
+        assert!(!KernelFeature::new_boxed("vmm")
+            .check(&host)
+            .unwrap_err()
+            .is_recoverable());
+    }
+
+    #[test]
+    fn absence_and_vm_instance() {
+        let host = FakeHost {
+            vms: std::cell::RefCell::new(vec!["vm0".to_string()]),
+            ..FakeHost::default()
+        }
+        .with_file("/tmp/console.sock", FileKind::Other, vec![]);
+
+        let absence = Absence {
+            resource: FsEntity::FsItem(PathBuf::from("/tmp/console.sock")),
+        };
+        recover(absence.check(&host).unwrap_err(), &host);
+        assert!(absence.check(&host).is_ok());
+
+        /* a regular file in the way is not removed */
+        let host = host.with_file("/tmp/console.sock", FileKind::File, vec![]);
+        assert!(!absence.check(&host).unwrap_err().is_recoverable());
+
+        let instance = VmInstanceAbsent {
+            name: "vm0".to_string(),
+        };
+        recover(instance.check(&host).unwrap_err(), &host);
+        assert!(instance.check(&host).is_ok());
+
+        /* the instance of a running supervisor is never destroyed */
+        host.vms.borrow_mut().push("vm0".to_string());
+        host.supervised.borrow_mut().push("vm0".to_string());
+        let assertion = instance.check(&host).unwrap_err();
+        assert!(!assertion.is_recoverable());
+        assert!(!assertion.has_recovery());
+    }
+
+    #[test]
+    fn uefi_var_store() {
+        let template = "/usr/local/share/uefi-firmware/BHYVE_UEFI_VARS.fd";
+        let host = FakeHost::default().with_file(template, FileKind::File, vec![0u8; 128]);
+
+        let store = UefiVarStore {
+            path: PathBuf::from("/vm/vars.fd"),
+            template: PathBuf::from(template),
+        };
+        recover(store.check(&host).unwrap_err(), &host);
+        assert!(store.check(&host).is_ok());
+
+        let host = host.with_file("/vm/vars.fd", FileKind::File, vec![0u8; 64]);
+        assert!(!store.check(&host).unwrap_err().is_recoverable());
+
+        let orphan = UefiVarStore {
+            path: PathBuf::from("/vm/vars.fd"),
+            template: PathBuf::from("/missing.fd"),
+        };
+        assert!(!orphan.check(&host).unwrap_err().is_recoverable());
+    }
+
+    #[test]
+    fn iso9660_signature() {
+        let mut image = vec![0u8; 16 * 2048 + 6];
+        image[16 * 2048 + 1..].copy_from_slice(b"CD001");
+        let host = FakeHost::default()
+            .with_file("/iso", FileKind::File, image)
+            .with_file("/img", FileKind::File, vec![0u8; 4096]);
+
+        let check = |path: &str| {
+            Iso9660Signature {
+                path: PathBuf::from(path),
+            }
+            .check(&host)
+        };
+        assert!(check("/iso").is_ok());
+        assert!(matches!(check("/img"), Err(Assertion::Warning(..))));
+        // left for the existence check
+        assert!(check("/missing").is_ok());
+    }
+
+    #[test]
+    fn bhyve_capabilities() {
+        let host = FakeHost {
+            capabilities: BhyveCapabilities {
+                device_models: Some(vec!["virtio-blk".to_string()]),
+                max_vcpus: Some(16),
+                ..BhyveCapabilities::default()
+            },
+            ..FakeHost::default()
+        };
+        let supported = |model: &str| {
+            DeviceModelSupported {
+                model: model.to_string(),
+            }
+            .check(&host)
+        };
+        assert!(supported("virtio-blk").is_ok());
+        assert!(!supported("virtio-9p").unwrap_err().is_recoverable());
+        assert!(VcpuLimit { vcpus: 16 }.check(&host).is_ok());
+        assert!(!VcpuLimit { vcpus: 17 }
+            .check(&host)
+            .unwrap_err()
+            .is_recoverable());
+    }
+
+    #[test]
+    fn plan_host_skips_probes() {
+        let host = PlanHost::default();
+        let conditions: Vec<Box<dyn Condition>> = vec![
+            Box::new(NetworkBackendAvailable {
+                backend: NetBackend::Tap,
+                name: "tap0".to_string(),
+            }),
+            Box::new(VmInstanceAbsent {
+                name: "vm0".to_string(),
+            }),
+            KernelFeature::new_boxed("vmm"),
+        ];
+        for cond in conditions {
+            match cond.check(&host) {
+                Err(Assertion::Warning(scope, reason)) => {
+                    assert_eq!(scope, cond.name());
+                    assert!(reason.starts_with("skipped: "), "{reason}");
+                }
+                Err(other) => panic!("expected {} to be skipped, got {other}", cond.name()),
+                Ok(()) => panic!("expected {} to be skipped", cond.name()),
+            }
+        }
+    }
+}
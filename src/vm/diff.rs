@@ -0,0 +1,233 @@
+use crate::vm::{PciSlot, VmRun};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    Added(String),
+    Removed(String),
+    Changed { from: String, to: String },
+}
+
+/// A difference between two VMs, `item` is what differs, for example `cpu`
+/// or `pci 0:3:0`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Difference {
+    pub item: String,
+    pub change: Change,
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.change {
+            Change::Added(value) => write!(f, "{}: added {value}", self.item),
+            Change::Removed(value) => write!(f, "{}: removed {value}", self.item),
+            Change::Changed { from, to } => write!(f, "{}: {from} -> {to}", self.item),
+        }
+    }
+}
+
+fn compare<K: Ord>(
+    differences: &mut Vec<Difference>,
+    from: BTreeMap<K, String>,
+    mut to: BTreeMap<K, String>,
+    item: impl Fn(&K) -> String,
+) {
+    for (key, from) in from {
+        let change = match to.remove(&key) {
+            None => Change::Removed(from),
+            Some(to) if to != from => Change::Changed { from, to },
+            Some(_) => continue,
+        };
+        differences.push(Difference {
+            item: item(&key),
+            change,
+        });
+    }
+    for (key, to) in to {
+        differences.push(Difference {
+            item: item(&key),
+            change: Change::Added(to),
+        });
+    }
+}
+
+impl VmRun {
+    fn settings(&self) -> BTreeMap<String, String> {
+        let flags = [
+            ("generate_acpi", self.generate_acpi),
+            ("wire_guest_mem", self.wire_guest_mem),
+            ("yield_on_hlt", self.yield_on_hlt),
+            ("force_msi", self.force_msi),
+            ("disable_mptable_gen", self.disable_mptable_gen),
+            ("utc_clock", self.utc_clock),
+            ("power_off_destroy_vm", self.power_off_destroy_vm),
+        ];
+
+        let mut settings = BTreeMap::from([
+            ("name".to_string(), self.name.to_string()),
+            ("cpu".to_string(), self.cpu.as_bhyve_arg()),
+            ("mem".to_string(), format!("{}K", self.mem_kb)),
+            ("extra_options".to_string(), self.extra_options.join(" ")),
+        ]);
+        for (flag, value) in flags {
+            settings.insert(flag.to_string(), value.to_string());
+        }
+        if let Some(uuid) = &self.uuid {
+            settings.insert("uuid".to_string(), uuid.to_string());
+        }
+        if let Some(gdb) = &self.gdb {
+            settings.insert("gdb".to_string(), gdb.to_string());
+        }
+        if let Some(loader) = &self.loader {
+            let loader = format!("{} {}", loader.program(), loader.args(self).join(" "));
+            settings.insert("loader".to_string(), loader);
+        }
+        settings
+    }
+
+    fn lpc(&self) -> BTreeMap<String, String> {
+        self.lpc_devices
+            .iter()
+            .map(|lpc| (format!("lpc {}", lpc.identifier()), lpc.to_bhyve_arg()))
+            .collect()
+    }
+
+    /// The differences of `other` from this VM, in the order of the settings,
+    /// the PCI devices and the lpc devices
+    pub fn diff(&self, other: &VmRun) -> Vec<Difference> {
+        let mut differences = vec![];
+        let same = |item: &String| item.to_string();
+        let pci = |slot: &PciSlot| format!("pci {}", slot.as_bhyve_arg());
+        compare(&mut differences, self.settings(), other.settings(), same);
+        compare(&mut differences, self.slot_map(), other.slot_map(), pci);
+        compare(&mut differences, self.lpc(), other.lpc(), same);
+        differences
+    }
+}
+
+/// A line of the difference between two argument lists
+#[derive(Debug, PartialEq, Eq)]
+pub enum ArgLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+impl Display for ArgLine<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgLine::Same(arg) => write!(f, "  {arg}"),
+            ArgLine::Removed(arg) => write!(f, "- {arg}"),
+            ArgLine::Added(arg) => write!(f, "+ {arg}"),
+        }
+    }
+}
+
+/// Difference of two argument lists by the longest common subsequence, the
+/// lists are short enough for the quadratic table
+pub fn diff_args<'a>(from: &'a [String], to: &'a [String]) -> Vec<ArgLine<'a>> {
+    let (n, m) = (from.len(), to.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if from[i] == to[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && from[i] == to[j] {
+            lines.push(ArgLine::Same(&from[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(ArgLine::Removed(&from[i]));
+            i += 1;
+        } else {
+            lines.push(ArgLine::Added(&to[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::VmSpec;
+    use crate::util::os::plan::PlanHost;
+    use vmrun::bhyve::group_args;
+
+    const CONFIG: &str = r#"{
+        "name": "test",
+        "cpu": 2,
+        "mem": "512M",
+        "com1": "stdio",
+        "emulations": [
+            {"device": "virtio-blk", "path": "disk.img"},
+            {"device": "virtio-blk", "path": "data.img"}
+        ],
+        "targets": {
+            "install": {
+                "cpu": 4,
+                "com1": "/dev/nmdm0A",
+                "emulations": [{"device": "ahci-cd", "path": "install.iso"}]
+            }
+        }
+    }"#;
+
+    #[test]
+    fn diff_targets() {
+        let root: VmSpec = serde_json::from_str(CONFIG).unwrap();
+        let mut install = root.clone();
+        install.consume_target(&"install".to_string()).unwrap();
+
+        let host = PlanHost::default();
+        let from = root.build(&host, &[]).unwrap();
+        let to = install.build(&host, &[]).unwrap();
+
+        let changed = |item: &str, from: &str, to: &str| Difference {
+            item: item.to_string(),
+            change: Change::Changed {
+                from: from.to_string(),
+                to: to.to_string(),
+            },
+        };
+        assert_eq!(
+            from.diff(&to),
+            vec![
+                changed("cpu", "2", "4"),
+                Difference {
+                    item: "pci 0:3:0".to_string(),
+                    change: Change::Added("ahci-cd,install.iso".to_string()),
+                },
+                changed("lpc com1", "com1,stdio", "com1,/dev/nmdm0A"),
+            ]
+        );
+        assert_eq!(from.diff(&from), vec![]);
+
+        let from = group_args(&from.bhyve_args().unwrap());
+        let to = group_args(&to.bhyve_args().unwrap());
+        let changes: Vec<String> = diff_args(&from, &to)
+            .iter()
+            .filter(|line| !matches!(line, ArgLine::Same(_)))
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                "- -c 2",
+                "+ -c 4",
+                "+ -s 0:3:0,ahci-cd,install.iso",
+                "- -l com1,stdio",
+                "+ -l com1,/dev/nmdm0A",
+            ]
+        );
+    }
+}
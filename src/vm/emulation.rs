@@ -1,6 +1,6 @@
 use crate::vm::conditions::{
-    Absence, Condition, Existence, FsEntity, NestedConditions, NetworkBackendAvailable, NoCond,
-    ValidPassthruDevice, ValidResolution,
+    Absence, Condition, Existence, FramebufferWait, FsEntity, Iso9660Signature, NestedConditions,
+    NetworkBackendAvailable, NoCond, ValidPassthruDevice, ValidResolution,
 };
 use crate::vm::{BhyveArg, BhyveDev, EmulatedPci, NetBackend, PciSlot, Resource};
 
@@ -88,7 +88,7 @@ impl EmulatedPci for VirtioBlk {
                 None => format!("{logical}"),
             };
 
-            base.push_str(format!("sectorsize={value}").as_str());
+            base.push_str(format!(",sectorsize={value}").as_str());
         }
 
         BhyveArg::Legacy(base)
@@ -96,7 +96,7 @@ impl EmulatedPci for VirtioBlk {
 }
 
 macro_rules! mk_ahci_frontend {
-    ($name:ident, $value:literal) => {
+    ($name:ident, $value:literal $(, $media_check:ident)*) => {
         #[derive(Debug, Clone)]
         pub struct $name {
             pub path: String,
@@ -120,14 +120,20 @@ macro_rules! mk_ahci_frontend {
         impl BhyveDev for $name {
             fn preconditions(&self) -> Box<dyn Condition> {
                 let pathbuf = std::path::PathBuf::from(self.path.to_string());
-                Box::new(Existence {
-                    resource: FsEntity::FsItem(pathbuf),
+                #[allow(unused_mut)]
+                let mut conditions: Vec<Box<dyn Condition>> = vec![Box::new(Existence {
+                    resource: FsEntity::FsItem(pathbuf.clone()),
+                })];
+                $(conditions.push(Box::new($media_check { path: pathbuf.clone() }));)*
+                Box::new(NestedConditions {
+                    name: $value.to_string(),
+                    conditions,
                 })
             }
         }
     };
 }
-mk_ahci_frontend!(AhciCd, "ahci-cd");
+mk_ahci_frontend!(AhciCd, "ahci-cd", Iso9660Signature);
 mk_ahci_frontend!(AhciHd, "ahci-hd");
 
 #[derive(Debug, Clone)]
@@ -292,9 +298,15 @@ pub struct Framebuffer {
 
 impl BhyveDev for Framebuffer {
     fn preconditions(&self) -> Box<dyn Condition> {
-        Box::new(ValidResolution {
-            w: self.w,
-            h: self.h,
+        Box::new(NestedConditions {
+            name: "fbuf".to_string(),
+            conditions: vec![
+                Box::new(ValidResolution {
+                    w: self.w,
+                    h: self.h,
+                }),
+                Box::new(FramebufferWait { wait: self.wait }),
+            ],
         })
     }
 }
@@ -378,7 +390,7 @@ mod tests {
 
         assert_eq!(
             fb.as_bhyve_arg(),
-            BhyveArg::Legacy("fbuf,host=0.0.0.0,w=1280,h=920,wait".to_string())
+            BhyveArg::Legacy("fbuf,tcp=0.0.0.0:5900,w=1280,h=920,wait".to_string())
         );
     }
 }
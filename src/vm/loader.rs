@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::util::os::tools::ToolPaths;
+use crate::vm::conditions::{Condition, Existence, FsEntity, NestedConditions};
+use crate::vm::{serial_device_conditions, BhyveDev, Resource, VmRun};
+
+/// A program the supervisor runs before every launch of bhyve to load the
+/// guest kernel into the VM, in place of a bootrom
+#[derive(Debug, Clone)]
+pub enum Loader {
+    Bhyveload(Bhyveload),
+    Grub(GrubBoot),
+}
+
+/// Boot the guest with bhyveload(8), which creates the VM instance and loads
+/// the FreeBSD kernel from `disk` into it
+#[derive(Debug, Clone)]
+pub struct Bhyveload {
+    /// The disk image or device the loader reads the kernel from
+    pub disk: String,
+    /// Environment variables of the loader, as `-e NAME=VALUE`
+    pub env: BTreeMap<String, String>,
+    /// The console of the loader, stdio or a nmdm device
+    pub console: Option<String>,
+    /// The directory of the host the loader can read files from, as `host:`
+    pub host_dir: Option<String>,
+}
+
+/// Boot the guest with grub-bhyve, for Linux and other guests that cannot
+/// boot from the UEFI firmware
+#[derive(Debug, Clone)]
+pub struct GrubBoot {
+    /// The disks of the guest by their grub names, such as `hd0` and `cd0`,
+    /// written to the `device.map` of grub-bhyve
+    pub devices: BTreeMap<String, String>,
+    /// The grub device to boot from, such as `hd0,msdos1`
+    pub root: Option<String>,
+    /// The directory of `grub.cfg` on `root`
+    pub directory: Option<String>,
+    /// The kernel on `root` to boot with a generated `grub.cfg` instead of
+    /// the one of the guest
+    pub kernel: Option<String>,
+    pub initrd: Option<String>,
+    pub cmdline: Option<String>,
+    /// The console of the loader, stdio or a nmdm device
+    pub console: Option<String>,
+    /// The directory the generated files are written to
+    pub work_dir: Option<String>,
+}
+
+impl GrubBoot {
+    /// The directory of the generated files, a directory of the VM under the
+    /// temporary directory unless given
+    pub fn work_dir(&self, name: &str) -> PathBuf {
+        match &self.work_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::temp_dir().join(format!("vmrun.{name}")),
+        }
+    }
+
+    fn device_map(&self) -> String {
+        self.devices
+            .iter()
+            .map(|(device, path)| format!("({device}) {path}\n"))
+            .collect()
+    }
+
+    /// The `grub.cfg` booting `kernel`, `None` if the guest boots with its
+    /// own configuration
+    fn config(&self) -> Option<String> {
+        let kernel = self.kernel.as_ref()?;
+        let root = self.root.as_deref().unwrap_or_default();
+        let mut config = format!("linux ({root}){kernel}");
+        if let Some(cmdline) = &self.cmdline {
+            config.push_str(&format!(" {cmdline}"));
+        }
+        config.push('\n');
+        if let Some(initrd) = &self.initrd {
+            config.push_str(&format!("initrd ({root}){initrd}\n"));
+        }
+        config.push_str("boot\n");
+        Some(config)
+    }
+}
+
+impl Loader {
+    pub fn program(&self) -> &'static str {
+        match self {
+            Loader::Bhyveload(_) => &ToolPaths::current().bhyveload,
+            Loader::Grub(_) => &ToolPaths::current().grub_bhyve,
+        }
+    }
+
+    /// The files the loader reads, generated by the supervisor before every
+    /// run of the loader
+    pub fn generated_files(&self, vmrun: &VmRun) -> Vec<(PathBuf, String)> {
+        match self {
+            Loader::Bhyveload(_) => vec![],
+            Loader::Grub(grub) => {
+                let dir = grub.work_dir(&vmrun.name);
+                let mut files = vec![(dir.join("device.map"), grub.device_map())];
+                if let Some(config) = grub.config() {
+                    files.push((dir.join("grub.cfg"), config));
+                }
+                files
+            }
+        }
+    }
+
+    pub fn ephemeral_objects(&self, vmrun: &VmRun) -> Vec<Resource> {
+        self.generated_files(vmrun)
+            .into_iter()
+            .map(|(path, _)| Resource::FsItem(path.to_string_lossy().to_string()))
+            .collect()
+    }
+
+    /// The arguments of the loader to load the guest of `vmrun`
+    pub fn args(&self, vmrun: &VmRun) -> Vec<String> {
+        match self {
+            Loader::Bhyveload(bhyveload) => {
+                let mut args = vec![];
+                if vmrun.wire_guest_mem {
+                    args.push("-S".to_string());
+                }
+                args.extend(["-m".to_string(), format!("{}K", vmrun.mem_kb)]);
+                args.extend(["-d".to_string(), bhyveload.disk.to_string()]);
+                for (name, value) in bhyveload.env.iter() {
+                    args.extend(["-e".to_string(), format!("{name}={value}")]);
+                }
+                if let Some(console) = &bhyveload.console {
+                    args.extend(["-c".to_string(), console.to_string()]);
+                }
+                if let Some(host_dir) = &bhyveload.host_dir {
+                    args.extend(["-h".to_string(), host_dir.to_string()]);
+                }
+                args.push(vmrun.name.to_string());
+                args
+            }
+            Loader::Grub(grub) => {
+                let dir = grub.work_dir(&vmrun.name);
+                let mut args = vec![];
+                if vmrun.wire_guest_mem {
+                    args.push("-S".to_string());
+                }
+                args.extend(["-M".to_string(), format!("{}M", vmrun.mem_kb / 1024)]);
+                args.extend([
+                    "-m".to_string(),
+                    dir.join("device.map").to_string_lossy().to_string(),
+                ]);
+                /* the generated grub.cfg is read from the host */
+                if grub.kernel.is_some() {
+                    args.extend(["-r".to_string(), "host".to_string()]);
+                    args.extend(["-d".to_string(), dir.to_string_lossy().to_string()]);
+                } else {
+                    if let Some(root) = &grub.root {
+                        args.extend(["-r".to_string(), root.to_string()]);
+                    }
+                    if let Some(directory) = &grub.directory {
+                        args.extend(["-d".to_string(), directory.to_string()]);
+                    }
+                }
+                if let Some(console) = &grub.console {
+                    args.extend(["-c".to_string(), console.to_string()]);
+                }
+                args.push(vmrun.name.to_string());
+                args
+            }
+        }
+    }
+}
+
+impl BhyveDev for Loader {
+    fn preconditions(&self) -> Box<dyn Condition> {
+        let mut conditions: Vec<Box<dyn Condition>> = vec![];
+        match self {
+            Loader::Bhyveload(bhyveload) => {
+                /* the disk can be an image or a device such as a zvol */
+                conditions.push(Box::new(Existence {
+                    resource: FsEntity::FsItem(PathBuf::from(&bhyveload.disk)),
+                }));
+                if let Some(host_dir) = &bhyveload.host_dir {
+                    conditions.push(Box::new(Existence {
+                        resource: FsEntity::Directory(PathBuf::from(host_dir)),
+                    }));
+                }
+                if let Some(console) = &bhyveload.console {
+                    conditions.extend(serial_device_conditions(console));
+                }
+            }
+            Loader::Grub(grub) => {
+                for path in grub.devices.values() {
+                    conditions.push(Box::new(Existence {
+                        resource: FsEntity::FsItem(PathBuf::from(path)),
+                    }));
+                }
+                if let Some(console) = &grub.console {
+                    conditions.extend(serial_device_conditions(console));
+                }
+            }
+        }
+        Box::new(NestedConditions {
+            name: "loader".to_string(),
+            conditions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::{BootOptions, VmSpec};
+    use crate::util::os::fake::FakeHost;
+    use crate::util::os::plan::PlanHost;
+    use crate::util::os::FileKind;
+
+    const CONFIG: &str = r#"{
+        "name": "fbsd",
+        "cpu": 1,
+        "mem": "1G",
+        "bhyveload": {
+            "disk": "/vm/fbsd.img",
+            "env": {"boot_verbose": "YES", "autoboot_delay": "3"},
+            "console": "/dev/nmdm0A",
+            "host_dir": "/vm/share"
+        },
+        "emulations": [{"device": "virtio-blk", "path": "/vm/fbsd.img"}]
+    }"#;
+
+    #[test]
+    fn bhyveload_args() {
+        let spec: VmSpec = serde_json::from_str(CONFIG).unwrap();
+        let vmrun = spec.build(&PlanHost::default(), &[]).unwrap();
+
+        let loader = vmrun.loader.as_ref().unwrap();
+        assert_eq!(
+            loader.args(&vmrun).join(" "),
+            "-m 1048576K -d /vm/fbsd.img -e autoboot_delay=3 -e boot_verbose=YES \
+             -c /dev/nmdm0A -h /vm/share fbsd"
+        );
+        /* the loader replaces the bootrom */
+        assert!(!vmrun
+            .bhyve_args()
+            .unwrap()
+            .iter()
+            .any(|arg| arg.starts_with("bootrom")));
+
+        let host = FakeHost::default();
+        assert!(loader.preconditions().check(&host).is_err());
+        let host = FakeHost {
+            klds: vec!["nmdm".to_string()],
+            ..FakeHost::default()
+        }
+        .with_file("/vm/fbsd.img", FileKind::File, vec![])
+        .with_file("/vm/share", FileKind::Directory, vec![]);
+        assert!(loader.preconditions().check(&host).is_ok());
+    }
+
+    #[test]
+    fn grub_files() {
+        let config = r#"{
+            "name": "linux",
+            "cpu": 1,
+            "mem": "512M",
+            "grub": {
+                "devices": {"hd0": "/vm/linux.img", "cd0": "/vm/install.iso"},
+                "root": "hd0,msdos1",
+                "kernel": "/vmlinuz",
+                "initrd": "/initrd.img",
+                "cmdline": "root=/dev/vda1 console=ttyS0",
+                "console": "stdio",
+                "work_dir": "/var/run/vmrun/linux"
+            },
+            "emulations": [{"device": "virtio-blk", "path": "/vm/linux.img"}]
+        }"#;
+        let spec: VmSpec = serde_json::from_str(config).unwrap();
+        let vmrun = spec.build(&PlanHost::default(), &[]).unwrap();
+        let loader = vmrun.loader.as_ref().unwrap();
+
+        assert_eq!(
+            loader.args(&vmrun).join(" "),
+            "-M 512M -m /var/run/vmrun/linux/device.map -r host -d /var/run/vmrun/linux \
+             -c stdio linux"
+        );
+        assert_eq!(
+            loader.generated_files(&vmrun),
+            vec![
+                (
+                    PathBuf::from("/var/run/vmrun/linux/device.map"),
+                    "(cd0) /vm/install.iso\n(hd0) /vm/linux.img\n".to_string()
+                ),
+                (
+                    PathBuf::from("/var/run/vmrun/linux/grub.cfg"),
+                    "linux (hd0,msdos1)/vmlinuz root=/dev/vda1 console=ttyS0\n\
+                     initrd (hd0,msdos1)/initrd.img\nboot\n"
+                        .to_string()
+                ),
+            ]
+        );
+        assert_eq!(vmrun.ephemeral_objects().len(), 2);
+
+        let mut spec: VmSpec = serde_json::from_str(config).unwrap();
+        if let Some(BootOptions::Grub { grub }) = &mut spec.bootopt {
+            grub.root = None;
+        }
+        assert!(spec.build(&PlanHost::default(), &[]).is_err());
+    }
+}
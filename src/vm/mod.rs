@@ -1,13 +1,23 @@
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 
 use crate::util::assertion::Assertion;
+use crate::util::os::plan::PlanHost;
 use crate::vm::conditions::{
-    Condition, Existence, FsEntity, GenericFatalCondition, KernelFeature, NestedConditions, NoCond,
-    ValidBhyveVPciSlot,
+    Condition, DeviceModelSupported, Existence, FsEntity, GenericFatalCondition, HostCpuCount,
+    KernelFeature, LpcSlotAssignment, MemoryAlignment, NestedConditions, NoCond,
+    TcpConsoleSupported, UefiVarStore, ValidBhyveVPciSlot, VcpuLimit, VmInstanceAbsent,
 };
+use crate::vm::recovery::Recovery;
 
+pub mod backend;
 pub mod conditions;
+pub mod diff;
 pub mod emulation;
+pub mod loader;
+pub mod recovery;
+pub mod script;
+pub mod verify;
 
 type Result<T> = std::result::Result<T, Assertion>;
 
@@ -53,10 +63,97 @@ pub struct VmRun {
     pub power_off_destroy_vm: bool,
 
     pub extra_options: Vec<String>,
+
+    /// If devices can be rendered with `-o`, otherwise only the legacy `-s`
+    /// syntax is used
+    pub config_syntax: bool,
+
+    /// The loader to run before every launch of bhyve, `None` if the VM boots
+    /// from a bootrom
+    pub loader: Option<loader::Loader>,
+
+    /// Where the output of a com port is logged, `None` if it is not
+    pub console_log: Option<ConsoleLogging>,
+
+    /// What the supervisor does when the guest prints a pattern on a com port
+    pub console_triggers: Vec<ConsoleTrigger>,
+
+    /// The keys the supervisor types into a com port as the guest boots
+    pub boot_script: Option<script::BootScript>,
+
+    /// The slots of the devices of every target in the stable mode, by the
+    /// identities of the devices, empty in the sequential mode
+    pub stable_slots: BTreeMap<String, PciSlot>,
+}
+
+/// Log of the output of a com port, rotated by size
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsoleLogging {
+    pub path: String,
+    pub com: u8,
+    /// The log is rotated before it grows over this size
+    pub max_bytes: u64,
+    /// Number of rotated logs kept, as `PATH.1` to `PATH.KEEP`
+    pub keep: usize,
+}
+
+/// A pattern the supervisor looks for in the output of a com port, and what
+/// it does once the guest prints it
+#[derive(Debug, Clone)]
+pub struct ConsoleTrigger {
+    /// Matched within a line of the output
+    pub pattern: regex::Regex,
+    pub com: u8,
+    pub action: TriggerAction,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TriggerAction {
+    /// Power off the VM without rebooting, if `failed` the supervisor exits
+    /// with an error
+    Stop { failed: bool },
+    /// Reset the VM, which reboots into the next target
+    Reset,
+    /// Reset the VM and reboot into `target`
+    SwitchTarget { target: String },
+    /// Run a shell command next to the VM, with the matched text in
+    /// `VMRUN_MATCH`
+    Hook { command: String },
+    /// Type the keys into the com port
+    Send { keys: String },
+}
+
+impl TriggerAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TriggerAction::Stop { .. } => "stop",
+            TriggerAction::Reset => "reset",
+            TriggerAction::SwitchTarget { .. } => "switch_target",
+            TriggerAction::Hook { .. } => "hook",
+            TriggerAction::Send { .. } => "send",
+        }
+    }
+
+    /// If the action ends the current run of the VM
+    pub fn ends_run(&self) -> bool {
+        matches!(
+            self,
+            TriggerAction::Stop { .. } | TriggerAction::Reset | TriggerAction::SwitchTarget { .. }
+        )
+    }
 }
 
 impl BhyveDev for VmRun {
     fn preconditions(&self) -> Box<dyn Condition> {
+        self.launch_conditions(true)
+    }
+}
+
+impl VmRun {
+    /// The preconditions of a launch of bhyve. A reboot by the supervisor
+    /// reuses the VM instance the previous run left, which bhyve
+    /// reinitialises, so only the first launch checks for a stale instance
+    pub fn launch_conditions(&self, first_launch: bool) -> Box<dyn Condition> {
         let mut emuc = vec![];
         let mut lpc = vec![];
 
@@ -81,6 +178,20 @@ impl BhyveDev for VmRun {
             lpc.push(lpc_device.preconditions());
         }
 
+        /* a terminal or a device cannot be shared by two com ports */
+        let mut consoles_seen = vec![];
+        for (n, backend) in self.com_ports() {
+            let console = backend.as_bhyve_arg();
+            if consoles_seen.contains(&console) {
+                lpc.push(GenericFatalCondition::new_boxed(
+                    "duplicated_com_backend",
+                    format!("com{n} uses {console}, which is used by another com port").as_str(),
+                ));
+            } else {
+                consoles_seen.push(console);
+            }
+        }
+
         let nc = Box::new(NestedConditions {
             name: "vpci".to_string(),
             conditions: emuc,
@@ -89,102 +200,232 @@ impl BhyveDev for VmRun {
             name: "lpc".to_string(),
             conditions: lpc,
         });
+        let loader = match &self.loader {
+            Some(loader) => loader.preconditions(),
+            None => Box::new(NoCond {}),
+        };
 
         Box::new(NestedConditions {
             name: "vm".to_string(),
-            conditions: vec![nc, lc],
+            conditions: vec![
+                Box::new(MemoryAlignment {
+                    mem_kb: self.mem_kb,
+                }),
+                Box::new(HostCpuCount {
+                    vcpus: self.cpu.vcpus(),
+                }),
+                Box::new(VcpuLimit {
+                    vcpus: self.cpu.vcpus(),
+                }),
+                match first_launch {
+                    true => Box::new(VmInstanceAbsent {
+                        name: self.name.to_string(),
+                    }),
+                    false => Box::new(NoCond {}),
+                },
+                Box::new(LpcSlotAssignment {
+                    slot: self.lpc_slot,
+                }),
+                nc,
+                lc,
+                loader,
+            ],
         })
     }
 }
 
 impl VmRun {
+    /// Conditions the supervisor may apply recoveries to prior to launch the
+    /// VM, paired with if the recoveries are pre-approved by the configuration.
+    /// A stale VM instance is only destroyed before the first launch
+    pub fn recoverable_conditions(&self, first_launch: bool) -> Vec<(bool, Box<dyn Condition>)> {
+        let mut conditions: Vec<(bool, Box<dyn Condition>)> = self
+            .emulations
+            .iter()
+            .map(|emulation| (emulation.want_fix, emulation.preconditions()))
+            .collect();
+        /* giving a template approves copying it */
+        if let Some(varfile) = self.varfile().filter(|varfile| varfile.template.is_some()) {
+            conditions.push((true, varfile.preconditions()));
+        }
+        if first_launch {
+            conditions.push((
+                false,
+                Box::new(VmInstanceAbsent {
+                    name: self.name.to_string(),
+                }),
+            ));
+        }
+        conditions
+    }
+
+    /// The UEFI variable store, if the VM boots from a bootrom with one
+    pub fn varfile(&self) -> Option<&VarFile> {
+        self.lpc_devices
+            .iter()
+            .find_map(|lpc_device| match lpc_device {
+                LpcDevice::Bootrom(_, varfile) => varfile.as_ref(),
+                _ => None,
+            })
+    }
+
     pub fn ephemeral_objects(&self) -> Vec<Resource> {
         let mut ephemeral_objects = vec![];
         for emulation in self.emulations.iter() {
             ephemeral_objects.extend(emulation.emulation.ephemeral_objects());
         }
+        if let Some(loader) = &self.loader {
+            ephemeral_objects.extend(loader.ephemeral_objects(self));
+        }
         ephemeral_objects
     }
 
     pub fn using_stdio_as_serial(&self) -> bool {
-        self.lpc_devices.iter().any(|lpc_device| {
-            if let LpcDevice::Com(_, device) = lpc_device {
-                device == "stdio"
-            } else {
-                false
-            }
-        })
+        self.com_ports()
+            .any(|(_, backend)| *backend == ComBackend::Stdio)
+    }
+
+    /// The backend of the com port `n`
+    pub fn com_port(&self, n: u8) -> Option<&ComBackend> {
+        self.com_ports()
+            .find(|(com, _)| *com == n)
+            .map(|(_, backend)| backend)
+    }
+
+    /// The com ports of the VM by their numbers
+    pub fn com_ports(&self) -> impl Iterator<Item = (u8, &ComBackend)> {
+        self.lpc_devices
+            .iter()
+            .filter_map(|lpc_device| match lpc_device {
+                LpcDevice::Com(n, backend) => Some((*n, backend)),
+                _ => None,
+            })
     }
 
     pub fn bhyve_args(&self) -> Result<Vec<String>> {
-        let mut argv: Vec<String> = Vec::new();
+        // Only fatal failures found without the host, such as invalid slots
+        // or lpc devices, prevent generating the args. The conditions on the
+        // host are left to the supervisor
+        match self.preconditions().check(&PlanHost::default()) {
+            Err(assertion) if !assertion.is_recoverable() => return Err(assertion),
+            _ => (),
+        }
+
+        Ok(self
+            .bhyve_arg_groups()
+            .into_iter()
+            .flat_map(|(_, args)| args)
+            .collect())
+    }
 
-        self.preconditions().check()?;
+    /// The devices by their slots, in the legacy syntax such that a device
+    /// reads the same regardless of the bhyve on the host
+    pub fn slot_map(&self) -> BTreeMap<PciSlot, String> {
+        let mut devices = BTreeMap::from([
+            (self.hostbridge_slot, self.hostbridge_brand.to_string()),
+            (self.lpc_slot, "lpc".to_string()),
+        ]);
+        for emulation in self.emulations.iter() {
+            let arg = emulation.to_bhyve_args(false).pop().unwrap_or_default();
+            let (_, device) = arg.split_once(',').unwrap_or_default();
+            devices.insert(emulation.slot, device.to_string());
+        }
+        devices
+    }
 
-        let mut push_yesno = |cond: bool, value: &'static str| {
+    /// The bhyve arguments grouped by the part of the VM they come from
+    pub fn bhyve_arg_groups(&self) -> Vec<(ArgSource, Vec<String>)> {
+        let mut groups: Vec<(ArgSource, Vec<String>)> = Vec::new();
+
+        let flags = [
+            ("generate_acpi", self.generate_acpi, "-A"),
+            ("wire_guest_mem", self.wire_guest_mem, "-S"),
+            ("yield_on_hlt", self.yield_on_hlt, "-H"),
+            ("force_msi", self.force_msi, "-W"),
+            ("disable_mptable_gen", self.disable_mptable_gen, "-Y"),
+            ("utc_clock", self.utc_clock, "-u"),
+            ("power_off_destroy_vm", self.power_off_destroy_vm, "-D"),
+        ];
+        for (field, cond, value) in flags {
             if cond {
-                argv.push(value.to_string())
+                groups.push((ArgSource::Flag(field), vec![value.to_string()]))
             }
-        };
+        }
 
-        push_yesno(self.generate_acpi, "-A");
-        push_yesno(self.wire_guest_mem, "-S");
-        push_yesno(self.yield_on_hlt, "-H");
-        push_yesno(self.force_msi, "-W");
-        push_yesno(self.disable_mptable_gen, "-Y");
-        push_yesno(self.utc_clock, "-u");
-        push_yesno(self.power_off_destroy_vm, "-D");
-
-        let mut push_arg_pair = |key: &'static str, value: String| {
-            argv.push(key.to_string());
-            argv.push(value);
-        };
+        let pair = |key: &str, value: String| vec![key.to_string(), value];
 
-        push_arg_pair("-c", self.cpu.as_bhyve_arg());
-        push_arg_pair("-m", format!("{}K", self.mem_kb));
+        groups.push((ArgSource::Cpu, pair("-c", self.cpu.as_bhyve_arg())));
+        groups.push((ArgSource::Mem, pair("-m", format!("{}K", self.mem_kb))));
 
         if let Some(gdb) = &self.gdb {
-            push_arg_pair("-G", gdb.to_string());
+            groups.push((ArgSource::Gdb, pair("-G", gdb.to_string())));
         }
 
         if let Some(uuid) = &self.uuid {
-            push_arg_pair("-U", uuid.to_string());
+            groups.push((ArgSource::Uuid, pair("-U", uuid.to_string())));
         }
 
-        push_arg_pair(
-            "-s",
-            format!(
-                "{},{}",
-                self.hostbridge_slot.as_bhyve_arg(),
-                self.hostbridge_brand
+        groups.push((
+            ArgSource::Hostbridge,
+            pair(
+                "-s",
+                format!(
+                    "{},{}",
+                    self.hostbridge_slot.as_bhyve_arg(),
+                    self.hostbridge_brand
+                ),
             ),
-        );
-        push_arg_pair("-s", format!("{},lpc", self.lpc_slot.as_bhyve_arg()));
-
-        for emulation in self.emulations.iter() {
-            argv.extend(emulation.to_bhyve_args());
+        ));
+        groups.push((
+            ArgSource::Lpc,
+            pair("-s", format!("{},lpc", self.lpc_slot.as_bhyve_arg())),
+        ));
+
+        for (index, emulation) in self.emulations.iter().enumerate() {
+            groups.push((
+                ArgSource::Emulation(index),
+                emulation.to_bhyve_args(self.config_syntax),
+            ));
         }
 
         // Check for existence of lpc devices is done by precondition hooks
 
-        if !self.lpc_devices.is_empty() {
-            for lpc in &self.lpc_devices {
-                argv.push("-l".to_string());
-                argv.push(lpc.to_bhyve_arg());
-            }
+        for (index, lpc) in self.lpc_devices.iter().enumerate() {
+            groups.push((ArgSource::LpcDevice(index), pair("-l", lpc.to_bhyve_arg())));
         }
 
-        argv.push(self.name.to_string());
+        for (index, option) in self.extra_options.iter().enumerate() {
+            groups.push((ArgSource::ExtraOption(index), vec![option.to_string()]));
+        }
 
-        Ok(argv)
+        groups.push((ArgSource::Name, vec![self.name.to_string()]));
+
+        groups
     }
 }
 
+/// The part of the VM a group of bhyve arguments comes from, the indices are
+/// into the corresponding fields of `VmRun`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgSource {
+    Flag(&'static str),
+    Cpu,
+    Mem,
+    Gdb,
+    Uuid,
+    Hostbridge,
+    Lpc,
+    Emulation(usize),
+    LpcDevice(usize),
+    ExtraOption(usize),
+    Name,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum LpcDevice {
-    Com(u8, String),
-    Bootrom(String, Option<String>),
+    Com(u8, ComBackend),
+    Bootrom(String, Option<VarFile>),
     TestDev,
 }
 
@@ -206,10 +447,7 @@ impl BhyveDev for LpcDevice {
                     resource: FsEntity::File(std::path::PathBuf::from(bootrom)),
                 })];
                 if let Some(bootvars) = bootvars {
-                    let vars = std::path::PathBuf::from(bootvars);
-                    base.push(Box::new(Existence {
-                        resource: FsEntity::File(vars),
-                    }));
+                    base.push(bootvars.preconditions());
                 }
 
                 Box::new(NestedConditions {
@@ -217,36 +455,16 @@ impl BhyveDev for LpcDevice {
                     conditions: base,
                 })
             }
-            LpcDevice::Com(n, device) => {
+            LpcDevice::Com(n, backend) => {
                 let mut conditions = vec![];
-                // Bhyve so far support 3 com devices, com1 to com3
-                if let 1u8..=3 = n {
-                } else {
+                if !(1..=ComBackend::MAX_PORT).contains(n) {
                     conditions.push(GenericFatalCondition::new_boxed(
                         "invalid-com-number",
-                        "only com[1-3] are supported",
+                        "only com[1-4] are supported",
                     ));
                 }
 
-                // In Bhyve, serial port is either stdio or path to a nmdm device
-                match device.as_str() {
-                    "stdio" => (),
-                    otherwise => {
-                        // nmdm devices always starts with "nmdm", we should not
-                        // assume the path is always under /dev as technically devfs
-                        // can be mounted everywhere
-                        let paths = otherwise.split('/').collect::<Vec<_>>();
-                        match paths.last() {
-                            Some(node) if node.starts_with("nmdm") => {
-                                conditions.push(KernelFeature::new_boxed("nmdm"))
-                            }
-                            _ => conditions.push(GenericFatalCondition::new_boxed(
-                                "invalid-com-device",
-                                "com device must be either stdio or nmdm device",
-                            )),
-                        };
-                    }
-                }
+                conditions.extend(backend.preconditions());
                 Box::new(NestedConditions {
                     name: "lpc".to_string(),
                     conditions,
@@ -257,13 +475,93 @@ impl BhyveDev for LpcDevice {
     }
 }
 
+/// Conditions of a serial device, which in bhyve is either stdio or the path
+/// to a tty device such as one side of a nmdm pair
+pub(crate) fn serial_device_conditions(device: &str) -> Vec<Box<dyn Condition>> {
+    match device {
+        "stdio" => vec![],
+        otherwise => {
+            // nmdm devices always starts with "nmdm", we should not
+            // assume the path is always under /dev as technically devfs
+            // can be mounted everywhere. They are created when opened, so
+            // only the module is required
+            match nmdm_number(otherwise) {
+                Some(_) => vec![KernelFeature::new_boxed("nmdm")],
+                None => vec![Box::new(Existence {
+                    resource: FsEntity::Node(std::path::PathBuf::from(otherwise)),
+                })],
+            }
+        }
+    }
+}
+
+/// The number of the nmdm pair of a device path such as `/dev/nmdm3A`
+pub fn nmdm_number(path: &str) -> Option<u32> {
+    let node = path.rsplit('/').next()?.strip_prefix("nmdm")?;
+    let number = node.strip_suffix('A').or_else(|| node.strip_suffix('B'))?;
+    number.parse().ok()
+}
+
+/// The backend of a com port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComBackend {
+    /// The terminal of the supervisor, only one com port can use it
+    Stdio,
+    /// The A side of the nmdm pair of the number, the operator attaches to
+    /// the B side
+    Nmdm(u32),
+    /// A tty device given by path
+    Device(String),
+    /// A TCP listener at `ADDRESS:PORT`
+    Tcp(String),
+}
+
+impl ComBackend {
+    /// The highest com port of the lpc bridge of bhyve
+    pub const MAX_PORT: u8 = 4;
+
+    pub fn as_bhyve_arg(&self) -> String {
+        match self {
+            ComBackend::Stdio => "stdio".to_string(),
+            ComBackend::Nmdm(n) => format!("/dev/nmdm{n}A"),
+            ComBackend::Device(path) => path.to_string(),
+            ComBackend::Tcp(address) => format!("tcp={address}"),
+        }
+    }
+
+    /// What the operator connects to for the console, the other side of a
+    /// nmdm pair for `cu -l` or the TCP address, `None` for stdio and other
+    /// devices
+    pub fn peer(&self) -> Option<String> {
+        match self {
+            ComBackend::Stdio => None,
+            ComBackend::Nmdm(n) => Some(format!("/dev/nmdm{n}B")),
+            ComBackend::Device(path) => match nmdm_number(path) {
+                Some(_) if path.ends_with('A') => Some(format!("{}B", &path[..path.len() - 1])),
+                Some(_) => Some(format!("{}A", &path[..path.len() - 1])),
+                None => None,
+            },
+            ComBackend::Tcp(address) => Some(address.to_string()),
+        }
+    }
+
+    fn preconditions(&self) -> Vec<Box<dyn Condition>> {
+        match self {
+            ComBackend::Stdio => vec![],
+            ComBackend::Nmdm(_) => vec![KernelFeature::new_boxed("nmdm")],
+            ComBackend::Device(path) => serial_device_conditions(path),
+            ComBackend::Tcp(_) => vec![Box::new(TcpConsoleSupported {})],
+        }
+    }
+}
+
 impl LpcDevice {
     fn to_bhyve_arg(&self) -> String {
         match self {
-            LpcDevice::Com(i, val) => format!("com{},{}", i, val),
+            LpcDevice::Com(i, backend) => format!("com{},{}", i, backend.as_bhyve_arg()),
             LpcDevice::TestDev => "pc-testdev".to_string(),
             LpcDevice::Bootrom(firmware, varfile) => match varfile {
-                Some(var) => format!("bootrom,{},{}", firmware, var),
+                Some(var) => format!("bootrom,{},{}", firmware, var.path),
                 None => format!("bootrom,{}", firmware),
             },
         }
@@ -363,8 +661,12 @@ pub struct EmulatedPciDevice {
 
 impl BhyveDev for EmulatedPciDevice {
     fn preconditions(&self) -> Box<dyn Condition> {
-        let mut base: Vec<Box<dyn Condition>> =
-            vec![Box::new(ValidBhyveVPciSlot { slot: self.slot })];
+        let mut base: Vec<Box<dyn Condition>> = vec![
+            Box::new(ValidBhyveVPciSlot { slot: self.slot }),
+            Box::new(DeviceModelSupported {
+                model: self.model(),
+            }),
+        ];
 
         let (bus, slot, func) = (self.slot.bus, self.slot.slot, self.slot.func);
         base.push(self.emulation.preconditions());
@@ -376,8 +678,31 @@ impl BhyveDev for EmulatedPciDevice {
 }
 
 impl EmulatedPciDevice {
-    fn to_bhyve_args(&self) -> Vec<String> {
+    /// The bhyve device model of the emulation, for example `virtio-blk`
+    pub fn model(&self) -> String {
         match self.emulation.as_bhyve_arg() {
+            BhyveArg::Legacy(arg) => arg.split(',').next().unwrap_or_default().to_string(),
+            BhyveArg::Config(kvs) => kvs
+                .into_iter()
+                .find(|(key, _)| key == "device")
+                .map(|(_, model)| model)
+                .unwrap_or_default(),
+        }
+    }
+
+    fn to_bhyve_args(&self, config_syntax: bool) -> Vec<String> {
+        match self.emulation.as_bhyve_arg() {
+            /* bhyve without -o takes the same keys in the legacy syntax */
+            BhyveArg::Config(kvs) if !config_syntax => {
+                let mut arg = self.model();
+                for (key, value) in kvs.iter().filter(|(key, _)| key != "device") {
+                    arg.push_str(&format!(",{key}={value}"));
+                }
+                vec![
+                    "-s".to_string(),
+                    format!("{},{arg}", self.slot.as_bhyve_arg()),
+                ]
+            }
             BhyveArg::Config(kvs) => {
                 let mut args = vec![];
                 for (key, value) in kvs.iter() {
@@ -400,7 +725,46 @@ impl EmulatedPciDevice {
 #[derive(Debug, Clone)]
 pub struct UefiBoot {
     pub bootrom: String,
-    pub varfile: Option<String>,
+    pub varfile: Option<VarFile>,
+}
+
+/// The UEFI variable store of the VM
+#[derive(Debug, Clone)]
+pub struct VarFile {
+    pub path: String,
+    /// The store is copied from the template if it does not exist, such that
+    /// VMs sharing a template do not share their boot entries
+    pub template: Option<String>,
+    /// Copy the template over the store before every boot
+    pub reset: bool,
+}
+
+impl VarFile {
+    /// The copy of the template resetting the store, if it is to be reset
+    pub fn reset_recovery(&self) -> Option<Recovery> {
+        match &self.template {
+            Some(template) if self.reset => Some(Recovery::CopyFile {
+                from: std::path::PathBuf::from(template),
+                to: std::path::PathBuf::from(&self.path),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl BhyveDev for VarFile {
+    fn preconditions(&self) -> Box<dyn Condition> {
+        let path = std::path::PathBuf::from(&self.path);
+        match &self.template {
+            None => Box::new(Existence {
+                resource: FsEntity::File(path),
+            }),
+            Some(template) => Box::new(UefiVarStore {
+                path,
+                template: std::path::PathBuf::from(template),
+            }),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -411,13 +775,19 @@ pub struct CpuSpec {
 }
 
 impl CpuSpec {
+    pub fn vcpus(&self) -> usize {
+        self.threads * self.cores * self.sockets
+    }
+
+    /// A bare vCPU count only for the topology bhyve makes of it, any other
+    /// topology is spelled out
     fn as_bhyve_arg(&self) -> String {
-        if self.sockets == 1 && self.cores == 1 {
-            self.threads.to_string()
+        if self.cores == 1 && self.threads == 1 {
+            self.sockets.to_string()
         } else {
             format!(
                 "sockets={},threads={},cores={}",
-                self.sockets, self.cores, self.threads
+                self.sockets, self.threads, self.cores
             )
         }
     }
@@ -431,6 +801,12 @@ pub struct PciSlot {
 }
 
 impl PciSlot {
+    /// The highest virtual PCI bus, bhyve has `MAXBUSES = PCI_BUSMAX + 1`
+    /// buses (pci_emul.c)
+    pub const MAX_BUS: u8 = 255;
+    pub const MAX_SLOT: u8 = 31;
+    pub const MAX_FUNC: u8 = 7;
+
     pub fn as_bhyve_arg(&self) -> String {
         format!("{}:{}:{}", self.bus, self.slot, self.func)
     }
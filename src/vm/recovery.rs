@@ -0,0 +1,194 @@
+use crate::util::os::tools::ToolPaths;
+use crate::util::os::Host;
+use crate::util::shell_quote;
+use crate::vm::PciSlot;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// A known fix of a failed condition that can be applied by the supervisor.
+/// Recoveries are plain values such that they can be inspected, printed as a
+/// shell script for the operator to review, and reverted when the VM stops.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recovery {
+    /// Create a tap interface with the given name
+    CreateTap(String),
+    /// Destroy a tap interface, the reverse of `CreateTap`
+    DestroyTap(String),
+    /// Detach the driver currently attached to the PCI device and attach the
+    /// ppt driver such that the device can be passthru to the guest
+    AttachPpt(PciSlot),
+    /// Detach the ppt driver and let the kernel to probe the original driver,
+    /// the reverse of `AttachPpt`
+    ReleasePpt(PciSlot),
+    /// Remove a stale filesystem object left by a previous bhyve instance, for
+    /// example the sockets of virtio-console
+    RemoveStaleFile(PathBuf),
+    /// Destroy a stale VM instance of the given name left in the kernel
+    DestroyVm(String),
+    /// Copy a file from a template, for example the UEFI variable store of a
+    /// VM from the template of the firmware
+    CopyFile { from: PathBuf, to: PathBuf },
+}
+
+#[derive(Error, Debug)]
+#[error("recovery `{name}` failed: {reason}")]
+pub struct RecoveryError {
+    pub name: &'static str,
+    pub reason: String,
+}
+
+impl Recovery {
+    /// A short identifier of the kind of this recovery
+    pub fn name(&self) -> &'static str {
+        match self {
+            Recovery::CreateTap(_) => "create-tap",
+            Recovery::DestroyTap(_) => "destroy-tap",
+            Recovery::AttachPpt(_) => "pci-attach-ppt",
+            Recovery::ReleasePpt(_) => "pci-release-ppt",
+            Recovery::RemoveStaleFile(_) => "remove-stale-file",
+            Recovery::DestroyVm(_) => "destroy-vm",
+            Recovery::CopyFile { .. } => "copy-file",
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Recovery::CreateTap(name) => format!("create tap interface {name}"),
+            Recovery::DestroyTap(name) => format!("destroy tap interface {name}"),
+            Recovery::AttachPpt(slot) => {
+                format!("attach ppt driver to pci0:{}", slot.as_bhyve_arg())
+            }
+            Recovery::ReleasePpt(slot) => {
+                format!("release ppt driver from pci0:{}", slot.as_bhyve_arg())
+            }
+            Recovery::RemoveStaleFile(path) => format!("remove stale file {path:?}"),
+            Recovery::DestroyVm(name) => format!("destroy stale vm instance {name}"),
+            Recovery::CopyFile { from, to } => format!("copy {from:?} to {to:?}"),
+        }
+    }
+
+    /// The commands that perform this recovery on a FreeBSD host
+    pub fn commands(&self) -> Vec<Vec<String>> {
+        macro_rules! argv {
+            ($($arg:expr),*) => { vec![$($arg.to_string()),*] }
+        }
+
+        let tools = ToolPaths::current();
+        match self {
+            Recovery::CreateTap(name) => {
+                vec![argv!(tools.ifconfig, "tap", "create", "name", name)]
+            }
+            Recovery::DestroyTap(name) => vec![argv!(tools.ifconfig, name, "destroy")],
+            Recovery::AttachPpt(slot) => vec![argv!(
+                tools.devctl,
+                "set",
+                "driver",
+                "-f",
+                format!("pci0:{}", slot.as_bhyve_arg()),
+                "ppt"
+            )],
+            Recovery::ReleasePpt(slot) => vec![argv!(
+                tools.devctl,
+                "clear",
+                "driver",
+                "-f",
+                format!("pci0:{}", slot.as_bhyve_arg())
+            )],
+            Recovery::RemoveStaleFile(path) => {
+                vec![argv!("rm", "-f", path.to_string_lossy())]
+            }
+            Recovery::DestroyVm(name) => {
+                vec![argv!(tools.bhyvectl, "--destroy", format!("--vm={name}"))]
+            }
+            Recovery::CopyFile { from, to } => {
+                vec![argv!("cp", from.to_string_lossy(), to.to_string_lossy())]
+            }
+        }
+    }
+
+    /// The recovery as lines of shell script
+    pub fn script(&self) -> Vec<String> {
+        self.commands()
+            .iter()
+            .map(|argv| {
+                argv.iter()
+                    .map(|arg| shell_quote(arg))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect()
+    }
+
+    /// The recovery that revert the host change made by this recovery, if any
+    pub fn undo(&self) -> Option<Recovery> {
+        match self {
+            Recovery::CreateTap(name) => Some(Recovery::DestroyTap(name.to_string())),
+            Recovery::AttachPpt(slot) => Some(Recovery::ReleasePpt(*slot)),
+            _ => None,
+        }
+    }
+
+    /// Apply the recovery, and return the recovery to revert it if any
+    pub fn apply(&self, host: &dyn Host) -> Result<Option<Recovery>, RecoveryError> {
+        match self {
+            Recovery::CreateTap(name) => host.create_tap(name),
+            Recovery::DestroyTap(name) => host.destroy_tap(name),
+            Recovery::AttachPpt(slot) => host.set_pci_driver(slot, Some("ppt")),
+            Recovery::ReleasePpt(slot) => host.set_pci_driver(slot, None),
+            Recovery::RemoveStaleFile(path) => host.remove_file(path),
+            Recovery::DestroyVm(name) => host.destroy_vm(name),
+            Recovery::CopyFile { from, to } => host.copy_file(from, to),
+        }
+        .map_err(|error| RecoveryError {
+            name: self.name(),
+            reason: error.to_string(),
+        })?;
+        Ok(self.undo())
+    }
+}
+
+impl Serialize for Recovery {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(3))?;
+        map.serialize_entry("name", self.name())?;
+        map.serialize_entry("description", &self.describe())?;
+        map.serialize_entry("script", &self.script())?;
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovery_script() {
+        assert_eq!(
+            Recovery::CreateTap("tap3".to_string()).script(),
+            vec!["ifconfig tap create name tap3".to_string()]
+        );
+        assert_eq!(
+            Recovery::RemoveStaleFile(PathBuf::from("/tmp/my vm's.sock")).script(),
+            vec!["rm -f '/tmp/my vm'\\''s.sock'".to_string()]
+        );
+    }
+
+    #[test]
+    fn recovery_undo() {
+        let slot = PciSlot {
+            bus: 2,
+            slot: 0,
+            func: 0,
+        };
+        assert_eq!(
+            Recovery::CreateTap("tap3".to_string()).undo(),
+            Some(Recovery::DestroyTap("tap3".to_string()))
+        );
+        assert_eq!(
+            Recovery::AttachPpt(slot).undo(),
+            Some(Recovery::ReleasePpt(slot))
+        );
+        assert_eq!(Recovery::DestroyVm("vm".to_string()).undo(), None);
+    }
+}
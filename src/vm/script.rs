@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use crate::vm::TriggerAction;
+
+/// Steps typing into a com port as the guest boots, for example to drive a
+/// boot loader and an installer
+#[derive(Debug, Clone)]
+pub struct BootScript {
+    pub com: u8,
+    pub steps: Vec<ScriptStep>,
+    /// Pause between two keys, for consoles dropping keys typed too fast
+    pub key_delay: Duration,
+    /// What the supervisor does when a step fails, unless the step says
+    pub on_failure: TriggerAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum ScriptStep {
+    /// Wait for the guest to print the pattern, within a line of the output
+    /// printed after the previous keys were sent
+    WaitFor {
+        pattern: regex::Regex,
+        timeout: Duration,
+        on_failure: Option<TriggerAction>,
+    },
+    Send(Vec<Keys>),
+    Sleep(Duration),
+}
+
+/// A part of the keys of a `send` step
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Keys {
+    Bytes(Vec<u8>),
+    Wait(Duration),
+}
+
+/// The bytes of a special key such as `<enter>` or `<f2>`, named as in
+/// Packer. Keys beyond the terminal keys are sent as VT100 sequences
+fn special_key(name: &str) -> Option<Vec<u8>> {
+    let name = name.to_ascii_lowercase();
+    if let Some(letter) = name.strip_prefix("ctrl-") {
+        return match letter.as_bytes() {
+            [c @ b'a'..=b'z'] => Some(vec![c & 0x1f]),
+            _ => None,
+        };
+    }
+    let sequence: &[u8] = match name.as_str() {
+        "enter" | "return" => b"\r",
+        "esc" => b"\x1b",
+        "tab" => b"\t",
+        "bs" => b"\x08",
+        "del" => b"\x7f",
+        "spacebar" => b" ",
+        "up" => b"\x1b[A",
+        "down" => b"\x1b[B",
+        "right" => b"\x1b[C",
+        "left" => b"\x1b[D",
+        "home" => b"\x1b[H",
+        "end" => b"\x1b[F",
+        "insert" => b"\x1b[2~",
+        "pageup" => b"\x1b[5~",
+        "pagedown" => b"\x1b[6~",
+        "f1" => b"\x1bOP",
+        "f2" => b"\x1bOQ",
+        "f3" => b"\x1bOR",
+        "f4" => b"\x1bOS",
+        "f5" => b"\x1b[15~",
+        "f6" => b"\x1b[17~",
+        "f7" => b"\x1b[18~",
+        "f8" => b"\x1b[19~",
+        "f9" => b"\x1b[20~",
+        "f10" => b"\x1b[21~",
+        "f11" => b"\x1b[23~",
+        "f12" => b"\x1b[24~",
+        _ => return None,
+    };
+    Some(sequence.to_vec())
+}
+
+/// A pause of `<wait>` (a second) or `<waitN>` (N seconds)
+fn wait_key(name: &str) -> Option<Duration> {
+    let seconds = name.to_ascii_lowercase().strip_prefix("wait")?.to_string();
+    if seconds.is_empty() {
+        return Some(Duration::from_secs(1));
+    }
+    seconds
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+}
+
+/// Parse the keys of a `send` step: text with special keys in angle
+/// brackets, such as `boot -s<enter>`. A bracket not naming a key is sent as
+/// it is
+pub fn parse_keys(text: &str) -> Vec<Keys> {
+    let mut keys = vec![];
+    let mut bytes = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        bytes.extend_from_slice(&rest.as_bytes()[..start]);
+        rest = &rest[start..];
+        let name = rest[1..].find('>').map(|end| &rest[1..end + 1]);
+        match name {
+            Some(name) if special_key(name).is_some() => {
+                bytes.extend(special_key(name).unwrap());
+            }
+            Some(name) if wait_key(name).is_some() => {
+                if !bytes.is_empty() {
+                    keys.push(Keys::Bytes(std::mem::take(&mut bytes)));
+                }
+                keys.push(Keys::Wait(wait_key(name).unwrap()));
+            }
+            _ => {
+                bytes.push(b'<');
+                rest = &rest[1..];
+                continue;
+            }
+        }
+        rest = &rest[name.unwrap().len() + 2..];
+    }
+    bytes.extend_from_slice(rest.as_bytes());
+    if !bytes.is_empty() {
+        keys.push(Keys::Bytes(bytes));
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn special_keys() {
+        assert_eq!(
+            parse_keys("boot -s<enter>"),
+            vec![Keys::Bytes(b"boot -s\r".to_vec())]
+        );
+        assert_eq!(
+            parse_keys("<esc><wait>1<Down><wait2.5><ctrl-c>"),
+            vec![
+                Keys::Bytes(b"\x1b".to_vec()),
+                Keys::Wait(Duration::from_secs(1)),
+                Keys::Bytes(b"1\x1b[B".to_vec()),
+                Keys::Wait(Duration::from_millis(2500)),
+                Keys::Bytes(vec![3]),
+            ]
+        );
+        /* brackets not naming a key are text */
+        assert_eq!(
+            parse_keys("a <b> <c<tab>"),
+            vec![Keys::Bytes(b"a <b> <c\t".to_vec())]
+        );
+        assert!(parse_keys("").is_empty());
+    }
+}
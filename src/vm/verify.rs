@@ -0,0 +1,257 @@
+use crate::vm::{PciSlot, VmRun};
+use vmrun::bhyve::{BhyveCommand, Issue, PciAddress};
+
+fn address(slot: &PciSlot) -> PciAddress {
+    PciAddress {
+        bus: slot.bus.into(),
+        slot: slot.slot.into(),
+        func: slot.func.into(),
+    }
+}
+
+impl VmRun {
+    /// Compare the parsed bhyve arguments of this VM against the VM itself,
+    /// such that a device or an option that is formatted wrongly, or not
+    /// formatted at all, is found before bhyve sees it
+    pub fn verify_args(&self, command: &BhyveCommand) -> Vec<Issue> {
+        let mut issues = vec![];
+        let mut mismatch =
+            |location: String, reason: String| issues.push(Issue { location, reason });
+
+        let flags = [
+            ('A', self.generate_acpi),
+            ('S', self.wire_guest_mem),
+            ('H', self.yield_on_hlt),
+            ('W', self.force_msi),
+            ('Y', self.disable_mptable_gen),
+            ('u', self.utc_clock),
+            ('D', self.power_off_destroy_vm),
+        ];
+        for (flag, expected) in flags {
+            if command.flags.contains(&flag) != expected {
+                let reason = if expected { "missing" } else { "unexpected" };
+                mismatch(format!("-{flag}"), format!("{reason} flag"));
+            }
+        }
+
+        match &command.topology {
+            None => mismatch("-c".to_string(), "missing vCPU topology".to_string()),
+            Some(topology) => {
+                let expected = (self.cpu.sockets, self.cpu.cores, self.cpu.threads);
+                let actual = (topology.sockets, topology.cores, topology.threads);
+                if topology.cpus != self.cpu.vcpus() || actual != expected {
+                    mismatch(
+                        "-c".to_string(),
+                        format!(
+                            "expected sockets={},cores={},threads={}, got sockets={},cores={},threads={}",
+                            expected.0, expected.1, expected.2, actual.0, actual.1, actual.2
+                        ),
+                    );
+                }
+            }
+        }
+
+        let mem_bytes = self.mem_kb as u64 * 1024;
+        if command.mem_bytes != Some(mem_bytes) {
+            mismatch(
+                "-m".to_string(),
+                format!("expected {mem_bytes} bytes, got {:?}", command.mem_bytes),
+            );
+        }
+
+        if command.gdb != self.gdb {
+            mismatch("-G".to_string(), format!("expected {:?}", self.gdb));
+        }
+
+        if command.uuid != self.uuid {
+            mismatch("-U".to_string(), format!("expected {:?}", self.uuid));
+        }
+
+        let mut expected_devices = vec![
+            (self.hostbridge_slot, self.hostbridge_brand.to_string()),
+            (self.lpc_slot, "lpc".to_string()),
+        ];
+        for emulation in self.emulations.iter() {
+            expected_devices.push((emulation.slot, emulation.model()));
+        }
+
+        for (slot, model) in expected_devices {
+            let location = format!("pci {}", slot.as_bhyve_arg());
+            match command.device(address(&slot)) {
+                None => mismatch(location, format!("missing {model} device")),
+                Some(device) if device.model != model => mismatch(
+                    location,
+                    format!("expected {model} device, got {}", device.model),
+                ),
+                Some(_) => (),
+            }
+        }
+
+        for lpc in self.lpc_devices.iter() {
+            let expected = lpc.to_bhyve_arg();
+            let found = command.lpc_devices.iter().any(|device| {
+                std::iter::once(device.name.as_str())
+                    .chain(device.options.iter().map(|s| s.as_str()))
+                    .collect::<Vec<_>>()
+                    .join(",")
+                    == expected
+            });
+            if !found {
+                mismatch(
+                    format!("-l {}", lpc.identifier()),
+                    format!("expected {expected}"),
+                );
+            }
+        }
+
+        if command.name != self.name {
+            mismatch(
+                "name".to_string(),
+                format!("expected {}, got {}", self.name, command.name),
+            );
+        }
+
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::emulation::{Nvme, VirtioBlk};
+    use crate::vm::{CpuSpec, EmulatedPciDevice};
+    use vmrun::bhyve::Topology;
+
+    fn vmrun(cpu: CpuSpec, emulations: Vec<EmulatedPciDevice>) -> VmRun {
+        VmRun {
+            cpu,
+            mem_kb: 512 * 1024,
+            hostbridge_brand: "hostbridge".to_string(),
+            hostbridge_slot: PciSlot {
+                bus: 0,
+                slot: 0,
+                func: 0,
+            },
+            lpc_slot: PciSlot {
+                bus: 0,
+                slot: 31,
+                func: 0,
+            },
+            lpc_devices: vec![],
+            emulations,
+            name: "vm".to_string(),
+            uuid: None,
+            gdb: None,
+            utc_clock: true,
+            yield_on_hlt: true,
+            generate_acpi: true,
+            wire_guest_mem: false,
+            force_msi: false,
+            disable_mptable_gen: false,
+            power_off_destroy_vm: false,
+            extra_options: vec![],
+            config_syntax: true,
+            loader: None,
+            console_log: None,
+            console_triggers: vec![],
+            boot_script: None,
+            stable_slots: Default::default(),
+        }
+    }
+
+    fn verify(vmrun: &VmRun) -> Vec<Issue> {
+        let args = vmrun.bhyve_args().unwrap();
+        let command = BhyveCommand::parse(&args).unwrap();
+        let mut issues = command.validate();
+        issues.extend(vmrun.verify_args(&command));
+        issues
+    }
+
+    #[test]
+    fn verify_round_trip() {
+        let cpu = CpuSpec {
+            threads: 2,
+            cores: 4,
+            sockets: 1,
+        };
+        let blk = VirtioBlk {
+            path: "disk.img".to_string(),
+            nocache: false,
+            direct: false,
+            ro: false,
+            logical_sector_size: Some(512),
+            physical_sector_size: Some(4096),
+            nodelete: true,
+        };
+        let vmrun = vmrun(
+            cpu,
+            vec![EmulatedPciDevice {
+                slot: PciSlot {
+                    bus: 0,
+                    slot: 3,
+                    func: 0,
+                },
+                want_fix: false,
+                emulation: Box::new(blk),
+            }],
+        );
+        assert_eq!(verify(&vmrun), vec![]);
+    }
+
+    #[test]
+    fn verify_flat_cpu() {
+        /* bhyve makes a socket of every vCPU of `-c 4` */
+        let flat = vmrun(CpuSpec::from_flat(4), vec![]);
+        let args = flat.bhyve_args().unwrap();
+        assert!(args.contains(&"4".to_string()));
+        let topology = BhyveCommand::parse(&args).unwrap().topology.unwrap();
+        assert_eq!(
+            (topology.sockets, topology.cores, topology.threads),
+            (4, 1, 1)
+        );
+        assert_eq!(verify(&flat), vec![]);
+
+        /* a single socket of threads is spelled out rather than flattened */
+        let threads = vmrun(
+            CpuSpec {
+                threads: 4,
+                cores: 1,
+                sockets: 1,
+            },
+            vec![],
+        );
+        let args = threads.bhyve_args().unwrap();
+        assert!(args.contains(&"sockets=1,threads=4,cores=1".to_string()));
+        assert_eq!(verify(&threads), vec![]);
+
+        /* and flattening it would be caught */
+        let mut command = BhyveCommand::parse(&args).unwrap();
+        command.topology = Some(Topology::parse("4").unwrap());
+        let issues = threads.verify_args(&command);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].location, "-c");
+    }
+
+    #[test]
+    fn verify_legacy_syntax() {
+        let nvme = EmulatedPciDevice {
+            slot: PciSlot {
+                bus: 0,
+                slot: 4,
+                func: 0,
+            },
+            want_fix: false,
+            emulation: Box::new(Nvme::from_ram_mb(1024)),
+        };
+        let mut vmrun = vmrun(CpuSpec::from_flat(1), vec![nvme]);
+        vmrun.config_syntax = false;
+        let args = vmrun.bhyve_args().unwrap();
+        assert!(args.contains(&"0:4:0,nvme,ram=1024".to_string()));
+        assert_eq!(verify(&vmrun), vec![]);
+
+        vmrun.config_syntax = true;
+        let args = vmrun.bhyve_args().unwrap();
+        assert!(args.contains(&"pci.0.4.0.device=nvme".to_string()));
+        assert_eq!(verify(&vmrun), vec![]);
+    }
+}